@@ -0,0 +1,381 @@
+//! Adaptive local means: Lee, enhanced Lee, Kuan and Frost filters.
+//! params:
+//! - filter and its damping factor
+//! - noise model (additive or multiplicative speckle) and level, 0 = estimated
+//! - window radius and border mode
+//!
+//! Every filter is driven by the local mean `m` and variance `v` over a (2r+1)² window.
+//! The noise level enters through the ratio `ρ = Ci / Cu` between the local variation and the
+//! noise variation: `Ci = √v / m`, `Cu = sigma` for speckle (`v * (1 + n)`), `Ci = √v`,
+//! `Cu = sigma` for additive noise. Flat areas (ρ ≈ 1) are averaged, edges (ρ ≫ 1) are kept.
+//! Color channels are filtered on their own, alpha is copied unchanged.
+
+use crate::error::ImageProcessingError;
+use crate::local_means::box_filter;
+use crate::utils::border::BorderMode;
+use crate::utils::buffer::{FloatImage, SigmaScale};
+use crate::Preset;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Adaptive filter
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AdaptiveFilter {
+    /// Lee (1980): `m + W (x - m)`, `W = 1 - 1/ρ²`
+    #[default]
+    Lee,
+    /// Lopes enhanced Lee: the mean below ρ = 1, the pixel above ρmax, exponential blend in between
+    EnhancedLee {
+        /// how fast the blend moves to the pixel value (1 is the usual choice)
+        damping: f64,
+    },
+    /// Kuan (1985): like Lee with `W = (1 - 1/ρ²) / (1 + Cu²)`, the same as Lee for additive noise
+    Kuan,
+    /// Frost (1982): weights `exp(-K ρ² |t|)` decreasing with the distance `|t|` from the centre
+    Frost {
+        /// K, higher keeps more detail
+        damping: f64,
+    },
+}
+
+impl AdaptiveFilter {
+    /// short lower case name
+    pub fn name(self) -> &'static str {
+        match self {
+            AdaptiveFilter::Lee => "lee",
+            AdaptiveFilter::EnhancedLee { .. } => "enhanced-lee",
+            AdaptiveFilter::Kuan => "kuan",
+            AdaptiveFilter::Frost { .. } => "frost",
+        }
+    }
+}
+
+/// Noise model and level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseKind {
+    /// `x + n`, `sigma` on the scale of [`AdaptiveParams::sigma_scale`], 0 = estimated
+    Additive {
+        /// standard deviation of n
+        sigma: f64,
+    },
+    /// speckle `x * (1 + n)`, as [`NoiseModel::Speckle`](crate::utils::noise::NoiseModel::Speckle)
+    Multiplicative {
+        /// standard deviation of n (1/√looks for SAR intensity), 0 = estimated
+        sigma: f64,
+    },
+}
+
+impl Default for NoiseKind {
+    fn default() -> Self {
+        NoiseKind::Additive { sigma: 0.0 }
+    }
+}
+
+/// Parameters of the adaptive local means filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveParams {
+    /// filter
+    pub filter: AdaptiveFilter,
+    /// noise model
+    pub noise: NoiseKind,
+    /// scale of an additive sigma
+    pub sigma_scale: SigmaScale,
+    /// window radius, the window is (2r+1) x (2r+1)
+    pub radius: usize,
+    /// how pixels outside the image are read
+    pub border: BorderMode,
+}
+
+impl Default for AdaptiveParams {
+    fn default() -> Self {
+        AdaptiveParams {
+            filter: AdaptiveFilter::Lee,
+            noise: NoiseKind::default(),
+            sigma_scale: SigmaScale::EightBit,
+            radius: 3,
+            border: BorderMode::Reflect,
+        }
+    }
+}
+
+impl AdaptiveParams {
+    /// Speckle filter for a known (or 0 = estimated) speckle sigma
+    pub fn speckle(filter: AdaptiveFilter, sigma: f64) -> Self {
+        AdaptiveParams { filter, noise: NoiseKind::Multiplicative { sigma }, ..Self::default() }
+    }
+
+    /// Lee filter for additive noise, the window grows with the preset
+    pub fn preset(preset: Preset, sigma: f64) -> Self {
+        let radius = match preset {
+            Preset::Fast => 2,
+            Preset::Default => 3,
+            Preset::HighQuality => 4,
+        };
+        AdaptiveParams { noise: NoiseKind::Additive { sigma }, radius, ..Self::default() }
+    }
+
+    fn validate(&self) -> Result<(), ImageProcessingError> {
+        let sigma = match self.noise {
+            NoiseKind::Additive { sigma } | NoiseKind::Multiplicative { sigma } => sigma,
+        };
+        if !(sigma.is_finite() && sigma >= 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!("noise sigma must be >= 0 (got {})", sigma)));
+        }
+        if self.radius == 0 {
+            return Err(ImageProcessingError::InvalidParameter("window radius must be >= 1".to_string()));
+        }
+        match self.filter {
+            AdaptiveFilter::EnhancedLee { damping } | AdaptiveFilter::Frost { damping }
+                if !(damping.is_finite() && damping > 0.0) =>
+            {
+                Err(ImageProcessingError::InvalidParameter(format!("damping must be > 0 (got {})", damping)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Filter an in-memory image, bit depth and channel layout are kept
+pub fn denoise_image(image: &DynamicImage, params: &AdaptiveParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    /// named after its filter
+    #[derive(Copy, PartialEq)]
+    AdaptiveDenoiser(AdaptiveParams),
+    name = |denoiser| denoiser.params.filter.name()
+}
+
+/// Filter the color channels of a float buffer
+pub fn denoise_buffer(img: &FloatImage, params: &AdaptiveParams) -> Result<FloatImage, ImageProcessingError> {
+    params.validate()?;
+    img.ensure_not_empty()?;
+    let (width, height) = img.dimensions();
+    let multiplicative = matches!(params.noise, NoiseKind::Multiplicative { .. });
+    let sigma = match params.noise {
+        NoiseKind::Additive { sigma } => params.sigma_scale.normalize(sigma, img.depth()),
+        NoiseKind::Multiplicative { sigma } => sigma,
+    };
+
+    let mut out = img.clone();
+    let channels = img.layout().color_channels();
+    for plane in out.planes_mut().iter_mut().take(channels) {
+        let stats = LocalStats::new(plane, width, height, params.radius, params.border);
+        // varianza del rumore stimata come mediana delle varianze locali: le zone piatte sono la maggioranza
+        let cu2 = if sigma > 0.0 { sigma * sigma } else { stats.median_variation(multiplicative) };
+        *plane = match params.filter {
+            AdaptiveFilter::Frost { damping } => frost(plane, width, height, &stats, cu2, multiplicative, damping, params),
+            filter => stats
+                .mean
+                .par_iter()
+                .zip(stats.variance.par_iter())
+                .zip(plane.par_iter())
+                .map(|((&m, &v), &x)| {
+                    let w = blend(filter, ratio2(m, v, cu2, multiplicative), cu2, multiplicative);
+                    (m as f64 + w * (x - m) as f64) as f32
+                })
+                .collect(),
+        };
+    }
+    Ok(out)
+}
+
+/// Local mean and variance of a plane
+struct LocalStats {
+    mean: Vec<f32>,
+    variance: Vec<f32>,
+}
+
+impl LocalStats {
+    fn new(plane: &[f32], width: usize, height: usize, radius: usize, border: BorderMode) -> Self {
+        let mean = box_filter(plane, width, height, radius, border);
+        let squares: Vec<f32> = plane.iter().map(|v| v * v).collect();
+        let variance = box_filter(&squares, width, height, radius, border)
+            .iter()
+            .zip(&mean)
+            .map(|(sq, m)| (sq - m * m).max(0.0))
+            .collect();
+        LocalStats { mean, variance }
+    }
+
+    /// median of v (additive) or v / m² (multiplicative)
+    fn median_variation(&self, multiplicative: bool) -> f64 {
+        let mut values: Vec<f64> = self
+            .mean
+            .iter()
+            .zip(&self.variance)
+            .filter_map(|(&m, &v)| match multiplicative {
+                false => Some(v as f64),
+                true if m > f32::EPSILON => Some(v as f64 / (m as f64 * m as f64)),
+                true => None,
+            })
+            .collect();
+        if values.is_empty() {
+            return 0.0;
+        }
+        let mid = values.len() / 2;
+        *values.select_nth_unstable_by(mid, f64::total_cmp).1
+    }
+}
+
+/// ρ² = Ci² / Cu², infinite when there is no noise
+fn ratio2(m: f32, v: f32, cu2: f64, multiplicative: bool) -> f64 {
+    let ci2 = match multiplicative {
+        true if m > f32::EPSILON => v as f64 / (m as f64 * m as f64),
+        true => 0.0,
+        false => v as f64,
+    };
+    if cu2 > 0.0 { ci2 / cu2 } else { f64::INFINITY }
+}
+
+/// Weight of the pixel against the local mean for the Lee family
+fn blend(filter: AdaptiveFilter, rho2: f64, cu2: f64, multiplicative: bool) -> f64 {
+    let lee = (1.0 - 1.0 / rho2).max(0.0);
+    match filter {
+        AdaptiveFilter::Lee | AdaptiveFilter::Frost { .. } => lee,
+        AdaptiveFilter::Kuan if multiplicative => lee / (1.0 + cu2),
+        AdaptiveFilter::Kuan => lee,
+        AdaptiveFilter::EnhancedLee { damping } => {
+            // Cmax = √(1 + 2 Cu²) per lo speckle, cioè ρmax = Cmax / Cu = √(1 / Cu² + 2);
+            // per il rumore additivo come a un look
+            let rho_max = if multiplicative { (1.0 / cu2 + 2.0).sqrt() } else { 3f64.sqrt() };
+            let rho = rho2.sqrt();
+            if rho <= 1.0 {
+                0.0
+            } else if rho >= rho_max {
+                1.0
+            } else {
+                1.0 - (-damping * (rho - 1.0) / (rho_max - rho)).exp()
+            }
+        }
+    }
+}
+
+/// Frost: exponential kernel whose decay follows the local variation
+#[allow(clippy::too_many_arguments)]
+fn frost(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    stats: &LocalStats,
+    cu2: f64,
+    multiplicative: bool,
+    damping: f64,
+    params: &AdaptiveParams,
+) -> Vec<f32> {
+    let r = params.radius as isize;
+    let mut out = vec![0.0; width * height];
+    out.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, o) in row.iter_mut().enumerate() {
+            let i = y * width + x;
+            let alpha = damping * ratio2(stats.mean[i], stats.variance[i], cu2, multiplicative);
+            if !alpha.is_finite() {
+                *o = plane[i];
+                continue;
+            }
+            let (mut sum, mut total) = (0.0, 0.0);
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let w = (-alpha * ((dx * dx + dy * dy) as f64).sqrt()).exp();
+                    sum += w * params.border.pixel(plane, width, height, x as isize + dx, y as isize + dy) as f64;
+                    total += w;
+                }
+            }
+            *o = (sum / total) as f32;
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::metrics::psnr;
+    use crate::utils::noise::{NoiseModel, NoiseRecord};
+    use crate::utils::testing::assert_invalid;
+
+    /// due zone piatte con un bordo verticale
+    fn clean() -> FloatImage {
+        FloatImage::from_dynamic(&DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(64, 48, |x, _| {
+            image::Luma([if x < 32 { 20000u16 } else { 45000 }])
+        })))
+    }
+
+    #[test]
+    fn test_speckle_filters() {
+        let clean = clean();
+        let record = NoiseRecord { clip: false, ..NoiseRecord::new(NoiseModel::Speckle { sigma: 0.25 }, 3) };
+        let noisy = record.apply(&clean).unwrap();
+        let before = psnr(&clean, &noisy, None).unwrap();
+
+        for filter in [
+            AdaptiveFilter::Lee,
+            AdaptiveFilter::EnhancedLee { damping: 1.0 },
+            AdaptiveFilter::Kuan,
+            AdaptiveFilter::Frost { damping: 0.5 },
+        ] {
+            for sigma in [0.25, 0.0] {
+                let out = denoise_buffer(&noisy, &AdaptiveParams::speckle(filter, sigma)).unwrap();
+                let after = psnr(&clean, &out, None).unwrap();
+                assert!(after > before + 5.0, "{} sigma {}: {:.2} -> {:.2}", filter.name(), sigma, before, after);
+                // il bordo resta netto
+                let (left, right) = (out.get(0, 29, 24), out.get(0, 34, 24));
+                assert!(right - left > 0.25, "{}: {} {}", filter.name(), left, right);
+            }
+        }
+    }
+
+    #[test]
+    fn test_additive_noise_and_estimate() {
+        let clean = clean();
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 20.0 / 255.0 }, 4).apply(&clean).unwrap();
+        let before = psnr(&clean, &noisy, None).unwrap();
+        for sigma in [20.0, 0.0] {
+            let params = AdaptiveParams { noise: NoiseKind::Additive { sigma }, ..AdaptiveParams::default() };
+            let after = psnr(&clean, &denoise_buffer(&noisy, &params).unwrap(), None).unwrap();
+            assert!(after > before + 5.0, "sigma {}: {:.2} -> {:.2}", sigma, before, after);
+        }
+
+        let stats = LocalStats::new(noisy.plane(0), 64, 48, 3, BorderMode::Reflect);
+        let estimated = stats.median_variation(false).sqrt() * 255.0;
+        assert!((estimated - 20.0).abs() < 2.0, "{}", estimated);
+    }
+
+    #[test]
+    fn test_weights() {
+        // rumore puro: media; bordo forte: pixel originale
+        assert_eq!(blend(AdaptiveFilter::Lee, 1.0, 0.04, true), 0.0);
+        assert!((blend(AdaptiveFilter::Lee, 4.0, 0.04, true) - 0.75).abs() < 1e-12);
+        assert!((blend(AdaptiveFilter::Kuan, 4.0, 0.04, true) - 0.75 / 1.04).abs() < 1e-12);
+        assert_eq!(blend(AdaptiveFilter::EnhancedLee { damping: 1.0 }, 0.5, 0.04, true), 0.0);
+        assert_eq!(blend(AdaptiveFilter::EnhancedLee { damping: 1.0 }, 100.0, 0.04, true), 1.0);
+    }
+
+    #[test]
+    fn test_enhanced_lee_upper_bound() {
+        // Cu = 0.25: Cmax = √(1 + 2 Cu²) = 0.3536, ρmax = Cmax / Cu = √18 = 4.243
+        let (filter, cu2) = (AdaptiveFilter::EnhancedLee { damping: 1.0 }, 0.0625);
+        assert_eq!(blend(filter, 4.25 * 4.25, cu2, true), 1.0);
+        // con ρmax = √(1 + 2 / Cu²) = 5.74 sarebbe 0.67
+        let below = blend(filter, 3.5 * 3.5, cu2, true);
+        assert!((below - (1.0 - (-2.5 / (18f64.sqrt() - 3.5)).exp())).abs() < 1e-12, "{}", below);
+        assert!(below > 0.96);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_rgba8(8, 8));
+        assert_invalid(
+            [
+                AdaptiveParams { radius: 0, ..AdaptiveParams::default() },
+                AdaptiveParams::speckle(AdaptiveFilter::Frost { damping: 0.0 }, 0.2),
+                AdaptiveParams::speckle(AdaptiveFilter::Lee, -1.0),
+            ],
+            |params| denoise_buffer(&img, params),
+        );
+        let empty = FloatImage::from_dynamic(&DynamicImage::new_rgba8(0, 0));
+        assert_invalid([AdaptiveParams::default()], |params| denoise_buffer(&empty, params));
+    }
+}
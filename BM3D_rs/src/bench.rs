@@ -0,0 +1,333 @@
+//! benchmark runner: clean images x sigmas x algorithms x presets.
+//!
+//! For every combination the clean image gets seeded gaussian noise, is denoised and compared
+//! with the original. Results carry PSNR/SSIM before and after, runtime and, when the binary
+//! installs [`TrackingAllocator`] as global allocator, the peak heap growth while denoising.
+
+use crate::adaptive_means::{AdaptiveDenoiser, AdaptiveParams};
+use crate::bilateral::{BilateralDenoiser, BilateralParams};
+use crate::bm3d::Bm3dDenoiser;
+use crate::diffusion::{DiffusionDenoiser, DiffusionParams};
+use crate::error::ImageProcessingError;
+use crate::utils::alloc::TrackingAllocator;
+use crate::utils::io::{fit_max_dimension, load_dynamic_image};
+use crate::utils::metrics::compare_images;
+use crate::utils::noise::{NoiseModel, NoiseRecord};
+use crate::guided::{GuidedDenoiser, GuidedParams};
+use crate::nlm::{NlmDenoiser, NlmParams};
+use crate::tv::{TvDenoiser, TvParams};
+use crate::wavelet::{WaveletDenoiser, WaveletParams};
+use crate::{Bm3dParams, Denoiser, DynDenoiser, ParamValue, Parameters, Preset};
+use image::DynamicImage;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Image extensions picked up by [`list_images`]
+const IMAGE_EXTENSIONS: [&str; 8] = ["png", "jpg", "jpeg", "tif", "tiff", "bmp", "webp", "pnm"];
+
+/// Denoising algorithm under test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    /// BM3D step 1 (hard thresholding)
+    Bm3d,
+    /// wavelet shrinkage
+    Wavelet,
+    /// non-local means, integral image variant
+    Nlm,
+    /// Lee adaptive local means
+    Lee,
+    /// bilateral filter
+    Bilateral,
+    /// total variation
+    Tv,
+    /// guided filter
+    Guided,
+    /// Perona-Malik diffusion
+    Diffusion,
+}
+
+impl Algorithm {
+    /// short lower case name
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Bm3d => "bm3d",
+            Algorithm::Wavelet => "wavelet",
+            Algorithm::Nlm => "nlm",
+            Algorithm::Lee => "lee",
+            Algorithm::Bilateral => "bilateral",
+            Algorithm::Tv => "tv",
+            Algorithm::Guided => "guided",
+            Algorithm::Diffusion => "diffusion",
+        }
+    }
+
+    /// The algorithm set up for a gaussian noise of `sigma` (0-255 scale)
+    pub fn denoiser(self, sigma: f64, preset: Preset) -> Box<dyn DynDenoiser> {
+        match self {
+            Algorithm::Bm3d => {
+                let mut params = Bm3dParams::new();
+                preset.apply(&mut params);
+                params.set(Parameters::Sigma, ParamValue::F64(sigma));
+                Box::new(Bm3dDenoiser::new(params))
+            }
+            Algorithm::Wavelet => Box::new(WaveletDenoiser::new(WaveletParams::preset(preset, sigma))),
+            Algorithm::Nlm => Box::new(NlmDenoiser::new(NlmParams::preset(preset, sigma))),
+            Algorithm::Lee => Box::new(AdaptiveDenoiser::new(AdaptiveParams::preset(preset, sigma))),
+            Algorithm::Bilateral => Box::new(BilateralDenoiser::new(BilateralParams::preset(preset, sigma))),
+            Algorithm::Tv => Box::new(TvDenoiser::new(TvParams::preset(preset, sigma))),
+            Algorithm::Guided => Box::new(GuidedDenoiser::new(GuidedParams::preset(preset, sigma))),
+            Algorithm::Diffusion => Box::new(DiffusionDenoiser::new(DiffusionParams::preset(preset, sigma))),
+        }
+    }
+
+    /// Denoise `noisy` for a gaussian noise of `sigma` (0-255 scale)
+    pub fn denoise(self, noisy: &DynamicImage, sigma: f64, preset: Preset) -> Result<DynamicImage, ImageProcessingError> {
+        self.denoiser(sigma, preset).denoise(noisy)
+    }
+}
+
+/// What to run
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchConfig {
+    /// clean images
+    pub images: Vec<PathBuf>,
+    /// noise sigmas, 0-255 scale
+    pub sigmas: Vec<f64>,
+    /// algorithms to compare
+    pub algorithms: Vec<Algorithm>,
+    /// presets of every algorithm
+    pub presets: Vec<Preset>,
+    /// seed of the noise, the same noisy image is given to every algorithm
+    pub seed: u64,
+    /// images are shrunk to this size first (0 = keep the original size)
+    pub max_dimension: u32,
+}
+
+impl BenchConfig {
+    /// BM3D with the default preset, seed 0 and no resize
+    pub fn new(images: Vec<PathBuf>, sigmas: Vec<f64>) -> Self {
+        BenchConfig {
+            images,
+            sigmas,
+            algorithms: vec![Algorithm::Bm3d],
+            presets: vec![Preset::Default],
+            seed: 0,
+            max_dimension: 0,
+        }
+    }
+}
+
+/// One row of the result table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchResult {
+    /// file name of the clean image
+    pub image: String,
+    /// width after the resize
+    pub width: u32,
+    /// height after the resize
+    pub height: u32,
+    /// noise sigma, 0-255 scale
+    pub sigma: f64,
+    /// algorithm name
+    pub algorithm: &'static str,
+    /// preset name
+    pub preset: &'static str,
+    /// seed of the noise
+    pub seed: u64,
+    /// PSNR of the noisy image in dB
+    pub noisy_psnr: f64,
+    /// SSIM of the noisy image
+    pub noisy_ssim: f64,
+    /// PSNR of the denoised image in dB
+    pub psnr: f64,
+    /// SSIM of the denoised image
+    pub ssim: f64,
+    /// denoising time in seconds
+    pub seconds: f64,
+    /// peak heap growth while denoising, `None` without the tracking allocator
+    pub peak_memory_bytes: Option<usize>,
+}
+
+/// Images of a folder, sorted by name
+pub fn list_images<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, ImageProcessingError> {
+    let mut images = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let known = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+        if path.is_file() && known {
+            images.push(path);
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+/// Run every combination, `on_result` is called as soon as a row is ready
+pub fn run(config: &BenchConfig, mut on_result: impl FnMut(&BenchResult)) -> Result<Vec<BenchResult>, ImageProcessingError> {
+    if config.sigmas.iter().any(|s| s.is_nan() || *s <= 0.0) {
+        return Err(ImageProcessingError::InvalidParameter(format!("sigmas must be > 0 ({:?})", config.sigmas)));
+    }
+
+    let mut results = Vec::new();
+    for path in &config.images {
+        let clean = fit_max_dimension(load_dynamic_image(path)?, config.max_dimension);
+        let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+
+        for &sigma in &config.sigmas {
+            let record = NoiseRecord::new(NoiseModel::Gaussian { sigma: sigma / 255.0 }, config.seed);
+            let noisy = record.apply_dynamic(&clean)?;
+            let noisy_quality = compare_images(&clean, &noisy, None)?;
+
+            for &algorithm in &config.algorithms {
+                for &preset in &config.presets {
+                    let baseline = TrackingAllocator::current();
+                    TrackingAllocator::reset_peak();
+                    let start = Instant::now();
+                    let denoised = algorithm.denoise(&noisy, sigma, preset)?;
+                    let seconds = start.elapsed().as_secs_f64();
+                    let peak_memory_bytes =
+                        TrackingAllocator::is_installed().then(|| TrackingAllocator::peak().saturating_sub(baseline));
+
+                    let quality = compare_images(&clean, &denoised, None)?;
+                    let result = BenchResult {
+                        image: name.clone(),
+                        width: clean.width(),
+                        height: clean.height(),
+                        sigma,
+                        algorithm: algorithm.name(),
+                        preset: preset.name(),
+                        seed: config.seed,
+                        noisy_psnr: noisy_quality.psnr,
+                        noisy_ssim: noisy_quality.ssim,
+                        psnr: quality.psnr,
+                        ssim: quality.ssim,
+                        seconds,
+                        peak_memory_bytes,
+                    };
+                    on_result(&result);
+                    results.push(result);
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Write the results as CSV with a header row
+pub fn write_csv<W: Write>(results: &[BenchResult], mut out: W) -> Result<(), ImageProcessingError> {
+    writeln!(
+        out,
+        "image,width,height,sigma,algorithm,preset,seed,noisy_psnr,noisy_ssim,psnr,ssim,seconds,peak_memory_bytes"
+    )?;
+    for r in results {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{:.4},{:.5},{:.4},{:.5},{:.4},{}",
+            csv_field(&r.image),
+            r.width,
+            r.height,
+            r.sigma,
+            r.algorithm,
+            r.preset,
+            r.seed,
+            r.noisy_psnr,
+            r.noisy_ssim,
+            r.psnr,
+            r.ssim,
+            r.seconds,
+            r.peak_memory_bytes.map_or_else(String::new, |b| b.to_string()),
+        )?;
+    }
+    Ok(())
+}
+
+/// Write the results as a pretty printed JSON array
+pub fn write_json<W: Write>(results: &[BenchResult], out: W) -> Result<(), ImageProcessingError> {
+    serde_json::to_writer_pretty(out, results).map_err(|e| ImageProcessingError::Other(format!("JSON: {}", e)))
+}
+
+/// quote a CSV field when needed
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_small_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let clean = image::RgbImage::from_fn(48, 48, |x, y| image::Rgb([(x * 5) as u8, (y * 5) as u8, 128]));
+        clean.save(dir.path().join("gradient.png")).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not an image").unwrap();
+
+        let images = list_images(dir.path()).unwrap();
+        assert_eq!(images.len(), 1);
+
+        let mut config = BenchConfig::new(images, vec![15.0, 30.0]);
+        config.presets = vec![Preset::Fast, Preset::Default];
+        let mut seen = 0;
+        let results = run(&config, |_| seen += 1).unwrap();
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(seen, 4);
+        for r in &results {
+            assert_eq!(r.image, "gradient.png");
+            assert!(r.psnr > r.noisy_psnr, "{:?}", r);
+            assert!(r.seconds > 0.0);
+        }
+        // stesso seed: stessa immagine rumorosa per tutti i preset
+        assert_eq!(results[0].noisy_psnr, results[1].noisy_psnr);
+
+        let mut csv = Vec::new();
+        write_csv(&results, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.starts_with("image,width,height,sigma"));
+
+        let mut json = Vec::new();
+        write_json(&results, &mut json).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed.as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_every_algorithm_behind_the_trait() {
+        let all = [
+            Algorithm::Bm3d,
+            Algorithm::Wavelet,
+            Algorithm::Nlm,
+            Algorithm::Lee,
+            Algorithm::Bilateral,
+            Algorithm::Tv,
+            Algorithm::Guided,
+            Algorithm::Diffusion,
+        ];
+        let img = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(48, 40, |x, y| {
+            image::Rgba([(x * 1000) as u16, (y * 1500) as u16, 30000, 65535])
+        }));
+        for algorithm in all {
+            let denoiser = algorithm.denoiser(20.0, Preset::Fast);
+            assert_eq!(denoiser.name(), algorithm.name());
+            let out = denoiser.denoise(&img).unwrap();
+            assert_eq!((out.color(), out.width(), out.height()), (img.color(), 48, 40), "{}", algorithm.name());
+        }
+    }
+
+    #[test]
+    fn test_csv_field_quoting() {
+        assert_eq!(csv_field("plain.png"), "plain.png");
+        assert_eq!(csv_field("a,b.png"), "\"a,b.png\"");
+        assert_eq!(csv_field("say \"hi\".png"), "\"say \"\"hi\"\".png\"");
+    }
+}
@@ -0,0 +1,435 @@
+//! Bilateral and joint (cross) bilateral filters.
+//! params:
+//! - spatial sigma in pixels, range sigma on the sample scale
+//! - variant: brute force reference or bilateral grid
+//! - optional guide image for the joint filter
+//!
+//! Weights are `exp(-|p - q|² / 2σs²) · exp(-|g(p) - g(q)|² / 2σr²)` where `g` is the guide,
+//! the image itself unless a guide is given. The brute force variant compares every color channel
+//! of the guide; the bilateral grid (Paris, Durand) works on the mean of the guide channels, with
+//! σr divided by √channels so that a gray edge weighs the same, splats the image in a
+//! (x/σs, y/σs, g/σr) grid, blurs it and slices it back with trilinear interpolation.
+//! Pixels outside the image do not contribute. Alpha is copied unchanged.
+//!
+//! The grid has one range cell per σr over the span of the guide, so a wide range (float or
+//! HDR guides) or a small σr makes it grow without bound: above `GRID_CELLS_PER_PIXEL` cells
+//! per pixel the filter falls back to the brute force variant. Non-finite guide samples are
+//! rejected.
+
+use crate::error::ImageProcessingError;
+use crate::utils::buffer::{FloatImage, SigmaScale};
+use crate::Preset;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Cells added around the grid so that the blur never reads outside it
+const GRID_PADDING: usize = 2;
+
+/// Grid cells allowed per image pixel (16 bytes each) before falling back to brute force
+const GRID_CELLS_PER_PIXEL: usize = 4;
+
+/// Cells always allowed, so that small images keep the grid
+const GRID_MIN_CELLS: usize = 1 << 20;
+
+/// How the filter is evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum BilateralVariant {
+    /// every neighbour in a radius of 2σs, O(σs²) per pixel; the reference
+    BruteForce,
+    /// bilateral grid downsampled by σs and σr, about O(1) per pixel
+    #[default]
+    Grid,
+}
+
+/// Parameters of the bilateral filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BilateralParams {
+    /// spatial standard deviation in pixels
+    pub sigma_spatial: f64,
+    /// range standard deviation
+    pub sigma_range: f64,
+    /// scale on which `sigma_range` is expressed
+    pub sigma_scale: SigmaScale,
+    /// brute force or grid
+    pub variant: BilateralVariant,
+}
+
+impl Default for BilateralParams {
+    fn default() -> Self {
+        BilateralParams {
+            sigma_spatial: 3.0,
+            sigma_range: 30.0,
+            sigma_scale: SigmaScale::EightBit,
+            variant: BilateralVariant::Grid,
+        }
+    }
+}
+
+impl BilateralParams {
+    /// Range sigma of twice the noise sigma, spatial sigma and variant from the preset
+    pub fn preset(preset: Preset, sigma: f64) -> Self {
+        let base = BilateralParams { sigma_range: 2.0 * sigma, ..Self::default() };
+        match preset {
+            Preset::Fast => BilateralParams { sigma_spatial: 2.0, ..base },
+            Preset::Default => base,
+            Preset::HighQuality => BilateralParams { variant: BilateralVariant::BruteForce, ..base },
+        }
+    }
+
+    fn validate(&self) -> Result<(), ImageProcessingError> {
+        if !(self.sigma_spatial.is_finite() && self.sigma_spatial > 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "spatial sigma must be > 0 (got {})",
+                self.sigma_spatial
+            )));
+        }
+        if !(self.sigma_range.is_finite() && self.sigma_range > 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "range sigma must be > 0 (got {})",
+                self.sigma_range
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Filter an in-memory image, bit depth and channel layout are kept
+pub fn denoise_image(image: &DynamicImage, params: &BilateralParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    /// the image guides itself
+    #[derive(Copy, PartialEq)]
+    BilateralDenoiser(BilateralParams),
+    name = "bilateral"
+}
+
+/// Filter `image` with edges taken from `guide`, which must have the same size
+pub fn joint_image(image: &DynamicImage, guide: &DynamicImage, params: &BilateralParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    let guide = FloatImage::from_dynamic(guide);
+    Ok(joint_buffer(&buffer, &guide, params)?.to_dynamic())
+}
+
+/// Filter a float buffer guided by itself
+pub fn denoise_buffer(img: &FloatImage, params: &BilateralParams) -> Result<FloatImage, ImageProcessingError> {
+    joint_buffer(img, img, params)
+}
+
+/// Filter the color channels of `img` with range weights computed on `guide`
+pub fn joint_buffer(img: &FloatImage, guide: &FloatImage, params: &BilateralParams) -> Result<FloatImage, ImageProcessingError> {
+    params.validate()?;
+    if img.dimensions() != guide.dimensions() {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "guide is {}x{}, image is {}x{}",
+            guide.width(),
+            guide.height(),
+            img.width(),
+            img.height()
+        )));
+    }
+    img.ensure_not_empty()?;
+    // σr sulla scala della guida
+    let sigma_range = params.sigma_scale.normalize(params.sigma_range, guide.depth());
+    let channels = img.layout().color_channels();
+    let planes: Vec<&[f32]> = img.planes().iter().take(channels).map(|p| p.as_slice()).collect();
+    let guides: Vec<&[f32]> = guide.planes().iter().take(guide.layout().color_channels()).map(|p| p.as_slice()).collect();
+    if guides.iter().any(|g| g.iter().any(|v| !v.is_finite())) {
+        return Err(ImageProcessingError::InvalidParameter("guide has NaN or infinite samples".to_string()));
+    }
+
+    let (width, height) = img.dimensions();
+    let brute = || brute_force(&planes, &guides, width, height, params.sigma_spatial, sigma_range);
+    let filtered = match params.variant {
+        BilateralVariant::BruteForce => brute(),
+        BilateralVariant::Grid => {
+            let luma = luminance(&guides);
+            let sigma_range = sigma_range / (guides.len() as f64).sqrt();
+            match GridShape::new(&luma, width, height, params.sigma_spatial, sigma_range) {
+                Some(shape) => planes.iter().map(|plane| grid(plane, &luma, width, &shape)).collect(),
+                // troppe celle sull'asse del range: il filtro esatto non alloca la griglia
+                None => brute(),
+            }
+        }
+    };
+
+    let mut out = img.clone();
+    for (plane, filtered) in out.planes_mut().iter_mut().zip(filtered) {
+        *plane = filtered;
+    }
+    Ok(out)
+}
+
+/// Reference bilateral filter, rows in parallel
+fn brute_force(planes: &[&[f32]], guides: &[&[f32]], width: usize, height: usize, sigma_s: f64, sigma_r: f64) -> Vec<Vec<f32>> {
+    let radius = (2.0 * sigma_s).ceil() as isize;
+    let spatial: Vec<f64> = (-radius..=radius)
+        .flat_map(|dy| (-radius..=radius).map(move |dx| ((dx * dx + dy * dy) as f64 / (-2.0 * sigma_s * sigma_s)).exp()))
+        .collect();
+    let side = (2 * radius + 1) as usize;
+
+    let mut interleaved = vec![0.0f32; width * height * planes.len()];
+    interleaved.par_chunks_mut(width * planes.len()).enumerate().for_each(|(y, row)| {
+        let mut sum = vec![0.0f64; planes.len()];
+        for (x, out) in row.chunks_mut(planes.len()).enumerate() {
+            let p = y * width + x;
+            sum.iter_mut().for_each(|s| *s = 0.0);
+            let mut total = 0.0;
+            for dy in -radius..=radius {
+                let qy = y as isize + dy;
+                if !(0..height as isize).contains(&qy) {
+                    continue;
+                }
+                for dx in -radius..=radius {
+                    let qx = x as isize + dx;
+                    if !(0..width as isize).contains(&qx) {
+                        continue;
+                    }
+                    let q = qy as usize * width + qx as usize;
+                    let range: f64 = guides.iter().map(|g| ((g[p] - g[q]) as f64).powi(2)).sum();
+                    let w = spatial[(dy + radius) as usize * side + (dx + radius) as usize]
+                        * (range / (-2.0 * sigma_r * sigma_r)).exp();
+                    for (s, plane) in sum.iter_mut().zip(planes) {
+                        *s += w * plane[q] as f64;
+                    }
+                    total += w;
+                }
+            }
+            for (o, s) in out.iter_mut().zip(&sum) {
+                *o = (s / total) as f32;
+            }
+        }
+    });
+
+    (0..planes.len())
+        .map(|c| interleaved.iter().skip(c).step_by(planes.len()).copied().collect())
+        .collect()
+}
+
+/// Mean of the guide channels, the range axis of the grid
+fn luminance(guides: &[&[f32]]) -> Vec<f32> {
+    let n = guides.len() as f32;
+    (0..guides[0].len()).map(|i| guides.iter().map(|g| g[i]).sum::<f32>() / n).collect()
+}
+
+/// Sampling of the bilateral grid over a finite guide
+struct GridShape {
+    /// lowest guide sample, z = 0 before padding
+    min: f32,
+    sigma_s: f64,
+    sigma_r: f64,
+    /// cells along x, y and the range axis
+    dims: [usize; 3],
+}
+
+impl GridShape {
+    /// None when the grid would need more than `GRID_CELLS_PER_PIXEL` cells per pixel
+    fn new(guide: &[f32], width: usize, height: usize, sigma_s: f64, sigma_r: f64) -> Option<Self> {
+        let (min, max) = guide.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &g| (lo.min(g), hi.max(g)));
+        let budget = (width * height).saturating_mul(GRID_CELLS_PER_PIXEL).max(GRID_MIN_CELLS);
+        // le estensioni sono confrontate in f64 prima di diventare usize
+        let size = |extent: f64| {
+            let cells = extent.ceil() + 1.0 + 2.0 * GRID_PADDING as f64;
+            (cells <= budget as f64).then_some(cells as usize)
+        };
+        let dims = [
+            size((width - 1) as f64 / sigma_s)?,
+            size((height - 1) as f64 / sigma_s)?,
+            size((max - min) as f64 / sigma_r)?,
+        ];
+        let cells = dims.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d))?;
+        (cells <= budget).then_some(GridShape { min, sigma_s, sigma_r, dims })
+    }
+}
+
+/// Bilateral grid: trilinear splat, [1 4 6 4 1] blur on the three axes, trilinear slice
+fn grid(plane: &[f32], guide: &[f32], width: usize, shape: &GridShape) -> Vec<f32> {
+    let GridShape { min, sigma_s, sigma_r, dims } = *shape;
+    let height = plane.len() / width;
+    let pad = GRID_PADDING as f64;
+    let coords = |x: usize, y: usize, g: f32| {
+        (x as f64 / sigma_s + pad, y as f64 / sigma_s + pad, (g - min) as f64 / sigma_r + pad)
+    };
+    let index = |x: usize, y: usize, z: usize| (z * dims[1] + y) * dims[0] + x;
+
+    // (valore, peso) per cella
+    let mut cells = vec![[0.0f64; 2]; dims[0] * dims[1] * dims[2]];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let (gx, gy, gz) = coords(x, y, guide[i]);
+            for (cx, cy, cz, w) in trilinear(gx, gy, gz) {
+                let cell = &mut cells[index(cx, cy, cz)];
+                cell[0] += w * plane[i] as f64;
+                cell[1] += w;
+            }
+        }
+    }
+
+    for axis in 0..3 {
+        blur_axis(&mut cells, dims, axis);
+    }
+
+    let mut out = vec![0.0; width * height];
+    out.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, o) in row.iter_mut().enumerate() {
+            let i = y * width + x;
+            let (gx, gy, gz) = coords(x, y, guide[i]);
+            let (mut value, mut weight) = (0.0, 0.0);
+            for (cx, cy, cz, w) in trilinear(gx, gy, gz) {
+                let cell = cells[index(cx, cy, cz)];
+                value += w * cell[0];
+                weight += w * cell[1];
+            }
+            *o = if weight > 0.0 { (value / weight) as f32 } else { plane[i] };
+        }
+    });
+    out
+}
+
+/// The 8 cells around a point with their trilinear weights
+fn trilinear(x: f64, y: f64, z: f64) -> impl Iterator<Item = (usize, usize, usize, f64)> {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+    (0..8).map(move |corner| {
+        let (dx, dy, dz) = (corner & 1, (corner >> 1) & 1, corner >> 2);
+        let w = if dx == 1 { fx } else { 1.0 - fx } * if dy == 1 { fy } else { 1.0 - fy } * if dz == 1 { fz } else { 1.0 - fz };
+        (x0 as usize + dx, y0 as usize + dy, z0 as usize + dz, w)
+    })
+}
+
+/// [1 4 6 4 1] / 16 along one axis (gaussian of one cell), lines in parallel
+fn blur_axis(cells: &mut [[f64; 2]], dims: [usize; 3], axis: usize) {
+    const KERNEL: [f64; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+    let stride = match axis {
+        0 => 1,
+        1 => dims[0],
+        _ => dims[0] * dims[1],
+    };
+    let len = dims[axis];
+    let starts: Vec<usize> = (0..cells.len()).filter(|i| (i / stride) % len == 0).collect();
+    let source = cells.to_vec();
+    let lines: Vec<Vec<[f64; 2]>> = starts
+        .par_iter()
+        .map(|&start| {
+            (0..len)
+                .map(|k| {
+                    let mut acc = [0.0; 2];
+                    for (t, w) in KERNEL.iter().enumerate() {
+                        // il padding della griglia è vuoto: fuori dalla linea non c'è nulla
+                        if let Some(j) = (k + t).checked_sub(2).filter(|&j| j < len) {
+                            let cell = source[start + j * stride];
+                            acc[0] += w * cell[0];
+                            acc[1] += w * cell[1];
+                        }
+                    }
+                    acc
+                })
+                .collect()
+        })
+        .collect();
+    for (start, line) in starts.iter().zip(lines) {
+        for (k, cell) in line.into_iter().enumerate() {
+            cells[start + k * stride] = cell;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::metrics::psnr;
+    use crate::utils::noise::{NoiseModel, NoiseRecord};
+    use crate::utils::testing::{assert_invalid, step};
+
+    #[test]
+    fn test_variants_denoise_and_keep_edges() {
+        let clean = step(60, 40, image::Luma([15000]), image::Luma([50000]));
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 15.0 / 255.0 }, 8).apply(&clean).unwrap();
+        let before = psnr(&clean, &noisy, None).unwrap();
+
+        let brute = BilateralParams { variant: BilateralVariant::BruteForce, ..BilateralParams::default() };
+        let reference = denoise_buffer(&noisy, &brute).unwrap();
+        let fast = denoise_buffer(&noisy, &BilateralParams::default()).unwrap();
+        for out in [&reference, &fast] {
+            let after = psnr(&clean, out, None).unwrap();
+            assert!(after > before + 6.0, "{:.2} -> {:.2}", before, after);
+        }
+        // la griglia approssima il filtro esatto
+        let diff = reference.plane(0).iter().zip(fast.plane(0)).map(|(a, b)| (a - b).abs()).sum::<f32>() / (60.0 * 40.0);
+        assert!(diff < 0.01, "{}", diff);
+    }
+
+    #[test]
+    fn test_joint_filter_follows_the_guide() {
+        // immagine senza bordi, guida con un bordo: il bordo della guida blocca la media
+        let guide = step(40, 30, image::Luma([15000]), image::Luma([50000]));
+        let flat = FloatImage::from_dynamic(&DynamicImage::ImageLuma8(image::GrayImage::from_fn(40, 30, |x, _| {
+            image::Luma([if x % 2 == 0 { 0 } else { 255 }])
+        })));
+        for variant in [BilateralVariant::BruteForce, BilateralVariant::Grid] {
+            let params = BilateralParams { sigma_range: 10.0, variant, ..BilateralParams::default() };
+            let out = joint_buffer(&flat, &guide, &params).unwrap();
+            assert!((out.get(0, 5, 15) - 0.5).abs() < 0.1, "{:?}", variant);
+        }
+        let small = FloatImage::from_dynamic(&DynamicImage::new_luma8(4, 4));
+        assert!(joint_buffer(&flat, &small, &BilateralParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_color_keeps_layout_and_constants() {
+        let rgba = DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(24, 16, image::Rgba([3000, 30000, 60000, 999])));
+        for variant in [BilateralVariant::BruteForce, BilateralVariant::Grid] {
+            let out = denoise_image(&rgba, &BilateralParams { variant, ..BilateralParams::default() }).unwrap();
+            for (a, b) in out.as_rgba16().unwrap().pixels().zip(rgba.as_rgba16().unwrap().pixels()) {
+                assert!(a.0.iter().zip(b.0).all(|(a, b)| a.abs_diff(b) <= 1), "{:?}: {:?} {:?}", variant, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_range_guide_falls_back_to_brute_force() {
+        // HDR: 0..1000 con σr = 30/255, decine di migliaia di celle sull'asse del range
+        let hdr = FloatImage::from_dynamic(&DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(64, 48, |x, y| {
+            let v = if x + y / 2 < 32 { 0.5 } else { 1000.0 } + ((x * 7 + y * 3) % 5) as f32 * 0.01;
+            image::Rgb([v, v, v])
+        })));
+        let luma = luminance(&hdr.planes().iter().map(|p| p.as_slice()).collect::<Vec<_>>());
+        assert!(GridShape::new(&luma, 64, 48, 3.0, (30.0 / 255.0) / 3f64.sqrt()).is_none());
+
+        let brute = BilateralParams { variant: BilateralVariant::BruteForce, ..BilateralParams::default() };
+        let expected = denoise_buffer(&hdr, &brute).unwrap();
+        let out = denoise_buffer(&hdr, &BilateralParams::default()).unwrap();
+        assert_eq!(out.planes(), expected.planes());
+    }
+
+    #[test]
+    fn test_non_finite_guide_is_rejected() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_rgb32f(8, 8));
+        for bad in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN] {
+            let mut guide = img.clone();
+            guide.plane_mut(1)[9] = bad;
+            assert_invalid([BilateralVariant::BruteForce, BilateralVariant::Grid], |&variant| {
+                joint_buffer(&img, &guide, &BilateralParams { variant, ..BilateralParams::default() })
+            });
+        }
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_luma8(4, 4));
+        assert_invalid(
+            [
+                BilateralParams { sigma_spatial: 0.0, ..BilateralParams::default() },
+                BilateralParams { sigma_range: -1.0, ..BilateralParams::default() },
+            ],
+            |params| denoise_buffer(&img, params),
+        );
+        let empty = FloatImage::from_dynamic(&DynamicImage::new_luma8(0, 0));
+        assert_invalid([BilateralVariant::BruteForce, BilateralVariant::Grid], |&variant| {
+            joint_buffer(&empty, &empty, &BilateralParams { variant, ..BilateralParams::default() })
+        });
+    }
+}
@@ -2,7 +2,7 @@
 //! params:
 //! -mix: mixing factor between original image and reconstructed image, blending
 
-use crate::error::AggError;
+use crate::error::ImageProcessingError;
 
 
 /// Reconstructs two images (f64 pixel vectors) using a blending factor.
@@ -14,17 +14,17 @@ use crate::error::AggError;
 ///
 /// # Returns
 /// - `Ok(image)`: image resulting from blending.
-/// - `Err(Bm3dError)`: in case of size errors or other errors.
+/// - `Err(ImageProcessingError)`: in case of size errors or an invalid mix factor.
 pub fn aggregate(
     original: &[f64],
     reconstructed: &[f64],
     mix: f64,
-) -> Result<Vec<f64>, AggError> {
-    if mix < 0.0 || mix > 1.0 {
-        return Err(AggError::InvalidMixFactor(mix));
+) -> Result<Vec<f64>, ImageProcessingError> {
+    if !(0.0..=1.0).contains(&mix) {
+        return Err(ImageProcessingError::InvalidMixFactor(mix));
     }
     if original.len() != reconstructed.len() {
-        return Err(AggError::DimensionMismatch{ 
+        return Err(ImageProcessingError::DimensionMismatch{ 
             a: original.len(), 
             b: reconstructed.len() 
         });
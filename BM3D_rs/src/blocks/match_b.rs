@@ -4,17 +4,13 @@
 
 use std::cmp::Ordering;
 use crate::error::ImageProcessingError;
+use crate::utils::buffer::FloatImage;
 use crate::Margin;
 
-use zune_image::{
-    image::Image,
-    codecs::bmp::zune_core::colorspace::ColorSpace,
-};
-
 /// Find the search window whose center is the reference block in *Img*.
 /// Note that the center of the search window is not always the reference block due to image borders.
 pub fn search_window(
-    img: &Image, 
+    img: &FloatImage, 
     ref_point: (usize, usize),
     block_size: usize,
     window_size: usize
@@ -22,7 +18,7 @@ pub fn search_window(
     // Ensure block size is smaller than window size
     if block_size >= window_size {
         return Err(ImageProcessingError::InvalidParameter(
-            "Invalid Image size, block size must be smaller than window size"
+            "Invalid Image size, block size must be smaller than window size".to_string()
         ));
     }
 
@@ -61,16 +57,10 @@ pub struct Patch {
     pub data: Vec<f32>, // Patch data, e.g. flattened block
 }
 
-#[derive(Debug)]
-struct MatchedPatch {
-    pub patch: Patch,
-    pub distance: f32,
-}
-
 /// Extract a patch (block) from the image at the specified top-left coordinate
-/// The patch contains all YCbCr channels flattened into a single array.
-/// [Y data][Cb data][Cr data]
-pub fn extract_patch(img: &Image, top_left: (usize, usize), block_size: usize, ignore_alpha: bool) -> Option<Patch> {
+/// The patch contains all channels flattened into a single array.
+/// [c0 data][c1 data][c2 data], samples on the normalized [0, 1] scale.
+pub fn extract_patch(img: &FloatImage, top_left: (usize, usize), block_size: usize, ignore_alpha: bool) -> Option<Patch> {
     let (width, height) = img.dimensions();
 
     // Don't go out of image bounds
@@ -78,48 +68,45 @@ pub fn extract_patch(img: &Image, top_left: (usize, usize), block_size: usize, i
         return None;
     }
 
-    // Get the flattened image data
-    let data = img.flatten_to_u8();
-    if data.is_empty() {
-        return None;
-    }
-
-    // Get number of channels from colorspace
-    let colorspace = img.colorspace();
-    let channels_count = match colorspace {
-        ColorSpace::RGB => 3,
-        ColorSpace::RGBA => if ignore_alpha { 3 } else { 4 },
-        ColorSpace::YCbCr => 3,
-        ColorSpace::YCCK => 4,
-        ColorSpace::CMYK => 4,
-        ColorSpace::Luma => 1,
-        ColorSpace::LumaA => if ignore_alpha { 1 } else { 2 },
-        _ => 3, // default fallback
-    };
-
+    let channels_count = patch_channels(img, ignore_alpha);
     let mut patch_data = Vec::with_capacity(block_size * block_size * channels_count);
 
     // Extract patch data for each channel
-    for channel_idx in 0..channels_count.min(data.len()) {
-        let channel_data = &data[channel_idx];
+    for channel_data in img.planes().iter().take(channels_count) {
         for y in 0..block_size {
-            for x in 0..block_size {
-                let pixel_x = top_left.0 + x;
-                let pixel_y = top_left.1 + y;
-                let idx = pixel_y * width + pixel_x;
-                
-                if idx < channel_data.len() {
-                    patch_data.push(channel_data[idx] as f32);
-                } else {
-                    return None; // out of bounds
-                }
-            }
+            let row = (top_left.1 + y) * width + top_left.0;
+            patch_data.extend_from_slice(&channel_data[row..row + block_size]);
         }
     }
-    
+
     Some(Patch { top_left, data: patch_data })
 }
 
+/// Number of channels that take part in matching
+fn patch_channels(img: &FloatImage, ignore_alpha: bool) -> usize {
+    let layout = img.layout();
+    if ignore_alpha { layout.color_channels() } else { layout.channels() }
+}
+
+/// L2 distance between `reference` and the block at `top_left`, without extracting it.
+/// `channels` is the number of planes compared, as in the reference patch.
+pub fn l2_distance_at(img: &FloatImage, reference: &Patch, top_left: (usize, usize), block_size: usize, channels: usize) -> f32 {
+    let width = img.width();
+    let mut dist = 0.0;
+    for (c, channel_data) in img.planes().iter().take(channels).enumerate() {
+        let ref_block = &reference.data[c * block_size * block_size..(c + 1) * block_size * block_size];
+        for (y, ref_row) in ref_block.chunks(block_size).enumerate() {
+            let row = (top_left.1 + y) * width + top_left.0;
+            dist += channel_data[row..row + block_size]
+                .iter()
+                .zip(ref_row)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>();
+        }
+    }
+    dist
+}
+
 /// Compute the L2 (Euclidean) distance between two patches
 pub fn l2_patch_distance(a: &Patch, b: &Patch) -> f32 {
     a.data.iter()
@@ -129,9 +116,9 @@ pub fn l2_patch_distance(a: &Patch, b: &Patch) -> f32 {
 }
 
 /// Find the most similar patches to the reference patch inside its search window.
-/// Returns up to max_patches_per_group patches sorted by similarity.
+/// Returns up to max_patches_per_group patches sorted by similarity, starting with the reference patch.
 pub fn find_similar_patches(
-    img: &Image,
+    img: &FloatImage,
     ref_point: (usize, usize),
     block_size: usize,
     window_size: usize,
@@ -143,33 +130,56 @@ pub fn find_similar_patches(
 
     // 2. Extract the reference patch
     let reference_patch = extract_patch(img, ref_point, block_size, ignore_alpha)
-        .ok_or(ImageProcessingError::Other("Reference patch invalid"))?;
-    
+        .ok_or_else(|| ImageProcessingError::OutOfBounds("Reference patch invalid".to_string()))?;
+    let channels = patch_channels(img, ignore_alpha);
+
     // 3. For every possible patch in the search window, compute similarity to the reference patch
-    let mut candidates: Vec<MatchedPatch> = Vec::new();
-    
+    let mut candidates: Vec<((usize, usize), f32)> = Vec::new();
+
     let start_y = margin.top_left.1.max(0) as usize;
     let start_x = margin.top_left.0.max(0) as usize;
     let end_y = (margin.bottom_right.1 as usize).saturating_sub(block_size);
     let end_x = (margin.bottom_right.0 as usize).saturating_sub(block_size);
-    
+
     for y in start_y..=end_y {
         for x in start_x..=end_x {
-            if let Some(patch) = extract_patch(img, (x, y), block_size, ignore_alpha) {
-                let dist = l2_patch_distance(&reference_patch, &patch);
-                candidates.push(MatchedPatch { patch, distance: dist });
+            if (x, y) == ref_point {
+                continue;
             }
+            let dist = l2_distance_at(img, &reference_patch, (x, y), block_size, channels);
+            candidates.push(((x, y), dist));
         }
     }
-    
-    // 4. Sort patches by increasing distance (most similar first)
-    candidates.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal));
 
-    // 5. Take the top max_patches_per_group patches
-    let matched_patches = candidates.into_iter()
-        .take(max_patches_per_group)
-        .map(|mp| mp.patch)
+    // 4. Sort patches by increasing distance (most similar first)
+    candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+
+    // 5. Take the top max_patches_per_group patches, the reference always comes first
+    //    so that every reference block is covered even when many candidates tie at distance 0
+    let matched_patches = std::iter::once(reference_patch)
+        .chain(candidates.into_iter()
+            .take(max_patches_per_group.saturating_sub(1))
+            .filter_map(|(top_left, _)| extract_patch(img, top_left, block_size, ignore_alpha)))
         .collect();
 
     Ok(matched_patches)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::buffer::{BitDepth, ChannelLayout};
+
+    #[test]
+    fn test_reference_is_always_in_its_group() {
+        // immagine piatta: tutti i candidati hanno distanza 0. Ordinando solo per
+        // distanza il gruppo prendeva i primi blocchi in ordine di scansione e il
+        // blocco di riferimento restava fuori, senza mai essere ricostruito
+        let img = FloatImage::new(24, 24, ChannelLayout::Luma, BitDepth::U8);
+        let reference = (12, 12);
+        let group = find_similar_patches(&img, reference, 4, 16, 4, false).unwrap();
+        assert_eq!(group.len(), 4);
+        assert_eq!(group[0].top_left, reference);
+        assert!(group[1..].iter().all(|p| p.top_left != reference));
+    }
+}
@@ -1,31 +1,36 @@
 use crate::{
     blocks::match_b::Patch,
+    color::format::{from_color_space, to_color_space, ColorTransform},
+    color::transfer::TransferFunction,
     error::ImageProcessingError,
     threshold::hard::hard_threshold,
     transform::dct::{Dct2D, IDct2D},
-    utils::metrics::load_dynamic_image,
+    utils::buffer::{ChannelLayout, FloatImage, SigmaScale},
+    utils::io::{fit_max_dimension, load_dynamic_image, save_dynamic_image, SaveOutcome},
+    Bm3dParams, ParamValue, Parameters,
 };
+use image::DynamicImage;
 use rayon::prelude::*;
+use serde::Serialize;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
-use zune_image::{codecs::bmp::zune_core::colorspace::ColorSpace, image::Image};
 
-fn setup_rayon() {
-    let num_cpus = num_cpus::get();
-    println!("Detected {} CPU cores", num_cpus);
-
-    // Usa tutti i core tranne uno per il sistema
-    let threads_to_use = (num_cpus - 1).max(1);
+/// Threads used for `Threads = 0`: all the cores but one, left to the system
+pub fn default_threads() -> usize {
+    (num_cpus::get() - 1).max(1)
+}
 
-    if let Err(e) = rayon::ThreadPoolBuilder::new()
-        .num_threads(threads_to_use)
-        .build_global()
-    {
-        eprintln!("Warning: Failed to configure Rayon: {}", e);
-    }
+/// Pool with `threads` workers, the pipeline never touches the global rayon pool
+fn thread_pool(threads: usize) -> Result<rayon::ThreadPool, ImageProcessingError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| ImageProcessingError::Other(format!("thread pool: {}", e)))
 }
 
 /// Versione ottimizzata per CPU parallela
+#[allow(clippy::too_many_arguments)]
 pub fn denoise(
     image_path: &Path,
     output_path: &Path,
@@ -35,98 +40,242 @@ pub fn denoise(
     max_m: usize,
     stepp: usize,
     sizze: u32,
-) -> Result<(), ImageProcessingError> {
-    setup_rayon();
+) -> Result<SaveOutcome, ImageProcessingError> {
+    let mut params = Bm3dParams::new();
+    params.set(Parameters::Sigma, ParamValue::F64(sigma));
+    params.set(Parameters::Step1BlockSize, ParamValue::I32(block_s as i32));
+    params.set(Parameters::Step1WindowSize, ParamValue::I32(window_s as i32));
+    params.set(Parameters::Step1MaxMatch, ParamValue::I32(max_m as i32));
+    params.set(Parameters::Step1SpeedupFactor, ParamValue::I32(stepp as i32));
+
+    denoise_file(image_path, output_path, &params, sizze)
+}
+
+/// Denoise the image at `image_path` and save it to `output_path` with the same bit depth.
+/// Images larger than `max_size` px are downscaled first (0 = keep the original size).
+/// The outcome says whether the output format forced 8 bits or dropped the alpha channel.
+pub fn denoise_file(
+    image_path: &Path,
+    output_path: &Path,
+    params: &Bm3dParams,
+    max_size: u32,
+) -> Result<SaveOutcome, ImageProcessingError> {
+    let start_time = Instant::now();
 
     // 1. Carica immagine
     println!("Loading image from {:?}...", image_path);
     let dyn_img = load_dynamic_image(image_path)?;
 
     // 2. Ridimensiona per velocità
-    let max_size = sizze; // Imposta a512 1024 o più per qualità
-    let dyn_img = if dyn_img.width() > max_size || dyn_img.height() > max_size {
+    let dyn_img = if max_size > 0 && (dyn_img.width() > max_size || dyn_img.height() > max_size) {
         println!("Resizing to {}px max for performance...", max_size);
-        let scale = max_size as f32 / dyn_img.width().max(dyn_img.height()) as f32;
-        let new_width = (dyn_img.width() as f32 * scale) as u32;
-        let new_height = (dyn_img.height() as f32 * scale) as u32;
-        dyn_img.resize(new_width, new_height, image::imageops::FilterType::Triangle)
+        fit_max_dimension(dyn_img, max_size)
     } else {
         dyn_img
     };
 
-    // 3. Converti
-    let rgb_img = {
-        let rgb = dyn_img.to_rgb8();
-        let (width, height) = rgb.dimensions();
-        Image::from_u8(
-            rgb.as_raw(),
-            width as usize,
-            height as usize,
-            ColorSpace::RGB,
-        )
-    };
+    // 3. Denoise in memoria
+    let denoised = denoise_image(&dyn_img, params)?;
+
+    // 4. Salva
+    println!("Saving to {:?}...", output_path);
+    let outcome = save_dynamic_image(&denoised, output_path)?;
+
+    println!("\n✅ Denoising completed in {:.2}s!", start_time.elapsed().as_secs_f32());
+    Ok(outcome)
+}
+
+/// Denoise an in-memory image. 8-bit, 16-bit and float inputs are processed as f32
+/// and returned with their original bit depth and channel layout, alpha included.
+pub fn denoise_image(
+    image: &DynamicImage,
+    params: &Bm3dParams,
+) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    Bm3dDenoiser(Bm3dParams),
+    name = "bm3d"
+}
+
+/// Step 1 settings read from `Bm3dParams`
+struct Step1Config {
+    sigma: f64,
+    alpha_sigma: f64,
+    color_transform: ColorTransform,
+    luminance_only: bool,
+    transfer: TransferFunction,
+    lambda: f64,
+    block_size: usize,
+    window_size: usize,
+    max_match: usize,
+    step: usize,
+    threads: usize,
+}
 
-    let (width, height) = rgb_img.dimensions();
-    println!("Processing image: {}x{}", width, height);
+impl Step1Config {
+    fn from_params(params: &Bm3dParams, img: &FloatImage) -> Result<Self, ImageProcessingError> {
+        let size = |key: Parameters| {
+            params
+                .get_usize(&key)
+                .filter(|&v| v > 0)
+                .ok_or_else(|| ImageProcessingError::InvalidParameter(format!("{:?} must be a positive integer", key)))
+        };
+        let scale = match params.get(&Parameters::SigmaScale) {
+            Some(ParamValue::Scale(scale)) => *scale,
+            _ => SigmaScale::default(),
+        };
+        let sigma = params.get_f64(&Parameters::Sigma).unwrap_or(25.0);
+        if sigma.is_nan() || sigma <= 0.0 {
+            return Err(ImageProcessingError::InvalidParameter(format!("Sigma must be positive (got {})", sigma)));
+        }
+
+        let alpha_sigma = params.get_f64(&Parameters::AlphaSigma).unwrap_or(0.0);
+        if alpha_sigma.is_nan() || alpha_sigma < 0.0 {
+            return Err(ImageProcessingError::InvalidParameter(format!("AlphaSigma must be >= 0 (got {})", alpha_sigma)));
+        }
+
+        let color_transform = match params.get(&Parameters::ColorTransform) {
+            Some(ParamValue::Color(transform)) => *transform,
+            _ => ColorTransform::default(),
+        };
+        let luminance_only = params.get_bool(&Parameters::LuminanceOnly).unwrap_or(false);
+        if luminance_only && color_transform == ColorTransform::Rgb && img.layout().color_channels() == 3 {
+            return Err(ImageProcessingError::InvalidParameter(
+                "LuminanceOnly needs a color transform with a luminance channel, not Rgb".to_string(),
+            ));
+        }
+
+        let transfer = match params.get(&Parameters::TransferFunction) {
+            Some(ParamValue::Transfer(transfer)) => *transfer,
+            _ => TransferFunction::default(),
+        };
+        // con gamma < 1 la pendenza di decode in 0 e' infinita, e con lei le soglie sul nero
+        if let TransferFunction::Gamma(gamma) = transfer
+            && !(gamma.is_finite() && gamma >= 1.0)
+        {
+            return Err(ImageProcessingError::InvalidParameter(format!("Gamma must be at least 1 (got {})", gamma)));
+        }
+        if !transfer.is_linear() && color_transform == ColorTransform::Lab {
+            return Err(ImageProcessingError::InvalidParameter(
+                "Lab expects sRGB encoded input, it can't be combined with a linear-light TransferFunction".to_string(),
+            ));
+        }
+
+        // 0 (o assente) sceglie da solo, un valore negativo e' un errore e non un default
+        let threads = match params.get(&Parameters::Threads) {
+            None => 0,
+            Some(value) => params.get_usize(&Parameters::Threads).ok_or_else(|| {
+                ImageProcessingError::InvalidParameter(format!("Threads must be a non-negative integer (got {:?})", value))
+            })?,
+        };
+
+        let config = Self {
+            sigma: scale.normalize(sigma, img.depth()),
+            alpha_sigma,
+            color_transform,
+            luminance_only,
+            transfer,
+            lambda: params.get_f64(&Parameters::Lamb2D).unwrap_or(2.0),
+            block_size: size(Parameters::Step1BlockSize)?,
+            window_size: size(Parameters::Step1WindowSize)?,
+            max_match: size(Parameters::Step1MaxMatch)?,
+            step: size(Parameters::Step1SpeedupFactor)?,
+            threads: if threads == 0 { default_threads() } else { threads },
+        };
+        if img.width() < config.block_size || img.height() < config.block_size {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "Image {}x{} is smaller than the block size {}",
+                img.width(),
+                img.height(),
+                config.block_size
+            )));
+        }
+        Ok(config)
+    }
+}
+
+/// Top-left corners of the reference blocks along one axis, the last block always touches the border.
+fn reference_positions(len: usize, block_size: usize, step: usize) -> Vec<usize> {
+    let last = len - block_size;
+    let mut positions: Vec<usize> = (0..last).step_by(step).collect();
+    positions.push(last);
+    positions
+}
+
+/// Denoise a float buffer. Color channels are optionally decoded to linear light with `TransferFunction`,
+/// decorrelated with `ColorTransform` (opponent by default) and filtered together, the alpha channel is copied
+/// unchanged unless `AlphaSigma` is positive, in which case it is denoised on its own.
+pub fn denoise_buffer(
+    img: &FloatImage,
+    params: &Bm3dParams,
+) -> Result<FloatImage, ImageProcessingError> {
+    let config = Step1Config::from_params(params, img)?;
+    let (width, height) = img.dimensions();
+    println!("Processing image: {}x{} ({:?}, {:?})", width, height, img.layout(), img.depth());
+
+    let original = img;
+    let (noise_map, img) = prepare(original, &config)?;
+    let img = &img;
+    if !config.transfer.is_linear() {
+        println!("Linear light: {:?}", config.transfer);
+    }
+    if img.layout().color_channels() == 3 {
+        println!("Color space: {:?}", config.color_transform);
+    }
 
     // 4. Parametri ottimizzati per CPU
-    let block_size = block_s;
-    let window_size = window_s; // Finestra più piccola per velocità
-    let max_match = max_m; // Meno match per velocità
-    let step = stepp; // Step più grande per velocità
+    let block_size = config.block_size;
+    let window_size = config.window_size;
+    let max_match = config.max_match;
+    let step = config.step;
 
     println!("Configuration (CPU optimized):");
     println!("  Block size: {}", block_size);
     println!("  Search window: {}x{}", window_size, window_size);
     println!("  Max matches: {}", max_match);
     println!("  Step: {}", step);
+    println!("  Threads: {} ({} CPU cores)", config.threads, num_cpus::get());
 
     // 5. Ricerca patch parallela con progresso
     println!("\nFinding similar patches...");
     let start_time = Instant::now();
 
-    let total_y = height.saturating_sub(block_size).div_ceil(step);
-    let total_x = width.saturating_sub(block_size).div_ceil(step);
-    let total_blocks = total_y * total_x;
+    let positions_y = reference_positions(height, block_size, step);
+    let positions_x = reference_positions(width, block_size, step);
+    let total_blocks = positions_y.len() * positions_x.len();
     println!("Total reference blocks: {}", total_blocks);
 
     // Usa parallelizzazione efficiente
-    let grouped_blocks: Vec<Vec<Patch>> = (0..height.saturating_sub(block_size))
-        .step_by(step)
-        .par_bridge()
-        .flat_map(|y| {
-            (0..width.saturating_sub(block_size))
-                .step_by(step)
-                .filter_map(|x| {
-                    // Stampa progresso ogni 100 blocchi
-                    static COUNTER: std::sync::atomic::AtomicUsize =
-                        std::sync::atomic::AtomicUsize::new(0);
-                    let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    if count.is_multiple_of(100) {
-                        let elapsed = start_time.elapsed().as_secs_f32();
-                        let rate = count as f32 / elapsed.max(0.1);
-                        print!(
-                            "\rProcessed: {}/{} blocks ({:.1} blocks/sec)",
-                            count, total_blocks, rate
-                        );
-                        let _ = std::io::Write::flush(&mut std::io::stdout());
-                    }
+    let counter = AtomicUsize::new(0);
+    let pool = thread_pool(config.threads)?;
+    let grouped_blocks: Vec<Vec<Patch>> = pool.install(|| {
+        positions_y
+            .par_iter()
+            .flat_map(|&y| {
+                positions_x
+                    .iter()
+                    .filter_map(|&x| {
+                        // Stampa progresso ogni 100 blocchi
+                        let count = counter.fetch_add(1, Ordering::Relaxed);
+                        if count.is_multiple_of(100) {
+                            let elapsed = start_time.elapsed().as_secs_f32();
+                            let rate = count as f32 / elapsed.max(0.1);
+                            print!(
+                                "\rProcessed: {}/{} blocks ({:.1} blocks/sec)",
+                                count, total_blocks, rate
+                            );
+                            let _ = std::io::Write::flush(&mut std::io::stdout());
+                        }
 
-                    match crate::blocks::match_b::find_similar_patches(
-                        &rgb_img,
-                        (x, y),
-                        block_size,
-                        window_size,
-                        max_match,
-                        true,
-                    ) {
-                        Ok(patches) if !patches.is_empty() => Some(patches),
-                        _ => None,
-                    }
-                })
-                .collect::<Vec<_>>()
-        })
-        .collect();
+                        match_block(img, (x, y), &config)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
 
     let elapsed = start_time.elapsed();
     println!(
@@ -137,86 +286,279 @@ pub fn denoise(
     );
 
     if grouped_blocks.is_empty() {
-        return Err(ImageProcessingError::Other("No patches found"));
+        return Err(ImageProcessingError::Other("No patches found".to_string()));
     }
 
     // 6. Hard thresholding
     println!("\nApplying hard thresholding...");
-    let dct = Dct2D::new(block_size, block_size);
-    let idct = IDct2D::new(block_size, block_size);
-    let threshold = 2.0 * sigma;
+    let filter = GroupFilter::new(&config, img.layout().color_channels(), noise_map.as_deref(), width);
 
     // Parallelizza anche il thresholding
-    let step1_reconstructed: Vec<Vec<Patch>> = grouped_blocks
-        .par_iter()
-        .map(|group| {
-            let mut reconstructed = Vec::new();
-            for patch in group {
-                // Prendi solo il canale Y (luminanza) per semplificare
-                let y_channel_size = block_size * block_size;
-                let y_data: Vec<f64> = patch.data[..y_channel_size.min(patch.data.len())]
-                    .iter()
-                    .map(|&v| v as f64)
-                    .collect();
+    let step1_reconstructed: Vec<Vec<Patch>> =
+        pool.install(|| grouped_blocks.par_iter().map(|group| filter.filter(group)).collect());
 
-                if y_data.len() < y_channel_size {
-                    continue;
-                }
+    // 7. Aggregazione
+    println!("Aggregating patches...");
+    let aggregated = aggregate_patches(&step1_reconstructed, img, block_size)?;
+    let aggregated = FloatImage::from_planes(width, height, img.layout(), img.depth(), aggregated)?;
+    let mut output = config.transfer.encode_image(&from_color_space(&aggregated, config.color_transform)?);
+    let img = original;
+
+    // 8. Alpha: copiato, oppure filtrato come immagine in scala di grigi
+    if img.layout().has_alpha() && config.alpha_sigma > 0.0 {
+        println!("Denoising alpha channel (sigma {})...", config.alpha_sigma);
+        let alpha_idx = img.layout().channels() - 1;
+        let alpha = FloatImage::from_planes(
+            width,
+            height,
+            ChannelLayout::Luma,
+            img.depth(),
+            vec![img.plane(alpha_idx).to_vec()],
+        )?;
+        let mut alpha_params = params.clone();
+        alpha_params.set(Parameters::Sigma, ParamValue::F64(config.alpha_sigma));
+        alpha_params.set(Parameters::AlphaSigma, ParamValue::F64(0.0));
+        // copertura lineare su un solo canale: niente curva di trasferimento né spazio colore
+        alpha_params.set(Parameters::TransferFunction, ParamValue::Transfer(TransferFunction::Linear));
+        alpha_params.set(Parameters::ColorTransform, ParamValue::Color(ColorTransform::Rgb));
+        alpha_params.set(Parameters::LuminanceOnly, ParamValue::Bool(false));
+
+        let filtered = denoise_buffer(&alpha, &alpha_params)?;
+        output.plane_mut(alpha_idx).copy_from_slice(filtered.plane(0));
+    }
 
-                let mut block_2d: Vec<Vec<f64>> =
-                    y_data.chunks(block_size).map(|r| r.to_vec()).collect();
+    Ok(output)
+}
 
-                // DCT + Threshold + iDCT
-                dct.dct_2d(&mut block_2d);
-                hard_threshold(&mut block_2d, threshold);
-                idct.idct_2d(&mut block_2d);
+/// Measured estimate of a [`denoise_buffer`] run on this machine
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Estimate {
+    /// reference blocks of the full run
+    pub reference_blocks: usize,
+    /// reference blocks processed to calibrate
+    pub sampled_blocks: usize,
+    /// threads the estimate is made for
+    pub threads: usize,
+    /// average patches per group in the sample
+    pub patches_per_group: f64,
+    /// matching + filtering throughput with `threads` threads
+    pub blocks_per_second: f64,
+    /// time spent calibrating
+    pub calibration_seconds: f64,
+    /// extrapolated wall time of the full run
+    pub seconds: f64,
+    /// extrapolated peak heap usage, input buffer included
+    pub peak_memory_bytes: usize,
+}
 
-                let filtered_data: Vec<f32> =
-                    block_2d.into_iter().flatten().map(|v| v as f32).collect();
-                reconstructed.push(Patch {
-                    top_left: patch.top_left,
-                    data: filtered_data,
-                });
-            }
-            reconstructed
+/// Estimate time and peak memory of [`denoise_buffer`] with `params`: matching and filtering run for real,
+/// with the configured `Threads`, on at least `samples` reference blocks spread over the image, and the
+/// measured throughput is extrapolated to every reference block.
+pub fn estimate(img: &FloatImage, params: &Bm3dParams, samples: usize) -> Result<Estimate, ImageProcessingError> {
+    let calibration_start = Instant::now();
+    let config = Step1Config::from_params(params, img)?;
+    let (width, height) = img.dimensions();
+
+    let start = Instant::now();
+    let (noise_map, work) = prepare(img, &config)?;
+    let prepare_seconds = start.elapsed().as_secs_f64();
+
+    let positions_y = reference_positions(height, config.block_size, config.step);
+    let positions_x = reference_positions(width, config.block_size, config.step);
+    let total_blocks = positions_y.len() * positions_x.len();
+
+    // almeno 8 blocchi per thread, per bilanciare il carico come nella corsa completa
+    let sample_count = samples.max(8 * config.threads).clamp(1, total_blocks);
+    let sample: Vec<(usize, usize)> = (0..sample_count)
+        .map(|k| {
+            let i = k * total_blocks / sample_count;
+            (positions_x[i % positions_x.len()], positions_y[i / positions_x.len()])
         })
         .collect();
 
-    // 7. Aggregazione
-    println!("Aggregating patches...");
-    let aggregated = aggregate_patches(&step1_reconstructed, width, height, block_size)?;
+    let pool = thread_pool(config.threads)?;
+    let filter = GroupFilter::new(&config, work.layout().color_channels(), noise_map.as_deref(), width);
 
-    // 8. Crea immagine risultato (scala di grigi per ora)
-    println!("Creating output image...");
-    let gray_data: Vec<u8> = aggregated
-        .iter()
-        .map(|&v| v.clamp(0.0, 255.0) as u8)
-        .collect();
+    // riscaldamento: piani della DCT e cache
+    if let Some(group) = match_block(&work, sample[0], &config) {
+        filter.filter(&group);
+    }
 
-    let denoised_image = Image::from_u8(&gray_data, width, height, ColorSpace::Luma);
+    let start = Instant::now();
+    let filtered: Vec<Vec<Patch>> = pool.install(|| {
+        sample
+            .par_iter()
+            .filter_map(|&p| match_block(&work, p, &config))
+            .map(|group| filter.filter(&group))
+            .collect()
+    });
+    let block_seconds = start.elapsed().as_secs_f64().max(1e-9);
+    let blocks_per_second = sample_count as f64 / block_seconds;
+    let patches_per_group = filtered.iter().map(Vec::len).sum::<usize>() as f64 / filtered.len().max(1) as f64;
+
+    // aggregazione: parte fissa sui piani + parte proporzionale ai patch
+    let start = Instant::now();
+    aggregate_patches(&[], &work, config.block_size)?;
+    let aggregate_fixed = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    aggregate_patches(&filtered, &work, config.block_size)?;
+    let aggregate_sample = (start.elapsed().as_secs_f64() - aggregate_fixed).max(0.0);
+
+    let scale = total_blocks as f64 / sample_count as f64;
+    let matching_seconds = total_blocks as f64 / blocks_per_second;
+    let mut seconds = 2.0 * prepare_seconds + matching_seconds + aggregate_fixed + aggregate_sample * scale;
+    if img.layout().has_alpha() && config.alpha_sigma > 0.0 {
+        // l'alpha è una corsa a un solo canale
+        seconds += matching_seconds / img.layout().color_channels() as f64;
+    }
 
-    // 9. Salva
-    println!("Saving to {:?}...", output_path);
-    save_image(&denoised_image, output_path)?;
+    // memoria: copie dei piani + gruppi trovati e ricostruiti, vivi insieme durante l'aggregazione
+    let plane = width * height * std::mem::size_of::<f32>();
+    let all = img.layout().channels();
+    let color = img.layout().color_channels();
+    let patch_bytes = config.block_size * config.block_size * color * std::mem::size_of::<f32>() + std::mem::size_of::<Patch>();
+    let group_bytes = std::mem::size_of::<Vec<Patch>>() as f64 + patches_per_group * patch_bytes as f64;
+    let groups = (2.0 * total_blocks as f64 * group_bytes) as usize;
+    let planes = 3 * all + usize::from(noise_map.is_some()) + (color + 1 + all).max(3 * all);
+
+    Ok(Estimate {
+        reference_blocks: total_blocks,
+        sampled_blocks: sample_count,
+        threads: config.threads,
+        patches_per_group,
+        blocks_per_second,
+        calibration_seconds: calibration_start.elapsed().as_secs_f64(),
+        seconds,
+        peak_memory_bytes: planes * plane + groups,
+    })
+}
+
+/// Decodifica in luce lineare e passa allo spazio colore di lavoro.
+/// In luce lineare il sigma diventa sigma * decode'(v), pixel per pixel: la mappa è restituita a parte.
+fn prepare(img: &FloatImage, config: &Step1Config) -> Result<(Option<Vec<f32>>, FloatImage), ImageProcessingError> {
+    let noise_map = (!config.transfer.is_linear()).then(|| config.transfer.noise_map(img));
+    let linear = config.transfer.decode_image(img);
+    Ok((noise_map, to_color_space(&linear, config.color_transform)?))
+}
+
+/// Gruppo di blocchi simili al blocco di riferimento in `top_left`
+fn match_block(img: &FloatImage, top_left: (usize, usize), config: &Step1Config) -> Option<Vec<Patch>> {
+    match crate::blocks::match_b::find_similar_patches(
+        img,
+        top_left,
+        config.block_size,
+        config.window_size,
+        config.max_match,
+        true,
+    ) {
+        Ok(patches) if !patches.is_empty() => Some(patches),
+        _ => None,
+    }
+}
+
+/// DCT + hard threshold + iDCT of a group, shared by the pipeline and the estimator
+struct GroupFilter<'a> {
+    dct: Dct2D,
+    idct: IDct2D,
+    /// soglia per canale
+    thresholds: Vec<f64>,
+    noise_map: Option<&'a [f32]>,
+    width: usize,
+    block_size: usize,
+    channels: usize,
+    luminance_only: bool,
+}
+
+impl<'a> GroupFilter<'a> {
+    fn new(config: &Step1Config, channels: usize, noise_map: Option<&'a [f32]>, width: usize) -> Self {
+        let block_size = config.block_size;
+        // il rumore si ridistribuisce tra i canali con la trasformazione colore
+        let thresholds: Vec<f64> = if channels == 3 {
+            config.color_transform.noise_scale().iter().map(|s| config.lambda * config.sigma * s).collect()
+        } else {
+            vec![config.lambda * config.sigma]
+        };
+        GroupFilter {
+            dct: Dct2D::new(block_size, block_size),
+            idct: IDct2D::new(block_size, block_size),
+            thresholds,
+            noise_map,
+            width,
+            block_size,
+            channels,
+            luminance_only: config.luminance_only,
+        }
+    }
+
+    fn filter(&self, group: &[Patch]) -> Vec<Patch> {
+        let block_size = self.block_size;
+        let block_len = block_size * block_size;
+        let mut reconstructed = Vec::new();
+        for patch in group {
+            if patch.data.len() < block_len * self.channels {
+                continue;
+            }
+
+            // fattore del rumore in luce lineare, medio sul blocco
+            let noise_factor = self.noise_map.map_or(1.0, |map| {
+                let (x, y) = patch.top_left;
+                (0..block_size)
+                    .map(|row| map[(y + row) * self.width + x..][..block_size].iter().sum::<f32>())
+                    .sum::<f32>() as f64
+                    / block_len as f64
+            });
+
+            // DCT + Threshold + iDCT su ogni canale colore
+            let mut filtered_data: Vec<f32> = Vec::with_capacity(block_len * self.channels);
+            for (c, channel) in patch.data.chunks(block_len).take(self.channels).enumerate() {
+                if self.luminance_only && c > 0 {
+                    filtered_data.extend_from_slice(channel);
+                    continue;
+                }
+                let mut block_2d: Vec<Vec<f64>> = channel
+                    .chunks(block_size)
+                    .map(|r| r.iter().map(|&v| v as f64).collect())
+                    .collect();
+
+                self.dct.dct_2d(&mut block_2d);
+                hard_threshold(&mut block_2d, self.thresholds[c] * noise_factor);
+                self.idct.idct_2d(&mut block_2d);
 
-    println!("\n✅ Denoising completed in {:.2}s!", elapsed.as_secs_f32());
-    Ok(())
+                filtered_data.extend(block_2d.into_iter().flatten().map(|v| v as f32));
+            }
+            reconstructed.push(Patch {
+                top_left: patch.top_left,
+                data: filtered_data,
+            });
+        }
+        reconstructed
+    }
 }
 
-/// Aggrega i patches in un'immagine completa
+/// Aggrega i patches in un'immagine completa, un piano per canale.
+/// I pixel non coperti da nessun patch (e i canali extra come l'alpha) mantengono il valore di `original`.
 fn aggregate_patches(
     patch_groups: &[Vec<Patch>],
-    width: usize,
-    height: usize,
+    original: &FloatImage,
     block_size: usize,
-) -> Result<Vec<f32>, ImageProcessingError> {
+) -> Result<Vec<Vec<f32>>, ImageProcessingError> {
+    let (width, height) = original.dimensions();
+    let channels = original.layout().color_channels();
+    let block_len = block_size * block_size;
+
     // Crea buffer per accumulare i valori e i pesi
-    let mut accumulator = vec![0.0f32; width * height];
+    let mut accumulator = vec![vec![0.0f32; width * height]; channels];
     let mut weights = vec![0.0f32; width * height];
 
     for group in patch_groups {
         for patch in group {
             let (x, y) = patch.top_left;
+            if patch.data.len() < block_len * channels {
+                return Err(ImageProcessingError::DimensionMismatch {
+                    a: block_len * channels,
+                    b: patch.data.len(),
+                });
+            }
 
             for patch_y in 0..block_size {
                 for patch_x in 0..block_size {
@@ -225,11 +567,11 @@ fn aggregate_patches(
 
                     if img_x < width && img_y < height {
                         let patch_idx = patch_y * block_size + patch_x;
-                        if patch_idx < patch.data.len() {
-                            let img_idx = img_y * width + img_x;
-                            accumulator[img_idx] += patch.data[patch_idx];
-                            weights[img_idx] += 1.0;
+                        let img_idx = img_y * width + img_x;
+                        for (c, acc) in accumulator.iter_mut().enumerate() {
+                            acc[img_idx] += patch.data[c * block_len + patch_idx];
                         }
+                        weights[img_idx] += 1.0;
                     }
                 }
             }
@@ -237,85 +579,27 @@ fn aggregate_patches(
     }
 
     // Normalizza dividendo per i pesi
-    let mut result = vec![0.0f32; width * height];
-    for i in 0..accumulator.len() {
-        if weights[i] > 0.0 {
-            result[i] = accumulator[i] / weights[i];
+    let mut result = original.planes().to_vec();
+    for (plane, acc) in result.iter_mut().zip(&accumulator) {
+        for i in 0..acc.len() {
+            if weights[i] > 0.0 {
+                plane[i] = acc[i] / weights[i];
+            }
         }
     }
 
     Ok(result)
 }
 
-fn save_image(img: &Image, path: &Path) -> Result<(), ImageProcessingError> {
-    let (width, height) = img.dimensions();
-    let data = img.flatten_to_u8();
-
-    if data.is_empty() {
-        println!("ERROR: No image data to save!");
-        return Err(ImageProcessingError::Other("No image data"));
-    }
-
-    println!("Image data: {} channels, sizes:", data.len());
-    for (i, channel) in data.iter().enumerate() {
-        println!("  Channel {}: {} bytes", i, channel.len());
-    }
-
-    // Per immagini in scala di grigi (Luma), converti a RGB
-    if data.len() == 1 && data[0].len() == width * height {
-        println!("Converting Luma to RGB...");
-        let gray_data = &data[0];
-        let mut rgb_data = Vec::with_capacity(width * height * 3);
-
-        for &gray in gray_data {
-            rgb_data.push(gray); // R
-            rgb_data.push(gray); // G
-            rgb_data.push(gray); // B
-        }
-
-        let dyn_img = image::DynamicImage::ImageRgb8(
-            image::RgbImage::from_raw(width as u32, height as u32, rgb_data).ok_or_else(|| {
-                println!("ERROR: Failed to create RGB buffer from Luma");
-                ImageProcessingError::Other("Failed to create image buffer")
-            })?,
-        );
-
-        return dyn_img.save(path).map_err(|e| {
-            println!("ERROR: Failed to save image: {}", e);
-            ImageProcessingError::Other(Box::leak(
-                format!("Failed to save image: {}", e).into_boxed_str(),
-            ))
-        });
-    }
-
-    // Per immagini RGB
-    if !data.is_empty() && data[0].len() == width * height * 3 {
-        let rgb_data = data[0].clone();
-        let dyn_img = image::DynamicImage::ImageRgb8(
-            image::RgbImage::from_raw(width as u32, height as u32, rgb_data).ok_or_else(|| {
-                println!("ERROR: Invalid buffer size for RGB");
-                ImageProcessingError::Other("Failed to create image buffer")
-            })?,
-        );
-
-        return dyn_img.save(path).map_err(|e| {
-            println!("ERROR: Failed to save RGB image: {}", e);
-            ImageProcessingError::Other(Box::leak(
-                format!("Failed to save image: {}", e).into_boxed_str(),
-            ))
-        });
-    }
-
-    println!("ERROR: Unsupported image format");
-    Err(ImageProcessingError::Other(
-        "Unsupported image format for saving",
-    ))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::NamedTempFile;
+    use crate::utils::buffer::BitDepth;
+    use crate::Preset;
+    use crate::utils::metrics::compare_images;
+    use crate::utils::noise::{NoiseModel, NoiseRecord};
+    use image::GenericImageView;
+    use tempfile::Builder;
 
     #[test]
     fn test_denoise_with_test_image() {
@@ -334,38 +618,31 @@ mod tests {
             }
         }
 
-        let temp_input = NamedTempFile::new().unwrap();
-        let temp_output = NamedTempFile::new().unwrap();
+        let temp_input = Builder::new().suffix(".png").tempfile().unwrap();
+        let temp_output = Builder::new().suffix(".png").tempfile().unwrap();
 
-        // Salva l'immagine di test
-        let img = image::RgbImage::from_vec(width as u32, height as u32, img_data).unwrap();
-        img.save(temp_input.path()).unwrap();
-
-        // Test denoise con sigma piccolo
+        // Rumore gaussiano riproducibile sopra il pattern pulito
         let sigma = 10.0;
-        match denoise(
-            temp_input.path(),
-            temp_output.path(),
-            sigma,
-            8,
-            16,
-            8,
-            8,
-            512,
-        ) {
-            Ok(_) => {
-                // Verifica che il file di output esista
-                assert!(temp_output.path().exists());
-                println!("Test passed: denoising completed successfully");
-            }
-            Err(e) => {
-                // Per il test, stampa l'errore ma non fallisce
-                eprintln!(
-                    "Note: Denoising test encountered error (might be expected): {}",
-                    e
-                );
-            }
-        }
+        let clean = image::DynamicImage::ImageRgb8(
+            image::RgbImage::from_vec(width as u32, height as u32, img_data).unwrap(),
+        );
+        let record = NoiseRecord::new(NoiseModel::Gaussian { sigma: sigma / 255.0 }, 42);
+        let noisy = record.apply_dynamic(&clean).unwrap();
+        noisy.save(temp_input.path()).unwrap();
+
+        denoise(temp_input.path(), temp_output.path(), sigma, 8, 16, 8, 8, 512).unwrap();
+        assert!(temp_output.path().exists());
+
+        let denoised = image::open(temp_output.path()).unwrap();
+        let before = compare_images(&clean, &noisy, None).unwrap();
+        let after = compare_images(&clean, &denoised, None).unwrap();
+        assert!(
+            after.psnr > before.psnr + 1.0,
+            "PSNR {:.2} dB -> {:.2} dB",
+            before.psnr,
+            after.psnr
+        );
+        assert!(after.ssim > before.ssim);
     }
 
     #[test]
@@ -380,17 +657,170 @@ mod tests {
             data: vec![1.0; block_size * block_size],
         }]];
 
-        let result = aggregate_patches(&patches, width, height, block_size);
+        let original = FloatImage::new(width, height, ChannelLayout::Luma, BitDepth::U8);
+        let result = aggregate_patches(&patches, &original, block_size);
         assert!(result.is_ok());
 
         let aggregated = result.unwrap();
-        assert_eq!(aggregated.len(), width * height);
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(aggregated[0].len(), width * height);
+
+        // I pixel coperti dal patch (block_size x block_size) dovrebbero essere 1.0
+        for y in 0..block_size {
+            for x in 0..block_size {
+                assert!((aggregated[0][y * width + x] - 1.0).abs() < 0.001);
+            }
+        }
+    }
 
-        // I primi block_size*block_size pixel dovrebbero essere 1.0
-        for i in 0..block_size * block_size {
-            assert!((aggregated[i] - 1.0).abs() < 0.001);
+    #[test]
+    fn test_denoise_image_keeps_bit_depth() {
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 2000) as u16, (y * 2000) as u16, 30000 + (x * y) as u16])
+        });
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Sigma, ParamValue::F64(10.0));
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(16));
+        params.set(Parameters::Step1MaxMatch, ParamValue::I32(8));
+        params.set(Parameters::Step1SpeedupFactor, ParamValue::I32(8));
+
+        let denoised = denoise_image(&DynamicImage::ImageRgb16(img), &params).unwrap();
+        assert_eq!(denoised.color(), image::ColorType::Rgb16);
+        assert_eq!(denoised.dimensions(), (32, 32));
+    }
+
+    #[test]
+    fn test_denoise_image_keeps_alpha() {
+        let img = image::ImageBuffer::from_fn(24, 24, |x, y| {
+            image::Rgba([(x * 10) as u8, (y * 10) as u8, 128, if x < 12 { 0 } else { (y * 7) as u8 }])
+        });
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(16));
+        params.set(Parameters::Step1SpeedupFactor, ParamValue::I32(4));
+
+        let denoised = denoise_image(&DynamicImage::ImageRgba8(img.clone()), &params).unwrap();
+        let denoised = denoised.as_rgba8().expect("layout must stay RGBA");
+        for (a, b) in denoised.pixels().zip(img.pixels()) {
+            assert_eq!(a[3], b[3]);
         }
     }
+
+    #[test]
+    fn test_denoise_alpha_with_own_sigma() {
+        let img = image::ImageBuffer::from_fn(24, 24, |x, y| {
+            image::LumaA([100u8, if (x + y) % 2 == 0 { 97 } else { 103 }])
+        });
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(16));
+        params.set(Parameters::AlphaSigma, ParamValue::F64(30.0));
+
+        let denoised = denoise_image(&DynamicImage::ImageLumaA8(img), &params).unwrap();
+        let denoised = denoised.as_luma_alpha8().expect("layout must stay LumaA");
+        // the checkerboard is below the threshold, it must be flattened
+        assert!(denoised.pixels().all(|p| (p[1] as i32 - 100).abs() <= 2));
+    }
+
+    #[test]
+    fn test_denoise_alpha_stays_linear_with_srgb() {
+        // l'alpha è copertura, non colore: niente sRGB, niente soglie scalate dalla pendenza
+        let noise = |x: u32, y: u32| ((x * 7 + y * 13) % 9) as i32 - 4;
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| image::LumaA([60u8, (100 + 5 * noise(x, y)) as u8]));
+        let mean_in = img.pixels().map(|p| p[1] as f64).sum::<f64>() / 1024.0;
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(16));
+        params.set(Parameters::TransferFunction, ParamValue::Transfer(TransferFunction::Srgb));
+        params.set(Parameters::AlphaSigma, ParamValue::F64(40.0));
+
+        let denoised = denoise_image(&DynamicImage::ImageLumaA8(img), &params).unwrap();
+        let alpha: Vec<f64> = denoised.as_luma_alpha8().unwrap().pixels().map(|p| p[1] as f64).collect();
+        let mean = alpha.iter().sum::<f64>() / alpha.len() as f64;
+        let spread = alpha.iter().map(|a| (a - mean).abs()).fold(0.0, f64::max);
+        assert!((mean - mean_in).abs() < 0.5, "mean {} -> {}", mean_in, mean);
+        assert!(spread <= 2.0, "{}", spread);
+    }
+
+    #[test]
+    fn test_gamma_below_one_is_rejected() {
+        // decode' di v^0.5 vale +inf in 0: soglie infinite su ogni gruppo con pixel neri
+        let img = DynamicImage::new_rgb8(16, 16);
+        for gamma in [0.5, 0.0, -2.2, f64::NAN] {
+            let mut params = Bm3dParams::new();
+            params.set(Parameters::TransferFunction, ParamValue::Transfer(TransferFunction::Gamma(gamma)));
+            assert!(matches!(denoise_image(&img, &params), Err(ImageProcessingError::InvalidParameter(_))), "{}", gamma);
+        }
+    }
+
+    #[test]
+    fn test_luminance_only_needs_luma_channel() {
+        let img = DynamicImage::new_rgb8(16, 16);
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::LuminanceOnly, ParamValue::Bool(true));
+        params.set(Parameters::ColorTransform, ParamValue::Color(ColorTransform::Rgb));
+        assert!(matches!(denoise_image(&img, &params), Err(ImageProcessingError::InvalidParameter(_))));
+
+        params.set(Parameters::ColorTransform, ParamValue::Color(ColorTransform::Lab));
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(12));
+        assert!(denoise_image(&img, &params).is_ok());
+    }
+
+    #[test]
+    fn test_linear_light_keeps_flat_image() {
+        let img = DynamicImage::ImageRgb8(image::ImageBuffer::from_pixel(24, 24, image::Rgb([40u8, 128, 220])));
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(16));
+        params.set(Parameters::TransferFunction, ParamValue::Transfer(TransferFunction::Srgb));
+
+        let denoised = denoise_image(&img, &params).unwrap();
+        for p in denoised.as_rgb8().unwrap().pixels() {
+            for (a, b) in p.0.iter().zip([40u8, 128, 220]) {
+                assert!((*a as i32 - b as i32).abs() <= 1);
+            }
+        }
+
+        params.set(Parameters::ColorTransform, ParamValue::Color(ColorTransform::Lab));
+        assert!(matches!(denoise_image(&img, &params), Err(ImageProcessingError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_denoise_rejects_invalid_sigma() {
+        let img = DynamicImage::new_luma8(16, 16);
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Sigma, ParamValue::F64(0.0));
+
+        let res = denoise_image(&img, &params);
+        assert!(matches!(res, Err(ImageProcessingError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_estimate_calibrates_on_real_blocks() {
+        let img = FloatImage::from_dynamic(&DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        })));
+        let mut params = Bm3dParams::new();
+        Preset::Default.apply(&mut params);
+        params.set(Parameters::Threads, ParamValue::I32(2));
+
+        let estimate = estimate(&img, &params, 16).unwrap();
+        let blocks = reference_positions(64, 8, 8).len() * reference_positions(48, 8, 8).len();
+        assert_eq!(estimate.reference_blocks, blocks);
+        assert_eq!(estimate.threads, 2);
+        // almeno 8 blocchi per thread, mai più dei blocchi totali
+        assert_eq!(estimate.sampled_blocks, 16);
+        assert!(estimate.patches_per_group >= 1.0 && estimate.patches_per_group <= 8.0);
+        assert!(estimate.seconds > 0.0 && estimate.blocks_per_second > 0.0);
+        // almeno le copie dei piani dell'immagine
+        assert!(estimate.peak_memory_bytes > 6 * 64 * 48 * 3 * 4);
+    }
+
+    #[test]
+    fn test_negative_threads_is_rejected() {
+        let img = FloatImage::new(32, 32, ChannelLayout::Luma, BitDepth::U8);
+        let mut params = Bm3dParams::new();
+        params.set(Parameters::Threads, ParamValue::I32(-1));
+        assert!(matches!(estimate(&img, &params, 8), Err(ImageProcessingError::InvalidParameter(_))));
+        params.set(Parameters::Threads, ParamValue::Bool(true));
+        assert!(matches!(estimate(&img, &params, 8), Err(ImageProcessingError::InvalidParameter(_))));
+    }
 }
 
 
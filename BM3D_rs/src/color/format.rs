@@ -1,11 +1,215 @@
-//! conversion functions from rgb to YCbCr color space
+//! conversion functions between RGB and the color spaces used for denoising:
+//! YCbCr (BT.601, BT.709, BT.2020), the BM3D opponent space and CIE Lab.
+//!
+//! All conversions work on normalized samples: RGB in [0, 1], luma in [0, 1] and
+//! chroma centred on 0.5 so that converted images stay inside the [0, 1] range.
 use crate::{Bm3dParams, Bm3dImage};
 use crate::error::ImageProcessingError;
-use image::{DynamicImage, ImageBuffer};
-use zune_image::{
-    image::Image, 
-    codecs::bmp::zune_core::colorspace::ColorSpace,
-};
+use crate::utils::buffer::{ChannelLayout, FloatImage};
+use image::DynamicImage;
+use palette::{convert::FromColorUnclamped, Lab, LinSrgb, Srgb};
+
+/// Offset added to chroma channels so that they stay in [0, 1]
+const CHROMA_OFFSET: f32 = 0.5;
+
+/// L* range of CIE Lab
+const LAB_L_RANGE: f32 = 100.0;
+/// a*/b* are mapped from [-128, 128] to [0, 1]
+const LAB_AB_RANGE: f32 = 256.0;
+
+/// YCbCr matrix standard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum YCbCrStandard {
+    /// ITU-R BT.601 (JPEG, SD video)
+    #[default]
+    Bt601,
+    /// ITU-R BT.709 (HD video)
+    Bt709,
+    /// ITU-R BT.2020 (UHD video)
+    Bt2020,
+}
+
+impl YCbCrStandard {
+    /// (Kr, Kb) luma coefficients
+    pub fn coefficients(self) -> (f32, f32) {
+        match self {
+            YCbCrStandard::Bt601 => (0.299, 0.114),
+            YCbCrStandard::Bt709 => (0.2126, 0.0722),
+            YCbCrStandard::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Color space in which the color channels are denoised
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ColorTransform {
+    /// channels are processed as they are
+    Rgb,
+    /// full range YCbCr
+    YCbCr(YCbCrStandard),
+    /// BM3D opponent color space
+    #[default]
+    Opponent,
+    /// CIE Lab (D65) through `palette`
+    Lab,
+}
+
+impl ColorTransform {
+    /// Forward conversion of a single RGB pixel
+    pub fn forward(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorTransform::Rgb => rgb,
+            ColorTransform::YCbCr(standard) => rgb_to_ycbcr(rgb, standard),
+            ColorTransform::Opponent => rgb_to_opponent(rgb),
+            ColorTransform::Lab => rgb_to_lab(rgb),
+        }
+    }
+
+    /// Inverse conversion of a single pixel back to RGB
+    pub fn inverse(self, pixel: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorTransform::Rgb => pixel,
+            ColorTransform::YCbCr(standard) => ycbcr_to_rgb(pixel, standard),
+            ColorTransform::Opponent => opponent_to_rgb(pixel),
+            ColorTransform::Lab => lab_to_rgb(pixel),
+        }
+    }
+
+    /// Standard deviation of each output channel for unit i.i.d. noise on R, G and B.
+    /// Exact for the linear transforms, Lab is linearized around mid gray.
+    pub fn noise_scale(self) -> [f64; 3] {
+        const H: f32 = 1e-2;
+        let mid = [0.5f32; 3];
+        let center = self.forward(mid);
+        let mut sums = [0.0f64; 3];
+        for input in 0..3 {
+            let mut shifted = mid;
+            shifted[input] += H;
+            let out = self.forward(shifted);
+            for (sum, (o, c)) in sums.iter_mut().zip(out.iter().zip(&center)) {
+                let derivative = ((o - c) / H) as f64;
+                *sum += derivative * derivative;
+            }
+        }
+        sums.map(f64::sqrt)
+    }
+}
+
+/// RGB -> full range YCbCr, chroma centred on 0.5
+pub fn rgb_to_ycbcr([r, g, b]: [f32; 3], standard: YCbCrStandard) -> [f32; 3] {
+    let (kr, kb) = standard.coefficients();
+    let kg = 1.0 - kr - kb;
+    let y = kr * r + kg * g + kb * b;
+    let cb = (b - y) / (2.0 * (1.0 - kb));
+    let cr = (r - y) / (2.0 * (1.0 - kr));
+    [y, cb + CHROMA_OFFSET, cr + CHROMA_OFFSET]
+}
+
+/// Full range YCbCr -> RGB, exact inverse of [`rgb_to_ycbcr`]
+pub fn ycbcr_to_rgb([y, cb, cr]: [f32; 3], standard: YCbCrStandard) -> [f32; 3] {
+    let (kr, kb) = standard.coefficients();
+    let kg = 1.0 - kr - kb;
+    let cb = cb - CHROMA_OFFSET;
+    let cr = cr - CHROMA_OFFSET;
+    let r = y + 2.0 * (1.0 - kr) * cr;
+    let b = y + 2.0 * (1.0 - kb) * cb;
+    let g = (y - kr * r - kb * b) / kg;
+    [r, g, b]
+}
+
+/// RGB -> opponent color space used by BM3D (Dabov et al.)
+pub fn rgb_to_opponent([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        (r + g + b) / 3.0,
+        (r - b) / 2.0 + CHROMA_OFFSET,
+        (r - 2.0 * g + b) / 4.0 + CHROMA_OFFSET,
+    ]
+}
+
+/// Opponent -> RGB, exact inverse of [`rgb_to_opponent`]
+pub fn opponent_to_rgb([o1, o2, o3]: [f32; 3]) -> [f32; 3] {
+    let o2 = o2 - CHROMA_OFFSET;
+    let o3 = o3 - CHROMA_OFFSET;
+    [
+        o1 + o2 + 2.0 / 3.0 * o3,
+        o1 - 4.0 / 3.0 * o3,
+        o1 - o2 + 2.0 / 3.0 * o3,
+    ]
+}
+
+/// sRGB -> CIE Lab (D65), L* scaled from [0, 100] and a*/b* from [-128, 128] to [0, 1]
+pub fn rgb_to_lab([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let lab = Lab::from_color_unclamped(Srgb::new(r, g, b).into_linear::<f32>());
+    [
+        lab.l / LAB_L_RANGE,
+        lab.a / LAB_AB_RANGE + CHROMA_OFFSET,
+        lab.b / LAB_AB_RANGE + CHROMA_OFFSET,
+    ]
+}
+
+/// Normalized CIE Lab -> sRGB, inverse of [`rgb_to_lab`] (unclamped, out of gamut colors survive)
+pub fn lab_to_rgb([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let lab = Lab::new(
+        l * LAB_L_RANGE,
+        (a - CHROMA_OFFSET) * LAB_AB_RANGE,
+        (b - CHROMA_OFFSET) * LAB_AB_RANGE,
+    );
+    let rgb = Srgb::from_linear(LinSrgb::from_color_unclamped(lab));
+    [rgb.red, rgb.green, rgb.blue]
+}
+
+fn convert_planes(
+    img: &FloatImage,
+    convert: impl Fn([f32; 3]) -> [f32; 3],
+) -> Result<FloatImage, ImageProcessingError> {
+    let layout = img.layout();
+    if layout.color_channels() != 3 {
+        return Err(ImageProcessingError::ColorConversionError);
+    }
+    let mut out = img.clone();
+    let planes = out.planes_mut();
+    for i in 0..img.width() * img.height() {
+        let converted = convert([planes[0][i], planes[1][i], planes[2][i]]);
+        for (plane, value) in planes.iter_mut().zip(converted) {
+            plane[i] = value;
+        }
+    }
+    Ok(out)
+}
+
+/// Convert the color planes of an RGB(A) image, alpha is left untouched.
+/// Grayscale images are returned unchanged, they already hold a single luminance plane.
+pub fn to_color_space(img: &FloatImage, transform: ColorTransform) -> Result<FloatImage, ImageProcessingError> {
+    if transform == ColorTransform::Rgb || img.layout().color_channels() == 1 {
+        return Ok(img.clone());
+    }
+    convert_planes(img, |px| transform.forward(px))
+}
+
+/// Inverse of [`to_color_space`]
+pub fn from_color_space(img: &FloatImage, transform: ColorTransform) -> Result<FloatImage, ImageProcessingError> {
+    if transform == ColorTransform::Rgb || img.layout().color_channels() == 1 {
+        return Ok(img.clone());
+    }
+    convert_planes(img, |px| transform.inverse(px))
+}
+
+/// Convert an image to full range BT.601 YCbCr planes (alpha, if any, is kept as the last plane).
+pub fn dynamic_to_ycbcr(dynamic_img: &DynamicImage) -> Result<FloatImage, ImageProcessingError> {
+    let img = FloatImage::from_dynamic(dynamic_img);
+    if img.layout().color_channels() != 3 {
+        return Err(ImageProcessingError::ColorConversionError);
+    }
+    to_color_space(&img, ColorTransform::YCbCr(YCbCrStandard::Bt601))
+}
+
+/// Inverse of [`dynamic_to_ycbcr`], layout and bit depth come from the buffer.
+pub fn ycbcr_to_dynamic(ycbcr_img: FloatImage) -> Result<DynamicImage, ImageProcessingError> {
+    if ycbcr_img.layout().color_channels() != 3 {
+        return Err(ImageProcessingError::ColorConversionError);
+    }
+    Ok(from_color_space(&ycbcr_img, ColorTransform::YCbCr(YCbCrStandard::Bt601))?.to_dynamic())
+}
 
 impl Bm3dImage {
     /// constructor for Bm3dImage struct
@@ -18,56 +222,122 @@ impl Bm3dImage {
             params,
         }
     }
-    
-    /// Convert a DynamicImage to RGB format using Zune Image library.
-    pub fn convert_dynamic_to_rgb(dynamic_img: &DynamicImage) -> Result<Image, ImageProcessingError> {
-        let rgb_img = dynamic_img.to_rgb8();
-        let (width, height) = rgb_img.dimensions();
-        
-        Ok(Image::from_u8(
-            rgb_img.as_raw(),
-            width as usize, 
-            height as usize,
-            ColorSpace::RGB
-        ))
-    }
-    
-    /// Convert Image to DynamicImage
-    pub fn convert_to_dynamic(zune_img: &Image) -> Result<DynamicImage, ImageProcessingError> {
-        let (width, height) = zune_img.dimensions();
-        let data = zune_img.flatten_to_u8();
-        
-        if data.is_empty() {
+
+    /// Convert a DynamicImage to a planar RGB float image, alpha is dropped.
+    pub fn convert_dynamic_to_rgb(dynamic_img: &DynamicImage) -> Result<FloatImage, ImageProcessingError> {
+        let img = FloatImage::from_dynamic(dynamic_img).drop_alpha();
+        if img.layout() == ChannelLayout::Rgb {
+            return Ok(img);
+        }
+        // grayscale: replicate the luminance plane
+        let plane = img.plane(0).to_vec();
+        FloatImage::from_planes(
+            img.width(),
+            img.height(),
+            ChannelLayout::Rgb,
+            img.depth(),
+            vec![plane.clone(), plane.clone(), plane],
+        )
+    }
+
+    /// Convert a float image to DynamicImage, the layout and bit depth come from the buffer itself.
+    pub fn convert_to_dynamic(img: &FloatImage) -> Result<DynamicImage, ImageProcessingError> {
+        if img.planes().iter().any(|p| p.len() != img.width() * img.height()) {
             return Err(ImageProcessingError::ColorConversionError);
         }
-        
-        // Prendi il primo canale
-        let channel_data = &data[0];
-        
-        // Se abbiamo 3 canali (RGB), usa tutti, altrimenti usa solo il primo
-        let rgb_data = if data.len() >= 3 {
-            // Combina i canali RGB
-            let mut combined = Vec::with_capacity(width * height * 3);
-            for i in 0..width * height {
-                if i < data[0].len() { combined.push(data[0][i]); } else { combined.push(0); }
-                if i < data[1].len() { combined.push(data[1][i]); } else { combined.push(0); }
-                if i < data[2].len() { combined.push(data[2][i]); } else { combined.push(0); }
+        Ok(img.to_dynamic())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::buffer::BitDepth;
+
+    const EPS: f32 = 1e-5;
+
+    fn samples() -> Vec<[f32; 3]> {
+        let mut out = Vec::new();
+        for r in 0..=4 {
+            for g in 0..=4 {
+                for b in 0..=4 {
+                    out.push([r as f32 / 4.0, g as f32 / 4.0, b as f32 / 4.0]);
+                }
             }
-            combined
-        } else {
-            // Solo un canale (luminanza), duplica per RGB
-            let mut rgb = Vec::with_capacity(width * height * 3);
-            for &gray in channel_data {
-                rgb.push(gray); // R
-                rgb.push(gray); // G  
-                rgb.push(gray); // B
+        }
+        out
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], eps: f32) {
+        for (x, y) in a.iter().zip(&b) {
+            assert!((x - y).abs() < eps, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_ycbcr_roundtrip_all_standards() {
+        for standard in [YCbCrStandard::Bt601, YCbCrStandard::Bt709, YCbCrStandard::Bt2020] {
+            for rgb in samples() {
+                assert_close(ycbcr_to_rgb(rgb_to_ycbcr(rgb, standard), standard), rgb, EPS);
             }
-            rgb
-        };
-        
-        Ok(DynamicImage::ImageRgb8(
-            ImageBuffer::from_raw(width as u32, height as u32, rgb_data)
-                .ok_or(ImageProcessingError::ColorConversionError)?
-        ))
-    }
-}
\ No newline at end of file
+        }
+    }
+
+    #[test]
+    fn test_ycbcr_reference_values() {
+        // BT.601 full range (JFIF): pure red -> Y=0.299, Cb=-0.168736, Cr=0.5
+        assert_close(rgb_to_ycbcr([1.0, 0.0, 0.0], YCbCrStandard::Bt601), [0.299, 0.5 - 0.168_736, 1.0], EPS);
+        // BT.709: pure green luma = 0.7152
+        assert!((rgb_to_ycbcr([0.0, 1.0, 0.0], YCbCrStandard::Bt709)[0] - 0.7152).abs() < EPS);
+        // grays have neutral chroma in every space
+        for transform in [ColorTransform::YCbCr(YCbCrStandard::Bt2020), ColorTransform::Opponent] {
+            let out = transform.forward([0.3, 0.3, 0.3]);
+            assert_close(out, [0.3, 0.5, 0.5], EPS);
+        }
+    }
+
+    #[test]
+    fn test_opponent_roundtrip() {
+        for rgb in samples() {
+            assert_close(opponent_to_rgb(rgb_to_opponent(rgb)), rgb, EPS);
+        }
+    }
+
+    #[test]
+    fn test_lab_roundtrip() {
+        for rgb in samples() {
+            assert_close(lab_to_rgb(rgb_to_lab(rgb)), rgb, 1e-3);
+        }
+        // white is L*=100, a*=b*=0
+        assert_close(rgb_to_lab([1.0, 1.0, 1.0]), [1.0, 0.5, 0.5], 1e-3);
+    }
+
+    #[test]
+    fn test_noise_scale_opponent() {
+        let scale = ColorTransform::Opponent.noise_scale();
+        let expected = [(1.0f64 / 3.0).sqrt(), 0.5f64.sqrt(), 0.375f64.sqrt()];
+        for (s, e) in scale.iter().zip(&expected) {
+            assert!((s - e).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_plane_conversion_keeps_alpha() {
+        let img = FloatImage::from_planes(
+            2,
+            1,
+            ChannelLayout::Rgba,
+            BitDepth::U8,
+            vec![vec![1.0, 0.2], vec![0.0, 0.4], vec![0.5, 0.6], vec![0.7, 0.8]],
+        )
+        .unwrap();
+        let transform = ColorTransform::YCbCr(YCbCrStandard::Bt709);
+        let converted = to_color_space(&img, transform).unwrap();
+        assert_eq!(converted.plane(3), img.plane(3));
+
+        let back = from_color_space(&converted, transform).unwrap();
+        for (a, b) in back.planes().iter().flatten().zip(img.planes().iter().flatten()) {
+            assert!((a - b).abs() < EPS);
+        }
+    }
+}
@@ -1,4 +1,7 @@
 //! wrapper module for color conversions
 
 /// Module containing color conversions
-pub mod format;
\ No newline at end of file
+pub mod format;
+
+/// Module containing transfer functions (gamma, sRGB) for linear-light processing
+pub mod transfer;
@@ -0,0 +1,177 @@
+//! transfer functions between gamma encoded samples and linear light.
+//! params:
+//! - transfer function (sRGB, pure gamma, BT.709)
+//!
+//! Denoising in linear light changes the noise: a sigma measured on the encoded image becomes
+//! `sigma * decode'(v)` in linear light, small in the shadows and large in the highlights.
+//! [`TransferFunction::noise_map`] gives that factor for every pixel.
+
+use crate::utils::buffer::FloatImage;
+use palette::encoding::{FromLinear, IntoLinear, Srgb};
+
+/// Transfer function of the encoded samples
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TransferFunction {
+    /// samples are already linear, nothing is decoded
+    #[default]
+    Linear,
+    /// IEC 61966-2-1 sRGB curve (via `palette`)
+    Srgb,
+    /// pure power law, linear = encoded ^ gamma (e.g. 2.2), gamma >= 1
+    Gamma(f64),
+    /// ITU-R BT.709 OETF
+    Bt709,
+}
+
+impl TransferFunction {
+    /// encoded -> linear
+    pub fn decode(self, v: f32) -> f32 {
+        match self {
+            TransferFunction::Linear => v,
+            TransferFunction::Srgb => <Srgb as IntoLinear<f32, f32>>::into_linear(v),
+            TransferFunction::Gamma(gamma) => v.signum() * v.abs().powf(gamma as f32),
+            TransferFunction::Bt709 => {
+                if v < 0.081 {
+                    v / 4.5
+                } else {
+                    ((v + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+        }
+    }
+
+    /// linear -> encoded
+    pub fn encode(self, v: f32) -> f32 {
+        match self {
+            TransferFunction::Linear => v,
+            TransferFunction::Srgb => <Srgb as FromLinear<f32, f32>>::from_linear(v),
+            TransferFunction::Gamma(gamma) => v.signum() * v.abs().powf(1.0 / gamma as f32),
+            TransferFunction::Bt709 => {
+                if v < 0.018 {
+                    4.5 * v
+                } else {
+                    1.099 * v.powf(0.45) - 0.099
+                }
+            }
+        }
+    }
+
+    /// Derivative of [`decode`](Self::decode) at the encoded value `v`
+    pub fn slope(self, v: f32) -> f32 {
+        let v = v.clamp(0.0, 1.0);
+        match self {
+            TransferFunction::Linear => 1.0,
+            TransferFunction::Srgb => {
+                if v <= 0.04045 {
+                    1.0 / 12.92
+                } else {
+                    2.4 / 1.055 * ((v + 0.055) / 1.055).powf(1.4)
+                }
+            }
+            TransferFunction::Gamma(gamma) => gamma as f32 * v.powf(gamma as f32 - 1.0),
+            TransferFunction::Bt709 => {
+                if v < 0.081 {
+                    1.0 / 4.5
+                } else {
+                    1.0 / (0.45 * 1.099) * ((v + 0.099) / 1.099).powf(1.0 / 0.45 - 1.0)
+                }
+            }
+        }
+    }
+
+    /// true if nothing is decoded
+    pub fn is_linear(self) -> bool {
+        self == TransferFunction::Linear
+    }
+
+    /// Decode every color plane to linear light, alpha is left untouched
+    pub fn decode_image(self, img: &FloatImage) -> FloatImage {
+        self.map_color_planes(img, |v| self.decode(v))
+    }
+
+    /// Encode every color plane back from linear light, alpha is left untouched
+    pub fn encode_image(self, img: &FloatImage) -> FloatImage {
+        self.map_color_planes(img, |v| self.encode(v))
+    }
+
+    /// Per pixel factor from encoded to linear noise sigma, averaged over the color channels
+    /// of the encoded image.
+    pub fn noise_map(self, encoded: &FloatImage) -> Vec<f32> {
+        let channels = encoded.layout().color_channels();
+        let mut map = vec![0.0f32; encoded.width() * encoded.height()];
+        for plane in &encoded.planes()[..channels] {
+            for (m, &v) in map.iter_mut().zip(plane) {
+                *m += self.slope(v) / channels as f32;
+            }
+        }
+        map
+    }
+
+    fn map_color_planes(self, img: &FloatImage, f: impl Fn(f32) -> f32) -> FloatImage {
+        let channels = img.layout().color_channels();
+        let mut out = img.clone();
+        if !self.is_linear() {
+            for plane in &mut out.planes_mut()[..channels] {
+                plane.iter_mut().for_each(|v| *v = f(*v));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::buffer::{BitDepth, ChannelLayout};
+
+    const FUNCTIONS: [TransferFunction; 4] = [
+        TransferFunction::Linear,
+        TransferFunction::Srgb,
+        TransferFunction::Gamma(2.2),
+        TransferFunction::Bt709,
+    ];
+
+    #[test]
+    fn test_roundtrip() {
+        for tf in FUNCTIONS {
+            for i in 0..=100 {
+                let v = i as f32 / 100.0;
+                assert!((tf.encode(tf.decode(v)) - v).abs() < 1e-5, "{:?} at {}", tf, v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_srgb_reference_values() {
+        // sRGB mid gray 0.5 is 21.4% linear light
+        assert!((TransferFunction::Srgb.decode(0.5) - 0.214_041).abs() < 1e-5);
+        assert!((TransferFunction::Srgb.encode(1.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_slope_matches_finite_difference() {
+        for tf in FUNCTIONS {
+            for v in [0.02f32, 0.2, 0.5, 0.9] {
+                let h = 1e-3;
+                let numeric = (tf.decode(v + h) - tf.decode(v - h)) / (2.0 * h);
+                assert!((tf.slope(v) - numeric).abs() < 1e-2, "{:?} at {}", tf, v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_slope_is_finite_at_black() {
+        for tf in FUNCTIONS.into_iter().chain([TransferFunction::Gamma(1.0)]) {
+            assert!(tf.slope(0.0).is_finite(), "{:?}", tf);
+        }
+        let img = FloatImage::new(4, 4, ChannelLayout::Rgb, BitDepth::U8);
+        assert!(TransferFunction::Gamma(2.2).noise_map(&img).iter().all(|m| m.is_finite()));
+    }
+
+    #[test]
+    fn test_shadows_get_smaller_sigma() {
+        let tf = TransferFunction::Srgb;
+        assert!(tf.slope(0.1) < tf.slope(0.5));
+        assert!(tf.slope(0.5) < tf.slope(0.9));
+    }
+}
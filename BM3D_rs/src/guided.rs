@@ -0,0 +1,429 @@
+//! Guided image filter (He, Sun, Tang).
+//! params:
+//! - box radius and regularization epsilon, or a noise sigma to derive epsilon from
+//! - guide: every channel by itself, the gray (mean) guide or the full color guide
+//! - subsampling factor of the fast guided filter
+//!
+//! The output is locally linear in the guide, `q = a·I + b` on every window, with `a` and `b`
+//! from a ridge regression of the input on the guide; epsilon stops `a` from following the noise
+//! in flat areas. Every step is a box filter, so the cost is O(N) whatever the radius. The fast
+//! variant fits `a` and `b` on a guide subsampled by `s` and upsamples them bilinearly, which
+//! divides the cost by about s². Alpha is copied unchanged.
+//!
+//! Guiding a denoised image with the noisy input (`filter_image(&denoised, &noisy, ..)`)
+//! brings back edges that the denoiser softened.
+
+use crate::error::ImageProcessingError;
+use crate::local_means::box_filter;
+use crate::utils::border::BorderMode;
+use crate::utils::buffer::{BitDepth, FloatImage, SigmaScale};
+use crate::Preset;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// epsilon for a noise sigma on the normalized scale, `ε = (EPSILON_PER_SIGMA · σ)²`
+const EPSILON_PER_SIGMA: f64 = 1.5;
+
+/// Which guide channels drive each channel of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum GuideMode {
+    /// channel c follows channel c of the guide, or the only channel of a gray guide
+    #[default]
+    PerChannel,
+    /// every channel follows the mean of the guide channels
+    Gray,
+    /// every channel follows the three guide channels together (3x3 covariance per window)
+    Color,
+}
+
+/// Parameters of the guided filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuidedParams {
+    /// radius of the square window in pixels
+    pub radius: usize,
+    /// regularization on the normalized [0, 1] variance scale, 0 = derived from `sigma`
+    pub epsilon: f64,
+    /// noise standard deviation, only used when `epsilon` is 0
+    pub sigma: f64,
+    /// scale on which `sigma` is expressed
+    pub sigma_scale: SigmaScale,
+    /// how the guide channels are used
+    pub guide: GuideMode,
+    /// subsampling factor of the fast guided filter, 1 = exact
+    pub subsample: usize,
+}
+
+impl Default for GuidedParams {
+    fn default() -> Self {
+        GuidedParams {
+            radius: 1,
+            epsilon: 0.0,
+            sigma: 25.0,
+            sigma_scale: SigmaScale::EightBit,
+            guide: GuideMode::PerChannel,
+            subsample: 1,
+        }
+    }
+}
+
+impl GuidedParams {
+    /// epsilon derived from a known noise sigma
+    pub fn with_sigma(sigma: f64) -> Self {
+        GuidedParams { sigma, ..Self::default() }
+    }
+
+    /// Color guide for `HighQuality`; subsampling only pays off on large radii, so `Fast` is the default
+    pub fn preset(preset: Preset, sigma: f64) -> Self {
+        let base = Self::with_sigma(sigma);
+        match preset {
+            Preset::Fast | Preset::Default => base,
+            Preset::HighQuality => GuidedParams { guide: GuideMode::Color, ..base },
+        }
+    }
+
+    /// epsilon on the normalized scale for a guide of bit depth `depth`
+    fn epsilon_for(&self, depth: BitDepth) -> f64 {
+        if self.epsilon > 0.0 {
+            self.epsilon
+        } else {
+            (EPSILON_PER_SIGMA * self.sigma_scale.normalize(self.sigma, depth)).powi(2)
+        }
+    }
+
+    fn validate(&self) -> Result<(), ImageProcessingError> {
+        if self.radius == 0 {
+            return Err(ImageProcessingError::InvalidParameter("radius must be >= 1".to_string()));
+        }
+        if self.subsample == 0 || self.subsample > self.radius {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "subsample must be between 1 and the radius {} (got {})",
+                self.radius, self.subsample
+            )));
+        }
+        if !(self.epsilon.is_finite() && self.epsilon >= 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!("epsilon must be >= 0 (got {})", self.epsilon)));
+        }
+        if self.epsilon == 0.0 && !(self.sigma.is_finite() && self.sigma > 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "sigma must be > 0 when epsilon is 0 (got {})",
+                self.sigma
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Filter an in-memory image guided by itself, bit depth and channel layout are kept
+pub fn denoise_image(image: &DynamicImage, params: &GuidedParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    /// the image guides itself
+    #[derive(Copy, PartialEq)]
+    GuidedDenoiser(GuidedParams),
+    name = "guided"
+}
+
+/// Filter `image` guided by `guide`, which must have the same size
+pub fn filter_image(image: &DynamicImage, guide: &DynamicImage, params: &GuidedParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    let guide = FloatImage::from_dynamic(guide);
+    Ok(filter_buffer(&buffer, &guide, params)?.to_dynamic())
+}
+
+/// Filter a float buffer guided by itself
+pub fn denoise_buffer(img: &FloatImage, params: &GuidedParams) -> Result<FloatImage, ImageProcessingError> {
+    filter_buffer(img, img, params)
+}
+
+/// Filter the color channels of `img` guided by the color channels of `guide`
+pub fn filter_buffer(img: &FloatImage, guide: &FloatImage, params: &GuidedParams) -> Result<FloatImage, ImageProcessingError> {
+    params.validate()?;
+    if img.dimensions() != guide.dimensions() {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "guide is {}x{}, image is {}x{}",
+            guide.width(),
+            guide.height(),
+            img.width(),
+            img.height()
+        )));
+    }
+    img.ensure_not_empty()?;
+    let channels = img.layout().color_channels();
+    let guides: Vec<&[f32]> = guide.planes().iter().take(guide.layout().color_channels()).map(|p| p.as_slice()).collect();
+    let window = Window::new(img.width(), img.height(), params);
+    let epsilon = params.epsilon_for(guide.depth()) as f32;
+
+    let filtered: Vec<Vec<f32>> = match params.guide {
+        GuideMode::PerChannel => {
+            if guides.len() != 1 && guides.len() != channels {
+                return Err(ImageProcessingError::InvalidParameter(format!(
+                    "a per channel guide needs 1 or {} color channels (got {})",
+                    channels,
+                    guides.len()
+                )));
+            }
+            (0..channels)
+                .map(|c| window.gray(guides[c.min(guides.len() - 1)], img.plane(c), epsilon))
+                .collect()
+        }
+        GuideMode::Gray => {
+            let n = guides.len() as f32;
+            let mean: Vec<f32> = (0..guides[0].len()).map(|i| guides.iter().map(|g| g[i]).sum::<f32>() / n).collect();
+            (0..channels).map(|c| window.gray(&mean, img.plane(c), epsilon)).collect()
+        }
+        GuideMode::Color => {
+            let [r, g, b] = guides[..] else {
+                return Err(ImageProcessingError::InvalidParameter(format!(
+                    "a color guide needs 3 color channels (got {})",
+                    guides.len()
+                )));
+            };
+            (0..channels).map(|c| window.color([r, g, b], img.plane(c), epsilon)).collect()
+        }
+    };
+
+    let mut out = img.clone();
+    for (plane, filtered) in out.planes_mut().iter_mut().zip(filtered) {
+        *plane = filtered;
+    }
+    Ok(out)
+}
+
+/// Geometry shared by the exact and the subsampled filter
+struct Window {
+    width: usize,
+    height: usize,
+    /// subsampling factor
+    step: usize,
+    /// subsampled size
+    low_width: usize,
+    low_height: usize,
+    /// radius on the subsampled grid
+    radius: usize,
+}
+
+impl Window {
+    fn new(width: usize, height: usize, params: &GuidedParams) -> Self {
+        let step = params.subsample;
+        Window {
+            width,
+            height,
+            step,
+            low_width: width.div_ceil(step),
+            low_height: height.div_ceil(step),
+            radius: (params.radius / step).max(1),
+        }
+    }
+
+    /// Box mean on the subsampled grid
+    fn mean(&self, plane: &[f32]) -> Vec<f32> {
+        box_filter(plane, self.low_width, self.low_height, self.radius, BorderMode::Reflect)
+    }
+
+    /// Gray guide: `a = cov(I, p) / (var(I) + ε)`, `b = mean(p) - a·mean(I)`
+    fn gray(&self, guide: &[f32], input: &[f32], epsilon: f32) -> Vec<f32> {
+        let (i, p) = (self.down(guide), self.down(input));
+        let mean_i = self.mean(&i);
+        let mean_p = self.mean(&p);
+        let corr_ii = self.mean(&product(&i, &i));
+        let corr_ip = self.mean(&product(&i, &p));
+
+        let a: Vec<f32> = (0..i.len())
+            .into_par_iter()
+            .map(|k| {
+                let var = corr_ii[k] - mean_i[k] * mean_i[k];
+                let cov = corr_ip[k] - mean_i[k] * mean_p[k];
+                cov / (var + epsilon)
+            })
+            .collect();
+        let b: Vec<f32> = (0..i.len()).into_par_iter().map(|k| mean_p[k] - a[k] * mean_i[k]).collect();
+
+        let (a, b) = (self.up(&self.mean(&a)), self.up(&self.mean(&b)));
+        (0..guide.len()).into_par_iter().map(|k| a[k] * guide[k] + b[k]).collect()
+    }
+
+    /// Color guide: `a = (Σ + εU)⁻¹ cov(I, p)` with Σ the 3x3 covariance of the guide
+    fn color(&self, guide: [&[f32]; 3], input: &[f32], epsilon: f32) -> Vec<f32> {
+        let i = guide.map(|g| self.down(g));
+        let p = self.down(input);
+        let mean_i = [0, 1, 2].map(|c| self.mean(&i[c]));
+        let mean_p = self.mean(&p);
+        let corr_ip = [0, 1, 2].map(|c| self.mean(&product(&i[c], &p)));
+        // rr, rg, rb, gg, gb, bb
+        let pairs = [(0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (2, 2)];
+        let corr_ii = pairs.map(|(u, v)| self.mean(&product(&i[u], &i[v])));
+
+        let coefficients: Vec<[f32; 4]> = (0..p.len())
+            .into_par_iter()
+            .map(|k| {
+                let m = [0, 1, 2].map(|c| mean_i[c][k] as f64);
+                let cov = |n: usize| {
+                    let (u, v) = pairs[n];
+                    corr_ii[n][k] as f64 - m[u] * m[v] + if u == v { epsilon as f64 } else { 0.0 }
+                };
+                let sigma = [[cov(0), cov(1), cov(2)], [cov(1), cov(3), cov(4)], [cov(2), cov(4), cov(5)]];
+                let cov_ip = [0, 1, 2].map(|c| corr_ip[c][k] as f64 - m[c] * mean_p[k] as f64);
+                let a = solve3(&sigma, &cov_ip);
+                let b = mean_p[k] as f64 - (0..3).map(|c| a[c] * m[c]).sum::<f64>();
+                [a[0] as f32, a[1] as f32, a[2] as f32, b as f32]
+            })
+            .collect();
+
+        let smooth: Vec<Vec<f32>> = (0..4)
+            .map(|n| self.up(&self.mean(&coefficients.iter().map(|c| c[n]).collect::<Vec<_>>())))
+            .collect();
+        (0..input.len())
+            .into_par_iter()
+            .map(|k| (0..3).map(|c| smooth[c][k] * guide[c][k]).sum::<f32>() + smooth[3][k])
+            .collect()
+    }
+
+    /// Mean of every step x step block, blocks cut by the border average fewer pixels
+    fn down(&self, plane: &[f32]) -> Vec<f32> {
+        if self.step == 1 {
+            return plane.to_vec();
+        }
+        let mut out = vec![0.0; self.low_width * self.low_height];
+        out.par_chunks_mut(self.low_width).enumerate().for_each(|(ly, row)| {
+            let ys = ly * self.step..((ly + 1) * self.step).min(self.height);
+            for (lx, v) in row.iter_mut().enumerate() {
+                let xs = lx * self.step..((lx + 1) * self.step).min(self.width);
+                let count = (ys.len() * xs.len()) as f32;
+                *v = ys.clone().map(|y| plane[y * self.width + xs.start..y * self.width + xs.end].iter().sum::<f32>()).sum::<f32>() / count;
+            }
+        });
+        out
+    }
+
+    /// Bilinear upsampling back to full size, block centres as samples
+    fn up(&self, plane: &[f32]) -> Vec<f32> {
+        if self.step == 1 {
+            return plane.to_vec();
+        }
+        let coordinate = |i: usize, len: usize| {
+            let t = ((i as f32 + 0.5) / self.step as f32 - 0.5).clamp(0.0, (len - 1) as f32);
+            let i0 = t.floor() as usize;
+            (i0, (i0 + 1).min(len - 1), t - i0 as f32)
+        };
+        let mut out = vec![0.0; self.width * self.height];
+        out.par_chunks_mut(self.width).enumerate().for_each(|(y, row)| {
+            let (y0, y1, fy) = coordinate(y, self.low_height);
+            for (x, v) in row.iter_mut().enumerate() {
+                let (x0, x1, fx) = coordinate(x, self.low_width);
+                let at = |x: usize, y: usize| plane[y * self.low_width + x];
+                let top = at(x0, y0) + fx * (at(x1, y0) - at(x0, y0));
+                let bottom = at(x0, y1) + fx * (at(x1, y1) - at(x0, y1));
+                *v = top + fy * (bottom - top);
+            }
+        });
+        out
+    }
+}
+
+fn product(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.par_iter().zip(b.par_iter()).map(|(a, b)| a * b).collect()
+}
+
+/// `m⁻¹ v` for a symmetric positive definite 3x3 matrix, through the adjugate
+fn solve3(m: &[[f64; 3]; 3], v: &[f64; 3]) -> [f64; 3] {
+    let adj = [
+        [
+            m[1][1] * m[2][2] - m[1][2] * m[2][1],
+            m[0][2] * m[2][1] - m[0][1] * m[2][2],
+            m[0][1] * m[1][2] - m[0][2] * m[1][1],
+        ],
+        [
+            m[1][2] * m[2][0] - m[1][0] * m[2][2],
+            m[0][0] * m[2][2] - m[0][2] * m[2][0],
+            m[0][2] * m[1][0] - m[0][0] * m[1][2],
+        ],
+        [
+            m[1][0] * m[2][1] - m[1][1] * m[2][0],
+            m[0][1] * m[2][0] - m[0][0] * m[2][1],
+            m[0][0] * m[1][1] - m[0][1] * m[1][0],
+        ],
+    ];
+    let det = m[0][0] * adj[0][0] + m[0][1] * adj[1][0] + m[0][2] * adj[2][0];
+    [0, 1, 2].map(|r| (adj[r][0] * v[0] + adj[r][1] * v[1] + adj[r][2] * v[2]) / det)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::metrics::psnr;
+    use crate::utils::noise::{NoiseModel, NoiseRecord};
+    use crate::utils::testing::{assert_invalid, step};
+
+    /// bordo a colori, i canali saltano in versi diversi
+    fn color_step(width: u32, height: u32) -> FloatImage {
+        step(width, height, image::Rgb([12000, 20000, 30000]), image::Rgb([50000, 42000, 9000]))
+    }
+
+    #[test]
+    fn test_guides_denoise_and_keep_edges() {
+        let clean = color_step(64, 48);
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 20.0 / 255.0 }, 4).apply(&clean).unwrap();
+        let before = psnr(&clean, &noisy, None).unwrap();
+        for guide in [GuideMode::PerChannel, GuideMode::Gray, GuideMode::Color] {
+            for subsample in [1, 2] {
+                let params = GuidedParams { guide, radius: 2, subsample, ..GuidedParams::with_sigma(20.0) };
+                let after = psnr(&clean, &denoise_buffer(&noisy, &params).unwrap(), None).unwrap();
+                assert!(after > before + 4.0, "{:?} s={}: {:.2} -> {:.2}", guide, subsample, before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_epsilon_follows_the_guide() {
+        // ε piccolo con la guida uguale all'ingresso: q ≈ p
+        let img = color_step(32, 24);
+        let params = GuidedParams { epsilon: 1e-8, ..GuidedParams::default() };
+        for guide in [GuideMode::PerChannel, GuideMode::Color] {
+            let out = denoise_buffer(&img, &GuidedParams { guide, ..params }).unwrap();
+            let err = out.plane(2).iter().zip(img.plane(2)).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(err < 5e-3, "{:?}: {}", guide, err);
+        }
+    }
+
+    #[test]
+    fn test_post_step_with_noisy_guide() {
+        // una media che sfoca il bordo, poi il filtro guidato dal rumoroso lo riporta
+        let clean = color_step(64, 48);
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 10.0 / 255.0 }, 5).apply(&clean).unwrap();
+        let blurred = crate::local_means::denoise_buffer(&noisy, &crate::local_means::LocalMeansParams::box_filter(4)).unwrap();
+        let params = GuidedParams { guide: GuideMode::Color, radius: 2, ..GuidedParams::with_sigma(10.0) };
+        let refined = filter_buffer(&blurred, &noisy, &params).unwrap();
+        let (a, b) = (psnr(&clean, &refined, None).unwrap(), psnr(&clean, &blurred, None).unwrap());
+        assert!(a > b + 1.0, "{:.2} -> {:.2}", b, a);
+
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(10, 10, image::Rgba([10, 20, 30, 77])));
+        let gray = DynamicImage::new_luma8(10, 10);
+        let out = filter_image(&rgba, &gray, &GuidedParams::default()).unwrap();
+        assert!(out.as_rgba8().unwrap().pixels().all(|p| p.0 == [10, 20, 30, 77]));
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_rgb8(8, 8));
+        assert_invalid(
+            [
+                GuidedParams { radius: 0, ..GuidedParams::default() },
+                GuidedParams { subsample: 0, ..GuidedParams::default() },
+                GuidedParams { subsample: 2, ..GuidedParams::default() },
+                GuidedParams { epsilon: -1.0, ..GuidedParams::default() },
+                GuidedParams { sigma: 0.0, ..GuidedParams::default() },
+            ],
+            |params| denoise_buffer(&img, params),
+        );
+        let gray = FloatImage::from_dynamic(&DynamicImage::new_luma8(8, 8));
+        let color = GuidedParams { guide: GuideMode::Color, ..GuidedParams::default() };
+        assert!(filter_buffer(&img, &gray, &color).is_err());
+        let small = FloatImage::from_dynamic(&DynamicImage::new_rgb8(4, 4));
+        assert!(filter_buffer(&img, &small, &GuidedParams::default()).is_err());
+        let empty = FloatImage::from_dynamic(&DynamicImage::new_rgb8(0, 0));
+        assert_invalid([GuidedParams::default()], |params| denoise_buffer(&empty, params));
+    }
+}
@@ -13,6 +13,45 @@
 use image::DynamicImage;
 use std::collections::HashMap;
 
+/// `XxxDenoiser` of an algorithm module: a [`Denoiser`] that runs the `denoise_image` of the
+/// module with the parameters given to `new`. `name` is a literal or reads the denoiser, extra
+/// attributes (docs, derives) go on the struct.
+macro_rules! denoiser {
+    ($(#[$attr:meta])* $denoiser:ident($params:ty), name = $name:literal) => {
+        denoiser!($(#[$attr])* $denoiser($params), name = |_denoiser| $name);
+    };
+    ($(#[$attr:meta])* $denoiser:ident($params:ty), name = |$this:ident| $name:expr) => {
+        /// [`Denoiser`](crate::Denoiser) with fixed parameters
+        $(#[$attr])*
+        #[derive(Debug, Clone, Default)]
+        pub struct $denoiser {
+            /// parameters of every call
+            pub params: $params,
+        }
+
+        impl $crate::Denoiser for $denoiser {
+            type Params = $params;
+
+            fn new(params: $params) -> Self {
+                $denoiser { params }
+            }
+
+            fn params(&self) -> &$params {
+                &self.params
+            }
+
+            fn name(&self) -> &'static str {
+                let $this = self;
+                $name
+            }
+
+            fn denoise(&self, image: &image::DynamicImage) -> Result<image::DynamicImage, $crate::error::ImageProcessingError> {
+                denoise_image(image, &self.params)
+            }
+        }
+    };
+}
+
 /// wrapper for color operations
 pub mod color;
 
@@ -31,15 +70,64 @@ pub mod utils;
 /// wrapper for BM3D operations
 pub mod bm3d;
 
+/// wavelet shrinkage denoiser
+#[cfg(feature = "wavelet")]
+pub mod wavelet;
+
+/// non-local means denoiser
+#[cfg(feature = "nlm")]
+pub mod nlm;
+
+/// box, gaussian and separable local means
+#[cfg(feature = "local-means")]
+pub mod local_means;
+
+/// Lee, Kuan and Frost adaptive local means
+#[cfg(feature = "adaptive-means")]
+pub mod adaptive_means;
+
+/// bilateral and joint bilateral filters
+#[cfg(feature = "bilateral")]
+pub mod bilateral;
+
+/// total variation (ROF) denoiser
+#[cfg(feature = "tv")]
+pub mod tv;
+
+/// guided image filter
+#[cfg(feature = "guided")]
+pub mod guided;
+
+/// Perona-Malik and coherence-enhancing diffusion
+#[cfg(feature = "diffusion")]
+pub mod diffusion;
+
 
 /// public api for BM3D denoise operations
-pub use bm3d::denoise;
+pub use bm3d::{denoise, denoise_buffer, denoise_image, estimate, Estimate};
+
+/// public api for the internal float image buffer
+pub use utils::buffer::{BitDepth, ChannelLayout, FloatImage, SigmaScale};
+
+/// public api for image quality metrics
+pub use utils::metrics::{compare_images, ChannelQuality, QualityReport};
+
+/// public api for synthetic noise
+pub use utils::noise::{NoiseModel, NoiseRecord};
 
 /// public api for bm3d errors
 pub mod error;
 
+/// public api for the denoiser trait shared with the other crates
+pub use denoise_core::{Denoiser, DynDenoiser};
+
+/// benchmark runner: noise, denoise and measure
+#[cfg(feature = "bench")]
+pub mod bench;
+
 
 #[derive(Clone, Debug)]
+#[allow(dead_code)]
 /// BM3D image wrapper
 pub struct Bm3dImage {
     /// image descriptor
@@ -87,12 +175,22 @@ pub enum Parameters {
     Step2SpeedupFactor,
     /// step 2 window size (39)
     Step2WindowSize,
-    /// luminance only
+    /// luminance only, chroma channels are left as they are (false)
     LuminanceOnly,
     /// mix
     Mix,
     /// residual
     Residual,
+    /// scale on which sigma is expressed (8-bit units)
+    SigmaScale,
+    /// sigma used to denoise the alpha channel, same scale as sigma (0 = keep alpha unchanged)
+    AlphaSigma,
+    /// color space used to decorrelate the color channels (opponent)
+    ColorTransform,
+    /// transfer function decoded to linear light before denoising (none)
+    TransferFunction,
+    /// worker threads, 0 = all the cores but one (0)
+    Threads,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -104,6 +202,12 @@ pub enum ParamValue {
     I32(i32),
     /// boolean value
     Bool(bool),
+    /// sigma scale value
+    Scale(SigmaScale),
+    /// color transform value
+    Color(color::format::ColorTransform),
+    /// transfer function value
+    Transfer(color::transfer::TransferFunction),
 }
 
 impl Bm3dParams {
@@ -120,6 +224,11 @@ impl Bm3dParams {
         params.insert(LuminanceOnly, Bool(false));
         params.insert(Mix, F64(0.0));
         params.insert(Residual, Bool(false));
+        params.insert(Parameters::SigmaScale, Scale(crate::SigmaScale::EightBit));
+        params.insert(AlphaSigma, F64(0.0));
+        params.insert(Parameters::ColorTransform, Color(color::format::ColorTransform::Opponent));
+        params.insert(Parameters::TransferFunction, Transfer(color::transfer::TransferFunction::Linear));
+        params.insert(Threads, I32(0));
         params.insert(Step1ThresholdDist, I32(2500));
         params.insert(Step1MaxMatch, I32(16));
         params.insert(Step1BlockSize, I32(8));
@@ -143,6 +252,83 @@ impl Bm3dParams {
     pub fn get(&self, key: &Parameters) -> Option<&ParamValue> {
         self.params.get(key)
     }
+
+    /// float getter, integers are widened
+    pub fn get_f64(&self, key: &Parameters) -> Option<f64> {
+        match self.get(key)? {
+            ParamValue::F64(v) => Some(*v),
+            ParamValue::I32(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+
+    /// size getter, fails on negative or non integer values
+    pub fn get_usize(&self, key: &Parameters) -> Option<usize> {
+        match self.get(key)? {
+            ParamValue::I32(v) => usize::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    /// boolean getter
+    pub fn get_bool(&self, key: &Parameters) -> Option<bool> {
+        match self.get(key)? {
+            ParamValue::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+/// Parameter presets shared by the CLI and the benchmark runner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Preset {
+    /// balanced speed and quality, the CLI defaults
+    #[default]
+    Default,
+    /// fewer matches and sparse reference blocks, for previews
+    Fast,
+    /// wide search window and dense reference blocks
+    HighQuality,
+}
+
+impl Preset {
+    /// every preset, from the fastest
+    pub const ALL: [Preset; 3] = [Preset::Fast, Preset::Default, Preset::HighQuality];
+
+    /// short lower case name
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Default => "default",
+            Preset::Fast => "fast",
+            Preset::HighQuality => "high-quality",
+        }
+    }
+
+    /// step 1 block size, window size, max matches and step
+    pub fn step1(self) -> (usize, usize, usize, usize) {
+        match self {
+            Preset::Default => (8, 21, 8, 8),
+            Preset::Fast => (8, 21, 4, 16),
+            Preset::HighQuality => (8, 39, 16, 3),
+        }
+    }
+
+    /// largest side the image is resized to before denoising
+    pub fn max_dimension(self) -> u32 {
+        match self {
+            Preset::Default | Preset::Fast => 512,
+            Preset::HighQuality => 2048,
+        }
+    }
+
+    /// set the step 1 parameters of the preset, the others are left as they are
+    pub fn apply(self, params: &mut Bm3dParams) {
+        let (block_size, window_size, max_match, step) = self.step1();
+        params.set(Parameters::Step1BlockSize, ParamValue::I32(block_size as i32));
+        params.set(Parameters::Step1WindowSize, ParamValue::I32(window_size as i32));
+        params.set(Parameters::Step1MaxMatch, ParamValue::I32(max_match as i32));
+        params.set(Parameters::Step1SpeedupFactor, ParamValue::I32(step as i32));
+    }
 }
 
 /// struct Margin
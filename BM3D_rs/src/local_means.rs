@@ -0,0 +1,265 @@
+//! Local means: box, gaussian and generic separable filters.
+//! params:
+//! - kernel (box radius, gaussian sigma and radius, or any odd 1D kernel)
+//! - border mode
+//!
+//! Every kernel is separable: rows first, then columns, each pass parallel over lines.
+//! The box filter keeps a running sum, so its cost does not depend on the radius.
+//! Alpha is copied unchanged.
+
+use crate::error::ImageProcessingError;
+use crate::utils::border::BorderMode;
+use crate::utils::buffer::FloatImage;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Kernel of the local mean
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kernel {
+    /// uniform mean over a (2r+1) x (2r+1) square
+    Box {
+        /// radius in pixels
+        radius: usize,
+    },
+    /// gaussian weights
+    Gaussian {
+        /// standard deviation in pixels
+        sigma: f64,
+        /// radius in pixels, 0 = ceil(3 sigma)
+        radius: usize,
+    },
+    /// the same odd length 1D kernel on rows and columns, normalized to sum 1
+    Separable(Vec<f32>),
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Kernel::Box { radius: 1 }
+    }
+}
+
+impl Kernel {
+    /// 1D weights, odd length, summing to 1
+    pub fn weights(&self) -> Result<Vec<f32>, ImageProcessingError> {
+        match self {
+            Kernel::Box { radius } => Ok(vec![1.0 / (2 * radius + 1) as f32; 2 * radius + 1]),
+            Kernel::Gaussian { sigma, radius } => {
+                if !(sigma.is_finite() && *sigma > 0.0) {
+                    return Err(ImageProcessingError::InvalidParameter(format!(
+                        "gaussian sigma must be > 0 (got {})",
+                        sigma
+                    )));
+                }
+                Ok(gaussian_weights(*sigma, *radius))
+            }
+            Kernel::Separable(kernel) => {
+                let sum: f32 = kernel.iter().sum();
+                if kernel.len() % 2 == 0 || kernel.iter().any(|k| !k.is_finite()) || sum.abs() < f32::EPSILON {
+                    return Err(ImageProcessingError::InvalidParameter(format!(
+                        "separable kernel must have an odd length, finite taps and a non zero sum (got {:?})",
+                        kernel
+                    )));
+                }
+                Ok(kernel.iter().map(|k| k / sum).collect())
+            }
+        }
+    }
+}
+
+/// Parameters of the local means filter
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LocalMeansParams {
+    /// filter kernel
+    pub kernel: Kernel,
+    /// how pixels outside the image are read
+    pub border: BorderMode,
+}
+
+impl LocalMeansParams {
+    /// Box filter of radius `radius`
+    pub fn box_filter(radius: usize) -> Self {
+        LocalMeansParams { kernel: Kernel::Box { radius }, ..Self::default() }
+    }
+
+    /// Gaussian filter of standard deviation `sigma` (pixels), radius ceil(3 sigma)
+    pub fn gaussian(sigma: f64) -> Self {
+        LocalMeansParams { kernel: Kernel::Gaussian { sigma, radius: 0 }, ..Self::default() }
+    }
+}
+
+/// Filter an in-memory image, bit depth and channel layout are kept
+pub fn denoise_image(image: &DynamicImage, params: &LocalMeansParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    #[derive(PartialEq)]
+    LocalMeansDenoiser(LocalMeansParams),
+    name = "local-means"
+}
+
+/// Filter the color channels of a float buffer
+pub fn denoise_buffer(img: &FloatImage, params: &LocalMeansParams) -> Result<FloatImage, ImageProcessingError> {
+    let (width, height) = img.dimensions();
+    let weights = params.kernel.weights()?;
+    img.ensure_not_empty()?;
+    let mut out = img.clone();
+    let channels = img.layout().color_channels();
+    for plane in out.planes_mut().iter_mut().take(channels) {
+        *plane = match params.kernel {
+            Kernel::Box { radius } => box_filter(plane, width, height, radius, params.border),
+            _ => separable_filter(plane, width, height, &weights, params.border),
+        };
+    }
+    Ok(out)
+}
+
+/// Mean over the (2r+1) x (2r+1) square around every pixel, O(1) per pixel
+pub fn box_filter(plane: &[f32], width: usize, height: usize, radius: usize, border: BorderMode) -> Vec<f32> {
+    let pass = |line: &[f32], out: &mut [f32]| running_mean(line, radius, border, out);
+    separable(plane, width, height, pass)
+}
+
+/// Gaussian blur, `radius` 0 = ceil(3 sigma)
+pub fn gaussian_filter(plane: &[f32], width: usize, height: usize, sigma: f64, radius: usize, border: BorderMode) -> Vec<f32> {
+    separable_filter(plane, width, height, &gaussian_weights(sigma, radius), border)
+}
+
+/// Convolution with `kernel` (odd length, used as is) on rows and then on columns
+pub fn separable_filter(plane: &[f32], width: usize, height: usize, kernel: &[f32], border: BorderMode) -> Vec<f32> {
+    let pass = |line: &[f32], out: &mut [f32]| convolve(line, kernel, border, out);
+    separable(plane, width, height, pass)
+}
+
+/// Normalized gaussian taps, `radius` 0 = ceil(3 sigma)
+pub fn gaussian_weights(sigma: f64, radius: usize) -> Vec<f32> {
+    let radius = if radius == 0 { (3.0 * sigma).ceil().max(1.0) as usize } else { radius };
+    let taps: Vec<f64> = (-(radius as isize)..=radius as isize)
+        .map(|i| (-((i * i) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = taps.iter().sum();
+    taps.iter().map(|t| (t / sum) as f32).collect()
+}
+
+/// `pass` on every row, then on every column through a transposition; lines run in parallel
+fn separable(plane: &[f32], width: usize, height: usize, pass: impl Fn(&[f32], &mut [f32]) + Sync) -> Vec<f32> {
+    let mut rows = vec![0.0; width * height];
+    rows.par_chunks_mut(width)
+        .zip(plane.par_chunks(width))
+        .for_each(|(out, line)| pass(line, out));
+
+    let transposed = transpose(&rows, width, height);
+    let mut columns = vec![0.0; width * height];
+    columns
+        .par_chunks_mut(height)
+        .zip(transposed.par_chunks(height))
+        .for_each(|(out, line)| pass(line, out));
+    transpose(&columns, height, width)
+}
+
+/// Running sum over a window of 2r+1 samples
+fn running_mean(line: &[f32], radius: usize, border: BorderMode, out: &mut [f32]) {
+    let r = radius as isize;
+    let scale = 1.0 / (2 * radius + 1) as f64;
+    // somma in f64: nessuna deriva sulle righe lunghe
+    let mut sum: f64 = (-r..=r).map(|i| border.sample(line, i) as f64).sum();
+    for (x, o) in out.iter_mut().enumerate() {
+        *o = (sum * scale) as f32;
+        let x = x as isize;
+        sum += border.sample(line, x + r + 1) as f64 - border.sample(line, x - r) as f64;
+    }
+}
+
+/// Direct convolution with a symmetric or asymmetric odd kernel
+fn convolve(line: &[f32], kernel: &[f32], border: BorderMode, out: &mut [f32]) {
+    let r = (kernel.len() / 2) as isize;
+    for (x, o) in out.iter_mut().enumerate() {
+        let x = x as isize;
+        *o = kernel.iter().zip(-r..=r).map(|(k, i)| k * border.sample(line, x + i)).sum();
+    }
+}
+
+/// `width` x `height` plane to `height` x `width`
+fn transpose(plane: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut out = vec![0.0; width * height];
+    out.par_chunks_mut(height).enumerate().for_each(|(x, column)| {
+        for (y, v) in column.iter_mut().enumerate() {
+            *v = plane[y * width + x];
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::assert_invalid;
+
+    fn ramp(width: usize, height: usize) -> Vec<f32> {
+        (0..width * height).map(|i| ((i * 37) % 101) as f32 / 100.0).collect()
+    }
+
+    #[test]
+    fn test_box_matches_direct_mean() {
+        let (width, height) = (23, 17);
+        let plane = ramp(width, height);
+        for border in [BorderMode::Reflect, BorderMode::Reflect101, BorderMode::Replicate, BorderMode::Wrap, BorderMode::Constant(0.5)] {
+            let fast = box_filter(&plane, width, height, 4, border);
+            for (i, &v) in fast.iter().enumerate() {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let mut sum = 0.0;
+                for dy in -4..=4 {
+                    for dx in -4..=4 {
+                        sum += border.pixel(&plane, width, height, x + dx, y + dy);
+                    }
+                }
+                assert!((v - sum / 81.0).abs() < 1e-5, "{:?} at {}", border, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gaussian_weights() {
+        let w = gaussian_weights(1.5, 0);
+        assert_eq!(w.len(), 11);
+        assert!((w.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert_eq!(w[0], w[10]);
+        // costante: resta costante con i bordi che non inventano valori
+        let plane = vec![0.3f32; 15 * 9];
+        let out = gaussian_filter(&plane, 15, 9, 2.0, 0, BorderMode::Reflect101);
+        assert!(out.iter().all(|v| (v - 0.3).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_separable_kernel_and_layouts() {
+        let img = DynamicImage::ImageLumaA16(image::ImageBuffer::from_fn(12, 8, |x, y| {
+            image::LumaA([((x * 5000 + y * 300) % 65535) as u16, 4242])
+        }));
+        let params = LocalMeansParams { kernel: Kernel::Separable(vec![1.0, 2.0, 1.0]), border: BorderMode::Replicate };
+        let out = denoise_image(&img, &params).unwrap();
+        assert_eq!(out.color(), image::ColorType::La16);
+        assert!(out.as_luma_alpha16().unwrap().pixels().all(|p| p.0[1] == 4242));
+
+        let same = denoise_image(&img, &LocalMeansParams { kernel: Kernel::Separable(vec![0.0, 3.0, 0.0]), ..params }).unwrap();
+        assert_eq!(same.as_luma_alpha16(), img.as_luma_alpha16());
+    }
+
+    #[test]
+    fn test_invalid_kernels() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_rgb32f(4, 4));
+        assert_invalid(
+            [Kernel::Separable(vec![1.0, 1.0]), Kernel::Separable(vec![1.0, -1.0, 0.0]), Kernel::Gaussian { sigma: 0.0, radius: 2 }],
+            |kernel| denoise_buffer(&img, &LocalMeansParams { kernel: kernel.clone(), ..Default::default() }),
+        );
+    }
+
+    #[test]
+    fn test_empty_image() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_rgb8(0, 0));
+        assert_invalid(
+            [Kernel::Box { radius: 1 }, Kernel::Gaussian { sigma: 1.0, radius: 2 }, Kernel::Separable(vec![1.0, 2.0, 1.0])],
+            |kernel| denoise_buffer(&img, &LocalMeansParams { kernel: kernel.clone(), ..Default::default() }),
+        );
+    }
+}
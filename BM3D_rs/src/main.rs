@@ -1,6 +1,158 @@
-use std::path::PathBuf;
-use bm3d_rs::denoise;
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use bm3d_rs::{
+    bench::{self, Algorithm, BenchConfig},
+    bm3d::denoise_file,
+    nlm::{NlmParams, NlmVariant},
+    color::format::{ColorTransform, YCbCrStandard},
+    color::transfer::TransferFunction,
+    utils::io::{fit_max_dimension, load_dynamic_image, save_dynamic_image, SaveOutcome},
+    utils::metrics::{self, mask_from_image, QualityReport},
+    utils::noise::{record_path, NoiseModel, NoiseRecord},
+    Bm3dParams, ChannelLayout, FloatImage, ParamValue, Parameters, Preset, SigmaScale,
+};
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOC: bm3d_rs::utils::alloc::TrackingAllocator = bm3d_rs::utils::alloc::TrackingAllocator;
+
+/// Scale used to read `--sigma`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SigmaUnits {
+    /// 0-255 whatever the input depth
+    EightBit,
+    /// fraction of the full range (0.0-1.0)
+    Normalized,
+    /// units of the input samples (0-255, 0-65535 or 0.0-1.0)
+    Native,
+}
+
+impl From<SigmaUnits> for SigmaScale {
+    fn from(units: SigmaUnits) -> Self {
+        match units {
+            SigmaUnits::EightBit => SigmaScale::EightBit,
+            SigmaUnits::Normalized => SigmaScale::Normalized,
+            SigmaUnits::Native => SigmaScale::Native,
+        }
+    }
+}
+
+/// Color space used to denoise color images
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ColorSpaceArg {
+    /// no conversion
+    Rgb,
+    /// YCbCr, ITU-R BT.601
+    Ycbcr601,
+    /// YCbCr, ITU-R BT.709
+    Ycbcr709,
+    /// YCbCr, ITU-R BT.2020
+    Ycbcr2020,
+    /// BM3D opponent color space
+    Opponent,
+    /// CIE Lab
+    Lab,
+}
+
+impl From<ColorSpaceArg> for ColorTransform {
+    fn from(arg: ColorSpaceArg) -> Self {
+        match arg {
+            ColorSpaceArg::Rgb => ColorTransform::Rgb,
+            ColorSpaceArg::Ycbcr601 => ColorTransform::YCbCr(YCbCrStandard::Bt601),
+            ColorSpaceArg::Ycbcr709 => ColorTransform::YCbCr(YCbCrStandard::Bt709),
+            ColorSpaceArg::Ycbcr2020 => ColorTransform::YCbCr(YCbCrStandard::Bt2020),
+            ColorSpaceArg::Opponent => ColorTransform::Opponent,
+            ColorSpaceArg::Lab => ColorTransform::Lab,
+        }
+    }
+}
+
+/// Synthetic noise model
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum NoiseArg {
+    /// additive white gaussian noise, see --sigma
+    Gaussian,
+    /// shot noise, see --peak
+    Poisson,
+    /// shot noise plus gaussian read noise, see --peak and --sigma
+    PoissonGaussian,
+    /// impulse noise, see --density and --salt-ratio
+    SaltAndPepper,
+    /// multiplicative gaussian noise, see --sigma
+    Speckle,
+    /// spatially correlated gaussian noise, see --sigma and --correlation
+    Correlated,
+}
+
+/// Denoising algorithm
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum AlgorithmArg {
+    /// BM3D
+    Bm3d,
+    /// wavelet shrinkage
+    Wavelet,
+    /// non-local means
+    Nlm,
+    /// Lee adaptive local means
+    Lee,
+    /// bilateral filter
+    Bilateral,
+    /// total variation
+    Tv,
+    /// guided filter
+    Guided,
+    /// Perona-Malik diffusion
+    Diffusion,
+}
+
+impl From<AlgorithmArg> for Algorithm {
+    fn from(arg: AlgorithmArg) -> Self {
+        match arg {
+            AlgorithmArg::Bm3d => Algorithm::Bm3d,
+            AlgorithmArg::Wavelet => Algorithm::Wavelet,
+            AlgorithmArg::Nlm => Algorithm::Nlm,
+            AlgorithmArg::Lee => Algorithm::Lee,
+            AlgorithmArg::Bilateral => Algorithm::Bilateral,
+            AlgorithmArg::Tv => Algorithm::Tv,
+            AlgorithmArg::Guided => Algorithm::Guided,
+            AlgorithmArg::Diffusion => Algorithm::Diffusion,
+        }
+    }
+}
+
+/// Parameter preset
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum PresetArg {
+    /// fewer matches, sparse reference blocks
+    Fast,
+    /// the CLI defaults
+    Default,
+    /// wide search window, dense reference blocks
+    HighQuality,
+}
+
+impl From<PresetArg> for Preset {
+    fn from(arg: PresetArg) -> Self {
+        match arg {
+            PresetArg::Fast => Preset::Fast,
+            PresetArg::Default => Preset::Default,
+            PresetArg::HighQuality => Preset::HighQuality,
+        }
+    }
+}
+
+/// Transfer function decoded to linear light before denoising
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TransferArg {
+    /// samples are processed as they are
+    Linear,
+    /// sRGB curve
+    Srgb,
+    /// pure power law, see --gamma
+    Gamma,
+    /// ITU-R BT.709 curve
+    Bt709,
+}
 
 /// BM3D Denoising Tool
 #[derive(Parser, Debug)]
@@ -15,20 +167,55 @@ Example usage:
   bm3d --input noisy.jpg --output clean.jpg --sigma 25.0
   bm3d --input noisy.jpg --output clean.jpg --sigma 25.0 --window-size 39 --max-matches 16 --step-size 3
   bm3d --input noisy.jpg --output clean.jpg --sigma 25.0 --fast-params --max-dimension 1024
-"#
+  bm3d --input scan16.png --output clean16.png --sigma 0.02 --sigma-scale normalized
+  bm3d --input photo.png --output clean.png --sigma 15 --transfer srgb
+  bm3d compare --reference clean.png --test denoised.png --heatmap diff.png
+  bm3d add-noise --input clean.png --output noisy.png --model gaussian --sigma 25 --seed 1
+  bm3d bench --images clean/ --sigmas 15,25,50 --preset fast,default --csv results.csv
+  bm3d nlm --input noisy.png --output clean.png --sigma 25
+"#,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input image path
-    #[arg(short, long, value_name = "FILE")]
-    input: PathBuf,
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input: Option<PathBuf>,
     
     /// Output image path
-    #[arg(short, long, value_name = "FILE")]
-    output: PathBuf,
+    #[arg(short, long, value_name = "FILE", required = true)]
+    output: Option<PathBuf>,
     
     /// Noise sigma value (higher = more aggressive denoising)
     #[arg(short, long, default_value_t = 25.0, value_name = "FLOAT")]
     sigma: f64,
+
+    /// Scale of the sigma value (16-bit and float images keep their depth)
+    #[arg(long, value_enum, default_value_t = SigmaUnits::EightBit)]
+    sigma_scale: SigmaUnits,
+
+    /// Sigma for the alpha channel of RGBA/LumaA images, same scale as --sigma (0 = keep alpha unchanged)
+    #[arg(long, default_value_t = 0.0, value_name = "FLOAT")]
+    alpha_sigma: f64,
+
+    /// Color space in which color images are denoised
+    #[arg(long, value_enum, default_value_t = ColorSpaceArg::Opponent)]
+    color_space: ColorSpaceArg,
+
+    /// Decode to linear light before denoising and re-encode afterwards
+    #[arg(long, value_enum, default_value_t = TransferArg::Linear)]
+    transfer: TransferArg,
+
+    /// Exponent used by --transfer gamma, at least 1
+    #[arg(long, default_value_t = 2.2, value_name = "FLOAT")]
+    gamma: f64,
+
+    /// Denoise only the luminance channel, chroma is kept as is
+    #[arg(long, default_value_t = false)]
+    luminance_only: bool,
     
     /// Block size (patch size in pixels)
     #[arg(long, default_value_t = 8, value_name = "SIZE")]
@@ -62,17 +249,202 @@ struct Args {
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
     
-    /// Estimate processing time without actually denoising
+    /// Estimate processing time and memory with a short calibration run, without denoising
     #[arg(long, default_value_t = false)]
     estimate_only: bool,
+
+    /// Worker threads (0 = all the cores but one)
+    #[arg(long, default_value_t = 0, value_name = "COUNT")]
+    threads: usize,
+}
+
+/// Other tools, without a subcommand the input image is denoised
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare an image against a clean reference (PSNR, SSIM, MS-SSIM, MSE)
+    Compare(CompareArgs),
+    /// Add seeded synthetic noise to an image and save the parameters next to it
+    AddNoise(AddNoiseArgs),
+    /// Add noise to a folder of clean images, denoise them and record quality, time and memory
+    Bench(BenchArgs),
+    /// Denoise with non-local means
+    Nlm(NlmArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+struct NlmArgs {
+    /// Input image path
+    #[arg(short, long, value_name = "FILE")]
+    input: PathBuf,
+
+    /// Output image path
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    /// Noise sigma value
+    #[arg(short, long, default_value_t = 25.0, value_name = "FLOAT")]
+    sigma: f64,
+
+    /// Scale of the sigma and h values
+    #[arg(long, value_enum, default_value_t = SigmaUnits::EightBit)]
+    sigma_scale: SigmaUnits,
+
+    /// Patch side, odd (0 = chosen from sigma)
+    #[arg(long, default_value_t = 0, value_name = "SIZE")]
+    patch_size: usize,
+
+    /// Search window side, odd (0 = chosen from sigma)
+    #[arg(long, default_value_t = 0, value_name = "SIZE")]
+    search_window: usize,
+
+    /// Filtering parameter, same scale as --sigma (0 = a fraction of sigma)
+    #[arg(long, default_value_t = 0.0, value_name = "FLOAT")]
+    h: f64,
+
+    /// Use the pixelwise reference instead of the integral image variant (much slower)
+    #[arg(long, default_value_t = false)]
+    pixelwise: bool,
+
+    /// Maximum image dimension for processing (0 = no resize)
+    #[arg(long, default_value_t = 0, value_name = "PIXELS")]
+    max_dimension: u32,
+}
+
+#[derive(ClapArgs, Debug)]
+struct BenchArgs {
+    /// Folder of clean images
+    #[arg(long, value_name = "DIR")]
+    images: PathBuf,
+
+    /// Gaussian noise sigmas, 0-255 scale
+    #[arg(long, value_delimiter = ',', default_value = "15,25,50", value_name = "LIST")]
+    sigmas: Vec<f64>,
+
+    /// Algorithms to run
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "bm3d")]
+    algorithm: Vec<AlgorithmArg>,
+
+    /// Presets to run for every algorithm
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "default")]
+    preset: Vec<PresetArg>,
+
+    /// Seed of the noise
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Shrink the images to this size first (0 = no resize)
+    #[arg(long, default_value_t = 0, value_name = "PIXELS")]
+    max_dimension: u32,
+
+    /// Write the results as CSV
+    #[arg(long, value_name = "FILE")]
+    csv: Option<PathBuf>,
+
+    /// Write the results as JSON
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct AddNoiseArgs {
+    /// Clean input image
+    #[arg(short, long, value_name = "FILE")]
+    input: PathBuf,
+
+    /// Noisy output image (bit depth and alpha of the input are kept)
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    /// Noise model
+    #[arg(short, long, value_enum, default_value_t = NoiseArg::Gaussian)]
+    model: NoiseArg,
+
+    /// Noise sigma (for speckle: relative to the pixel value)
+    #[arg(short, long, default_value_t = 25.0, value_name = "FLOAT")]
+    sigma: f64,
+
+    /// Scale of the sigma value
+    #[arg(long, value_enum, default_value_t = SigmaUnits::EightBit)]
+    sigma_scale: SigmaUnits,
+
+    /// Photons at full scale for Poisson noise (lower = noisier)
+    #[arg(long, default_value_t = 30.0, value_name = "FLOAT")]
+    peak: f64,
+
+    /// Fraction of pixels hit by salt and pepper noise
+    #[arg(long, default_value_t = 0.05, value_name = "FLOAT")]
+    density: f64,
+
+    /// Fraction of the hit pixels set to white
+    #[arg(long, default_value_t = 0.5, value_name = "FLOAT")]
+    salt_ratio: f64,
+
+    /// Correlation length in pixels of correlated noise
+    #[arg(long, default_value_t = 1.0, value_name = "PIXELS")]
+    correlation: f64,
+
+    /// Seed of the random generator
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Keep values outside [0, 1] (only float outputs can store them)
+    #[arg(long, default_value_t = false)]
+    no_clip: bool,
+
+    /// Where to write the noise parameters (default: <output>.json)
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+}
+
+#[derive(ClapArgs, Debug)]
+struct CompareArgs {
+    /// Clean reference image
+    #[arg(short, long, value_name = "FILE")]
+    reference: PathBuf,
+
+    /// Image to evaluate, same size as the reference
+    #[arg(short, long, value_name = "FILE")]
+    test: PathBuf,
+
+    /// Only compare the pixels where this image is white (luma >= 0.5)
+    #[arg(long, value_name = "FILE")]
+    mask: Option<PathBuf>,
+
+    /// Write the absolute difference as a heatmap image
+    #[arg(long, value_name = "FILE")]
+    heatmap: Option<PathBuf>,
+
+    /// Difference shown as white in the heatmap, in the sample units of the reference
+    /// (0-255, 0-65535 or 0.0-1.0; default: the largest difference)
+    #[arg(long, value_name = "FLOAT")]
+    heatmap_scale: Option<f64>,
+
+    /// Print the report as JSON (infinite PSNR is written as null)
+    #[arg(long, default_value_t = false)]
+    json: bool,
 }
 
 fn main() {
     let args = Args::parse();
-    
+
+    match args.command {
+        Some(Command::Compare(ref compare)) => run_compare(compare),
+        Some(Command::AddNoise(ref add_noise)) => run_add_noise(add_noise),
+        Some(Command::Bench(ref bench_args)) => run_bench(bench_args),
+        Some(Command::Nlm(ref nlm_args)) => run_nlm(nlm_args),
+        None => run_denoise(args),
+    }
+}
+
+fn run_denoise(args: Args) {
+    // clap rende obbligatori input e output quando non c'è un sottocomando
+    let (Some(input), Some(output)) = (args.input.clone(), args.output.clone()) else {
+        unreachable!("--input and --output are required without a subcommand");
+    };
+
     // Validazione input
-    if !args.input.exists() {
-        eprintln!("❌ Error: Input file '{}' does not exist", args.input.display());
+    if !input.exists() {
+        eprintln!("❌ Error: Input file '{}' does not exist", input.display());
         std::process::exit(1);
     }
     
@@ -81,6 +453,11 @@ fn main() {
         std::process::exit(1);
     }
     
+    if args.alpha_sigma < 0.0 {
+        eprintln!("❌ Error: Alpha sigma must be >= 0 (got {})", args.alpha_sigma);
+        std::process::exit(1);
+    }
+    
     if args.block_size < 4 || args.block_size > 32 {
         eprintln!("❌ Error: Block size must be between 4 and 32 (got {})", args.block_size);
         std::process::exit(1);
@@ -100,11 +477,13 @@ fn main() {
     
     // Configurazione parametri
     let (block_size, window_size, max_matches, step_size, max_dimension) = if args.fast_params {
-    println!("⚠️ Using FAST parameters (optimized for speed)");
-    (8, 21, 4, 16, 512)  // ← CAMBIA QUI: 8 invece di 16, 21 invece di 15
-    }else if args.high_quality {
+        println!("⚠️ Using FAST parameters (optimized for speed)");
+        let (b, w, m, s) = Preset::Fast.step1();
+        (b, w, m, s, Preset::Fast.max_dimension() as usize)
+    } else if args.high_quality {
         println!("⚠️ Using HIGH QUALITY parameters (slower but better)");
-        (8, 39, 16, 3, if args.max_dimension > 0 { args.max_dimension } else { 2048 })
+        let (b, w, m, s) = Preset::HighQuality.step1();
+        (b, w, m, s, if args.max_dimension > 0 { args.max_dimension } else { Preset::HighQuality.max_dimension() as usize })
     } else {
         (args.block_size, args.window_size, args.max_matches, args.step_size, args.max_dimension)
     };
@@ -113,12 +492,14 @@ fn main() {
     println!("╔════════════════════════════════════════════════╗");
     println!("║              BM3D Denoising Tool               ║");
     println!("╚════════════════════════════════════════════════╝");
-    println!("");
+    println!();
     println!("📊 Configuration:");
-    println!("  Input:          {}", args.input.display());
-    println!("  Output:         {}", args.output.display());
-    println!("  Sigma:          {}", args.sigma);
-    println!("");
+    println!("  Input:          {}", input.display());
+    println!("  Output:         {}", output.display());
+    println!("  Sigma:          {} ({:?})", args.sigma, args.sigma_scale);
+    println!("  Color space:    {:?}", args.color_space);
+    println!("  Transfer:       {:?}", args.transfer);
+    println!();
     println!("⚙️ Parameters:");
     println!("  Block size:     {} px", block_size);
     println!("  Window size:    {} px", window_size);
@@ -129,44 +510,61 @@ fn main() {
     } else { 
         "Original".to_string() 
     });
-    println!("");
+    println!();
     
+    // Chiama la funzione denoise con i parametri
+    let mut params = Bm3dParams::new();
+    params.set(Parameters::Sigma, ParamValue::F64(args.sigma));
+    params.set(Parameters::SigmaScale, ParamValue::Scale(args.sigma_scale.into()));
+    params.set(Parameters::AlphaSigma, ParamValue::F64(args.alpha_sigma));
+    params.set(Parameters::ColorTransform, ParamValue::Color(args.color_space.into()));
+    params.set(Parameters::LuminanceOnly, ParamValue::Bool(args.luminance_only));
+    let transfer = match args.transfer {
+        TransferArg::Linear => TransferFunction::Linear,
+        TransferArg::Srgb => TransferFunction::Srgb,
+        TransferArg::Gamma => TransferFunction::Gamma(args.gamma),
+        TransferArg::Bt709 => TransferFunction::Bt709,
+    };
+    params.set(Parameters::TransferFunction, ParamValue::Transfer(transfer));
+    // i parametri interi della libreria sono i32: oltre si esce invece di troncare
+    let int = |name: &str, value: usize| {
+        ParamValue::I32(i32::try_from(value).unwrap_or_else(|_| {
+            eprintln!("❌ Error: {} is too large (got {})", name, value);
+            std::process::exit(1);
+        }))
+    };
+    params.set(Parameters::Step1BlockSize, int("Block size", block_size));
+    params.set(Parameters::Step1WindowSize, int("Window size", window_size));
+    params.set(Parameters::Step1MaxMatch, int("Max matches", max_matches));
+    params.set(Parameters::Step1SpeedupFactor, int("Step size", step_size));
+    params.set(Parameters::Threads, int("Threads", args.threads));
+
     if args.estimate_only {
-        estimate_processing_time(&args.input, block_size, window_size, step_size, max_dimension);
+        estimate_processing_time(&input, &params, max_dimension as u32);
         return;
     }
-    
+
     if args.verbose {
         println!("🚀 Starting denoising process...");
     }
-    
-    // Chiama la funzione denoise con i parametri
-    // Prima dobbiamo creare una versione di denoise che accetta parametri
-    match denoise(
-        &args.input,
-        &args.output,
-        args.sigma,
-        block_size,
-        window_size,
-        max_matches,
-        step_size,
-        max_dimension as u32,
-    ) {
-        Ok(_) => {
-            println!("");
+
+    match denoise_file(&input, &output, &params, max_dimension as u32) {
+        Ok(outcome) => {
+            warn_if_downgraded(&output, outcome);
+            println!();
             println!("✅ Denoising completed successfully!");
-            println!("📁 Output saved to: {}", args.output.display());
+            println!("📁 Output saved to: {}", output.display());
             
             // Mostra informazioni sul file di output
-            if let Ok(metadata) = std::fs::metadata(&args.output) {
+            if let Ok(metadata) = std::fs::metadata(&output) {
                 let size_kb = metadata.len() / 1024;
                 println!("📦 File size: {} KB", size_kb);
             }
         }
         Err(e) => {
-            eprintln!("");
+            eprintln!();
             eprintln!("❌ Error: {}", e);
-            eprintln!("");
+            eprintln!();
             eprintln!("💡 Troubleshooting tips:");
             eprintln!("  1. Check if input image is corrupted");
             eprintln!("  2. Try with --fast-params for faster processing");
@@ -178,77 +576,265 @@ fn main() {
     }
 }
 
-/// Stima il tempo di processing basato sui parametri
-fn estimate_processing_time(
-    input_path: &PathBuf,
-    block_size: usize,
-    window_size: usize,
-    step_size: usize,
-    max_dimension: usize,
-) {
-    println!("⏱️  Estimating processing time...");
-    
-    // Prova a ottenere le dimensioni dell'immagine
-    if let Ok(img) = image::open(input_path) {
-        
-        let orig_w = img.width();
-        let orig_h = img.height();
-        
-        // Calcola dimensioni di lavoro
-        let (work_w, work_h) = if max_dimension > 0 && (orig_w > max_dimension as u32 || orig_h > max_dimension as u32) {
-            let scale = max_dimension as f32 / orig_w.max(orig_h) as f32;
-            let new_w = (orig_w as f32 * scale) as u32;
-            let new_h = (orig_h as f32 * scale) as u32;
-            (new_w, new_h)
-        } else {
-            (orig_w, orig_h)
+/// Confronta un'immagine con il riferimento e stampa le metriche
+fn run_compare(args: &CompareArgs) {
+    let load = |path: &PathBuf| {
+        load_dynamic_image(path).unwrap_or_else(|e| {
+            eprintln!("❌ Error: cannot read '{}': {}", path.display(), e);
+            std::process::exit(1);
+        })
+    };
+    let reference = FloatImage::from_dynamic(&load(&args.reference));
+    let test = FloatImage::from_dynamic(&load(&args.test));
+    let mask = args.mask.as_ref().map(|path| mask_from_image(&load(path)));
+
+    let report = QualityReport::compute(&reference, &test, mask.as_deref()).unwrap_or_else(|e| {
+        eprintln!("❌ Error: {}", e);
+        std::process::exit(1);
+    });
+
+    if let Some(path) = &args.heatmap {
+        let saved = metrics::difference_heatmap(&reference, &test, args.heatmap_scale)
+            .and_then(|img| save_dynamic_image(&image::DynamicImage::ImageRgb8(img), path));
+        match saved {
+            Ok(outcome) => warn_if_downgraded(path, outcome),
+            Err(e) => {
+                eprintln!("❌ Error: cannot write heatmap '{}': {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.json {
+        let json = serde_json::json!({
+            "reference": args.reference,
+            "test": args.test,
+            "mask": args.mask,
+            "metrics": report,
+        });
+        println!("{}", serde_json::to_string_pretty(&json).expect("report is always serializable"));
+        return;
+    }
+
+    println!("📊 Quality of {} against {}", args.test.display(), args.reference.display());
+    println!("  PSNR:     {:.3} dB", report.psnr);
+    println!("  SSIM:     {:.5}", report.ssim);
+    match report.ms_ssim {
+        Some(v) => println!("  MS-SSIM:  {:.5}", v),
+        None => println!("  MS-SSIM:  n/a (image smaller than 176x176)"),
+    }
+    println!("  MSE:      {:.6e}", report.mse);
+
+    if report.channels.len() > 1 {
+        let names = match reference.layout() {
+            ChannelLayout::Rgb | ChannelLayout::Rgba => ["R", "G", "B"],
+            _ => ["Y", "", ""],
         };
-        
-        // Calcola numero di blocchi
-        let blocks_x = (work_w as usize).saturating_sub(block_size) / step_size + 1;
-        let blocks_y = (work_h as usize).saturating_sub(block_size) / step_size + 1;
-        let total_blocks = blocks_x * blocks_y;
-        
-        // Calcola operazioni per blocco
-        let positions_per_block = window_size * window_size;
-        let ops_per_block = positions_per_block * block_size * block_size * 3; // 3 canali RGB
-        
-        // Stima tempo (empirica)
-        let ops_per_second = 50_000_000.0; // Operazioni al secondo stimate
-        let estimated_seconds = (total_blocks as f64 * ops_per_block as f64) / ops_per_second;
-        
-        println!("");
-        println!("📈 Estimation:");
-        println!("  Original size:      {} x {}", orig_w, orig_h);
-        println!("  Working size:       {} x {}", work_w, work_h);
-        println!("  Total blocks:       {}", total_blocks);
-        println!("  Positions/block:    {}", positions_per_block);
-        println!("  Operations/block:   {:.1}M", ops_per_block as f64 / 1_000_000.0);
-        println!("  Total operations:   {:.1}B", (total_blocks as f64 * ops_per_block as f64) / 1_000_000_000.0);
-        println!("");
-        println!("⏱️  Estimated time:");
-        
-        if estimated_seconds < 60.0 {
-            println!("  About {:.1} seconds", estimated_seconds);
-        } else if estimated_seconds < 3600.0 {
-            println!("  About {:.1} minutes", estimated_seconds / 60.0);
-        } else {
-            println!("  About {:.1} hours", estimated_seconds / 3600.0);
+        println!();
+        for (name, channel) in names.iter().zip(&report.channels) {
+            println!(
+                "  {}: PSNR {:.3} dB, SSIM {:.5}, MSE {:.6e}",
+                name, channel.psnr, channel.ssim, channel.mse
+            );
+        }
+    }
+    if let Some(path) = &args.heatmap {
+        println!();
+        println!("🗺️  Heatmap saved to: {}", path.display());
+    }
+}
+
+/// Avvisa su stderr se il formato di uscita ha tolto profondità o alpha: stdout resta per i report
+fn warn_if_downgraded(path: &Path, outcome: SaveOutcome) {
+    if !outcome.is_exact() {
+        eprintln!("⚠️  Warning: '{}': {}", path.display(), outcome);
+    }
+}
+
+/// Aggiunge rumore sintetico e salva i parametri usati
+fn run_add_noise(args: &AddNoiseArgs) {
+    let fail = |msg: String| -> ! {
+        eprintln!("❌ Error: {}", msg);
+        std::process::exit(1);
+    };
+
+    let img = load_dynamic_image(&args.input)
+        .unwrap_or_else(|e| fail(format!("cannot read '{}': {}", args.input.display(), e)));
+    let clean = FloatImage::from_dynamic(&img);
+    let sigma = SigmaScale::from(args.sigma_scale).normalize(args.sigma, clean.depth());
+
+    let model = match args.model {
+        NoiseArg::Gaussian => NoiseModel::Gaussian { sigma },
+        NoiseArg::Poisson => NoiseModel::Poisson { peak: args.peak },
+        NoiseArg::PoissonGaussian => NoiseModel::PoissonGaussian { peak: args.peak, sigma },
+        NoiseArg::SaltAndPepper => NoiseModel::SaltAndPepper { density: args.density, salt_ratio: args.salt_ratio },
+        NoiseArg::Speckle => NoiseModel::Speckle { sigma },
+        NoiseArg::Correlated => NoiseModel::Correlated { sigma, correlation: args.correlation },
+    };
+    let record = NoiseRecord { model, seed: args.seed, clip: !args.no_clip };
+
+    let noisy = record.apply(&clean).unwrap_or_else(|e| fail(e.to_string()));
+    let outcome = save_dynamic_image(&noisy.to_dynamic(), &args.output)
+        .unwrap_or_else(|e| fail(format!("cannot write '{}': {}", args.output.display(), e)));
+    warn_if_downgraded(&args.output, outcome);
+
+    let record_file = args.record.clone().unwrap_or_else(|| record_path(&args.output));
+    record
+        .save(&record_file)
+        .unwrap_or_else(|e| fail(format!("cannot write '{}': {}", record_file.display(), e)));
+
+    println!("✅ Noisy image saved to: {}", args.output.display());
+    println!("📝 Noise parameters saved to: {}", record_file.display());
+    println!("{}", record.to_json());
+}
+
+/// Esegue il benchmark e scrive le tabelle dei risultati
+fn run_bench(args: &BenchArgs) {
+    let fail = |msg: String| -> ! {
+        eprintln!("❌ Error: {}", msg);
+        std::process::exit(1);
+    };
+
+    let images = bench::list_images(&args.images)
+        .unwrap_or_else(|e| fail(format!("cannot list '{}': {}", args.images.display(), e)));
+    if images.is_empty() {
+        fail(format!("no images in '{}'", args.images.display()));
+    }
+
+    let config = BenchConfig {
+        images,
+        sigmas: args.sigmas.clone(),
+        algorithms: args.algorithm.iter().map(|&a| a.into()).collect(),
+        presets: args.preset.iter().map(|&p| p.into()).collect(),
+        seed: args.seed,
+        max_dimension: args.max_dimension,
+    };
+
+    let runs = config.images.len() * config.sigmas.len() * config.algorithms.len() * config.presets.len();
+    println!("🏁 Benchmark: {} runs", runs);
+    let mut rows = Vec::new();
+    let results = bench::run(&config, |r| {
+        let row = format!(
+            "  {:<24} {:>4}x{:<4} σ={:<5} {:<7} {:<12} PSNR {:6.2} → {:6.2} dB  SSIM {:.4} → {:.4}  {:7.2}s  {}",
+            r.image,
+            r.width,
+            r.height,
+            r.sigma,
+            r.algorithm,
+            r.preset,
+            r.noisy_psnr,
+            r.psnr,
+            r.noisy_ssim,
+            r.ssim,
+            r.seconds,
+            r.peak_memory_bytes.map_or_else(|| "n/a".to_string(), |b| format!("{:.1} MB", b as f64 / 1_048_576.0)),
+        );
+        println!("{}", row);
+        rows.push(row);
+    })
+    .unwrap_or_else(|e| fail(e.to_string()));
+
+    println!();
+    println!("📊 Results:");
+    rows.iter().for_each(|row| println!("{}", row));
+
+    if let Some(path) = &args.csv {
+        let file = std::fs::File::create(path).unwrap_or_else(|e| fail(format!("cannot create '{}': {}", path.display(), e)));
+        bench::write_csv(&results, std::io::BufWriter::new(file)).unwrap_or_else(|e| fail(e.to_string()));
+        println!("📁 CSV saved to: {}", path.display());
+    }
+    if let Some(path) = &args.json {
+        let file = std::fs::File::create(path).unwrap_or_else(|e| fail(format!("cannot create '{}': {}", path.display(), e)));
+        bench::write_json(&results, std::io::BufWriter::new(file)).unwrap_or_else(|e| fail(e.to_string()));
+        println!("📁 JSON saved to: {}", path.display());
+    }
+}
+
+/// Denoise con non-local means
+fn run_nlm(args: &NlmArgs) {
+    let fail = |msg: String| -> ! {
+        eprintln!("❌ Error: {}", msg);
+        std::process::exit(1);
+    };
+
+    let img = load_dynamic_image(&args.input)
+        .unwrap_or_else(|e| fail(format!("cannot read '{}': {}", args.input.display(), e)));
+    let img = fit_max_dimension(img, args.max_dimension);
+    let params = NlmParams {
+        sigma: args.sigma,
+        sigma_scale: args.sigma_scale.into(),
+        patch_size: args.patch_size,
+        search_window: args.search_window,
+        h: args.h,
+        variant: if args.pixelwise { NlmVariant::Pixelwise } else { NlmVariant::Fast },
+    };
+
+    println!("🔍 Non-local means: {} ({}x{}), sigma {}", args.input.display(), img.width(), img.height(), args.sigma);
+    let start = std::time::Instant::now();
+    let denoised = bm3d_rs::nlm::denoise_image(&img, &params).unwrap_or_else(|e| fail(e.to_string()));
+    let outcome = save_dynamic_image(&denoised, &args.output)
+        .unwrap_or_else(|e| fail(format!("cannot write '{}': {}", args.output.display(), e)));
+    warn_if_downgraded(&args.output, outcome);
+
+    println!("✅ Denoising completed in {:.2}s", start.elapsed().as_secs_f64());
+    println!("📁 Output saved to: {}", args.output.display());
+}
+
+/// Stima tempo e memoria con una breve calibrazione sui blocchi reali dell'immagine
+fn estimate_processing_time(input_path: &PathBuf, params: &Bm3dParams, max_dimension: u32) {
+    println!("⏱️  Calibrating on a sample of reference blocks...");
+
+    let img = match load_dynamic_image(input_path) {
+        Ok(img) => img,
+        Err(e) => {
+            eprintln!("❌ Could not open image for estimation: {}", e);
+            std::process::exit(1);
         }
-        
-        println!("");
-        println!("💡 Suggestions:");
-        
-        if estimated_seconds > 300.0 {
-            println!("  ⚠️  This will take a long time!");
-            println!("  Try: --fast-params or --max-dimension 256");
-        } else if estimated_seconds > 60.0 {
-            println!("  ⏳ This will take a few minutes");
-            println!("  Consider: --step-size {}", step_size * 2);
-        } else {
-            println!("  🚀 This should be relatively fast");
+    };
+    let (orig_w, orig_h) = (img.width(), img.height());
+    let buffer = FloatImage::from_dynamic(&fit_max_dimension(img, max_dimension));
+
+    let estimate = match bm3d_rs::estimate(&buffer, params, 256) {
+        Ok(estimate) => estimate,
+        Err(e) => {
+            eprintln!("❌ Error: {}", e);
+            std::process::exit(1);
         }
+    };
+    let estimated_seconds = estimate.seconds;
+
+    println!();
+    println!("📈 Estimation:");
+    println!("  Original size:      {} x {}", orig_w, orig_h);
+    println!("  Working size:       {} x {}", buffer.width(), buffer.height());
+    println!("  Reference blocks:   {}", estimate.reference_blocks);
+    println!("  Calibration:        {} blocks in {:.2}s", estimate.sampled_blocks, estimate.calibration_seconds);
+    println!("  Patches per group:  {:.1}", estimate.patches_per_group);
+    println!("  Threads:            {}", estimate.threads);
+    println!("  Throughput:         {:.0} blocks/sec", estimate.blocks_per_second);
+    println!("  Peak memory:        {:.1} MB", estimate.peak_memory_bytes as f64 / 1_048_576.0);
+    println!();
+    println!("⏱️  Estimated time:");
+
+    if estimated_seconds < 60.0 {
+        println!("  About {:.1} seconds", estimated_seconds);
+    } else if estimated_seconds < 3600.0 {
+        println!("  About {:.1} minutes", estimated_seconds / 60.0);
+    } else {
+        println!("  About {:.1} hours", estimated_seconds / 3600.0);
+    }
+
+    println!();
+    println!("💡 Suggestions:");
+
+    if estimated_seconds > 300.0 {
+        println!("  ⚠️  This will take a long time!");
+        println!("  Try: --fast-params or --max-dimension 256");
+    } else if estimated_seconds > 60.0 {
+        println!("  ⏳ This will take a few minutes");
+        let step_size = params.get_usize(&Parameters::Step1SpeedupFactor).unwrap_or(1);
+        println!("  Consider: --step-size {}", step_size * 2);
     } else {
-        eprintln!("❌ Could not open image for estimation");
+        println!("  🚀 This should be relatively fast");
     }
 }
@@ -0,0 +1,392 @@
+//! Non-local means denoiser (Buades, Coll, Morel).
+//! params:
+//! - sigma and its scale
+//! - patch size, search window and filtering parameter h (0 = chosen from sigma as in the IPOL article)
+//! - variant: pixelwise reference or fast integral images of distances
+//!
+//! Every pixel becomes the weighted mean of the centres of the patches in its search window,
+//! weights are `exp(-max(d² - 2σ², 0) / h²)` with `d²` the mean squared patch distance over the
+//! color channels. The centre pixel gets the largest weight of its neighbours.
+//! The search window is clipped at the image borders, patches are completed by mirroring.
+//! Alpha is copied unchanged.
+
+use crate::blocks::match_b::{extract_patch, l2_distance_at};
+use crate::error::ImageProcessingError;
+use crate::utils::border::BorderMode;
+use crate::utils::buffer::{FloatImage, SigmaScale};
+use crate::Preset;
+use image::DynamicImage;
+use rayon::prelude::*;
+
+/// Rows of a strip of the fast variant, each strip is an independent parallel job
+const STRIP_ROWS: usize = 32;
+
+/// How patch distances are computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum NlmVariant {
+    /// one patch distance per pixel and neighbour, O(patch²) each; the reference implementation
+    Pixelwise,
+    /// one integral image of squared differences per offset, O(1) per pixel and neighbour
+    #[default]
+    Fast,
+}
+
+/// Parameters of the non-local means denoiser
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NlmParams {
+    /// noise standard deviation, must be > 0
+    pub sigma: f64,
+    /// scale on which `sigma` and `h` are expressed
+    pub sigma_scale: SigmaScale,
+    /// patch side, odd, 0 = from sigma
+    pub patch_size: usize,
+    /// side of the search window, odd, 0 = from sigma
+    pub search_window: usize,
+    /// filtering parameter, 0 = a fraction of sigma
+    pub h: f64,
+    /// pixelwise or integral images
+    pub variant: NlmVariant,
+}
+
+impl Default for NlmParams {
+    fn default() -> Self {
+        NlmParams {
+            sigma: 25.0,
+            sigma_scale: SigmaScale::EightBit,
+            patch_size: 0,
+            search_window: 0,
+            h: 0.0,
+            variant: NlmVariant::Fast,
+        }
+    }
+}
+
+impl NlmParams {
+    /// Default parameters with a known noise sigma
+    pub fn with_sigma(sigma: f64) -> Self {
+        NlmParams { sigma, ..Self::default() }
+    }
+
+    /// Parameters of a preset: a smaller search window for `Fast`, a wider one for `HighQuality`
+    pub fn preset(preset: Preset, sigma: f64) -> Self {
+        let base = Self::with_sigma(sigma);
+        match preset {
+            Preset::Fast => NlmParams { search_window: 11, ..base },
+            Preset::Default => base,
+            Preset::HighQuality => NlmParams { search_window: 35, ..base },
+        }
+    }
+
+    fn validate(&self) -> Result<(), ImageProcessingError> {
+        if !(self.sigma.is_finite() && self.sigma > 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!("sigma must be > 0 (got {})", self.sigma)));
+        }
+        if !(self.h.is_finite() && self.h >= 0.0) {
+            return Err(ImageProcessingError::InvalidParameter(format!("h must be >= 0 (got {})", self.h)));
+        }
+        if self.patch_size != 0 && self.patch_size.is_multiple_of(2) {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "patch size must be odd (got {})",
+                self.patch_size
+            )));
+        }
+        if self.search_window != 0 && (self.search_window < 3 || self.search_window.is_multiple_of(2)) {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "search window must be odd and >= 3 (got {})",
+                self.search_window
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sizes and h on the normalized scale, the zeros replaced as in the IPOL article
+    fn settings(&self, img: &FloatImage) -> Settings {
+        let sigma = self.sigma_scale.normalize(self.sigma, img.depth());
+        let color = img.layout().color_channels() == 3;
+        // tabella IPOL (Buades, Coll, Morel 2011) su sigma in scala 0-255
+        let (patch, search, factor) = match (color, sigma * 255.0) {
+            (false, s) if s <= 15.0 => (3, 21, 0.40),
+            (false, s) if s <= 30.0 => (5, 21, 0.40),
+            (false, s) if s <= 45.0 => (7, 35, 0.35),
+            (false, s) if s <= 75.0 => (9, 35, 0.35),
+            (false, _) => (11, 35, 0.30),
+            (true, s) if s <= 25.0 => (3, 21, 0.55),
+            (true, s) if s <= 55.0 => (5, 35, 0.40),
+            (true, _) => (7, 35, 0.35),
+        };
+        Settings {
+            sigma,
+            patch: if self.patch_size == 0 { patch } else { self.patch_size },
+            search: if self.search_window == 0 { search } else { self.search_window },
+            h: if self.h == 0.0 { factor * sigma } else { self.sigma_scale.normalize(self.h, img.depth()) },
+        }
+    }
+}
+
+/// Resolved parameters, normalized scale
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    sigma: f64,
+    patch: usize,
+    search: usize,
+    h: f64,
+}
+
+impl Settings {
+    /// weight of a neighbour whose patch is at squared distance `dist` (sum over `samples` values)
+    fn weight(&self, dist: f64, samples: f64) -> f64 {
+        let d2 = dist / samples - 2.0 * self.sigma * self.sigma;
+        (-d2.max(0.0) / (self.h * self.h)).exp()
+    }
+}
+
+/// Denoise an in-memory image, bit depth and channel layout are kept
+pub fn denoise_image(image: &DynamicImage, params: &NlmParams) -> Result<DynamicImage, ImageProcessingError> {
+    let buffer = FloatImage::from_dynamic(image);
+    Ok(denoise_buffer(&buffer, params)?.to_dynamic())
+}
+
+denoiser! {
+    #[derive(Copy, PartialEq)]
+    NlmDenoiser(NlmParams),
+    name = "nlm"
+}
+
+/// Denoise a float buffer, rows (or strips of rows) are processed in parallel
+pub fn denoise_buffer(img: &FloatImage, params: &NlmParams) -> Result<FloatImage, ImageProcessingError> {
+    params.validate()?;
+    img.ensure_not_empty()?;
+    let settings = params.settings(img);
+    let channels = img.layout().color_channels();
+    let padded = pad(img, settings.patch / 2)?;
+
+    let denoised = match params.variant {
+        NlmVariant::Pixelwise => pixelwise(&padded, img.width(), img.height(), channels, &settings),
+        NlmVariant::Fast => integral(&padded, img.width(), img.height(), channels, &settings),
+    };
+
+    let mut out = img.clone();
+    for (c, plane) in out.planes_mut().iter_mut().take(channels).enumerate() {
+        for (dst, src) in plane.iter_mut().zip(denoised.iter().skip(c).step_by(channels)) {
+            *dst = *src as f32;
+        }
+    }
+    Ok(out)
+}
+
+/// Reference implementation on the block matching distances, interleaved output
+fn pixelwise(padded: &FloatImage, width: usize, height: usize, channels: usize, s: &Settings) -> Vec<f64> {
+    let (r, half) = (s.patch / 2, s.search / 2);
+    let samples = (channels * s.patch * s.patch) as f64;
+
+    let mut out = vec![0.0; width * height * channels];
+    out.par_chunks_mut(width * channels).enumerate().for_each(|(y, row)| {
+        let mut acc = Accumulator::new(channels);
+        for x in 0..width {
+            acc.clear();
+            let reference = extract_patch(padded, (x, y), s.patch, true).expect("the padded image covers every patch");
+            for qy in y.saturating_sub(half)..=(y + half).min(height - 1) {
+                for qx in x.saturating_sub(half)..=(x + half).min(width - 1) {
+                    if (qx, qy) == (x, y) {
+                        continue;
+                    }
+                    let dist = l2_distance_at(padded, &reference, (qx, qy), s.patch, channels) as f64;
+                    acc.add(s.weight(dist, samples), |c| padded.get(c, qx + r, qy + r));
+                }
+            }
+            acc.finish(|c| padded.get(c, x + r, y + r), &mut row[x * channels..][..channels]);
+        }
+    });
+    out
+}
+
+/// Fast variant: for every offset the squared differences between the image and its shifted copy
+/// are summed in an integral image, a patch distance is then four lookups
+fn integral(padded: &FloatImage, width: usize, height: usize, channels: usize, s: &Settings) -> Vec<f64> {
+    let (r, half) = (s.patch / 2, (s.search / 2) as isize);
+    let samples = (channels * s.patch * s.patch) as f64;
+    let pw = padded.width();
+    let ph = padded.height();
+
+    let mut out = vec![0.0; width * height * channels];
+    out.par_chunks_mut(STRIP_ROWS * width * channels).enumerate().for_each(|(strip, chunk)| {
+        let y0 = strip * STRIP_ROWS;
+        let rows = chunk.len() / (width * channels);
+        // righe del padding coperte dalle patch della striscia
+        let sh = rows + 2 * r;
+        let stride = pw + 1;
+        let mut table = vec![0.0f64; (sh + 1) * stride];
+        let mut acc: Vec<Accumulator> = (0..rows * width).map(|_| Accumulator::new(channels)).collect();
+
+        for dy in -half..=half {
+            for dx in -half..=half {
+                if (dx, dy) == (0, 0) {
+                    continue;
+                }
+                for i in 0..sh {
+                    let (py, qy) = (y0 + i, (y0 + i) as isize + dy);
+                    let mut line = 0.0;
+                    for j in 0..pw {
+                        let qx = j as isize + dx;
+                        if (0..ph as isize).contains(&qy) && (0..pw as isize).contains(&qx) {
+                            line += (0..channels)
+                                .map(|c| {
+                                    let d = padded.get(c, j, py) as f64 - padded.get(c, qx as usize, qy as usize) as f64;
+                                    d * d
+                                })
+                                .sum::<f64>();
+                        }
+                        table[(i + 1) * stride + j + 1] = table[i * stride + j + 1] + line;
+                    }
+                }
+
+                for row in 0..rows {
+                    let qy = (y0 + row) as isize + dy;
+                    if !(0..height as isize).contains(&qy) {
+                        continue;
+                    }
+                    for x in 0..width {
+                        let qx = x as isize + dx;
+                        if !(0..width as isize).contains(&qx) {
+                            continue;
+                        }
+                        let (top, bottom, left, right) = (row, row + s.patch, x, x + s.patch);
+                        let dist = table[bottom * stride + right] - table[top * stride + right] - table[bottom * stride + left]
+                            + table[top * stride + left];
+                        let (qx, qy) = (qx as usize + r, qy as usize + r);
+                        acc[row * width + x].add(s.weight(dist.max(0.0), samples), |c| padded.get(c, qx, qy));
+                    }
+                }
+            }
+        }
+
+        for (i, (acc, px)) in acc.iter().zip(chunk.chunks_mut(channels)).enumerate() {
+            let (x, y) = (i % width, y0 + i / width);
+            acc.finish(|c| padded.get(c, x + r, y + r), px);
+        }
+    });
+    out
+}
+
+/// Weighted sum of the neighbours of one pixel
+#[derive(Debug, Clone)]
+struct Accumulator {
+    sum: Vec<f64>,
+    total: f64,
+    max: f64,
+}
+
+impl Accumulator {
+    fn new(channels: usize) -> Self {
+        Accumulator { sum: vec![0.0; channels], total: 0.0, max: 0.0 }
+    }
+
+    fn clear(&mut self) {
+        self.sum.iter_mut().for_each(|v| *v = 0.0);
+        self.total = 0.0;
+        self.max = 0.0;
+    }
+
+    fn add(&mut self, weight: f64, value: impl Fn(usize) -> f32) {
+        for (c, v) in self.sum.iter_mut().enumerate() {
+            *v += weight * value(c) as f64;
+        }
+        self.total += weight;
+        self.max = self.max.max(weight);
+    }
+
+    /// add the centre with the largest weight of its neighbours and normalize
+    fn finish(&self, centre: impl Fn(usize) -> f32, out: &mut [f64]) {
+        let weight = if self.max > 0.0 { self.max } else { 1.0 };
+        for (c, o) in out.iter_mut().enumerate() {
+            *o = (self.sum[c] + weight * centre(c) as f64) / (self.total + weight);
+        }
+    }
+}
+
+/// Color planes of `img` with a mirrored border of `r` pixels
+fn pad(img: &FloatImage, r: usize) -> Result<FloatImage, ImageProcessingError> {
+    let (width, height) = img.dimensions();
+    let (pw, ph) = (width + 2 * r, height + 2 * r);
+    let r = r as isize;
+    let planes = img
+        .planes()
+        .iter()
+        .take(img.layout().color_channels())
+        .map(|plane| {
+            let mut padded = Vec::with_capacity(pw * ph);
+            for y in 0..ph {
+                let y = y as isize - r;
+                padded.extend((0..pw).map(|x| BorderMode::Reflect.pixel(plane, width, height, x as isize - r, y)));
+            }
+            padded
+        })
+        .collect();
+    FloatImage::from_planes(pw, ph, img.layout().without_alpha(), img.depth(), planes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::metrics::psnr;
+    use crate::utils::noise::{NoiseModel, NoiseRecord};
+    use crate::utils::testing::assert_invalid;
+
+    fn clean(width: u32, height: u32) -> FloatImage {
+        FloatImage::from_dynamic(&DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            let edge = if x > width / 2 { 80 } else { 0 };
+            image::Rgb([(x + edge) as u8 + 30, (y * 2) as u8 + 20, 100 + edge as u8])
+        })))
+    }
+
+    #[test]
+    fn test_variants_agree() {
+        let clean = clean(37, 29);
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 20.0 / 255.0 }, 1).apply(&clean).unwrap();
+        let params = NlmParams { search_window: 9, ..NlmParams::with_sigma(20.0) };
+        let fast = denoise_buffer(&noisy, &params).unwrap();
+        let reference = denoise_buffer(&noisy, &NlmParams { variant: NlmVariant::Pixelwise, ..params }).unwrap();
+        for (a, b) in fast.planes().iter().flatten().zip(reference.planes().iter().flatten()) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_improves_color_and_gray() {
+        let clean = clean(64, 48);
+        let gray = FloatImage::from_dynamic(&DynamicImage::ImageLuma16(clean.to_dynamic().to_luma16()));
+        for clean in [clean, gray] {
+            let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 25.0 / 255.0 }, 2).apply(&clean).unwrap();
+            let out = denoise_buffer(&noisy, &NlmParams::with_sigma(25.0)).unwrap();
+            assert_eq!(out.layout(), clean.layout());
+            assert_eq!(out.depth(), clean.depth());
+            let (before, after) = (psnr(&clean, &noisy, None).unwrap(), psnr(&clean, &out, None).unwrap());
+            assert!(after > before + 3.0, "{:?}: {:.2} -> {:.2}", clean.layout(), before, after);
+        }
+    }
+
+    #[test]
+    fn test_keeps_alpha_and_constants() {
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(20, 20, image::Rgba([90, 120, 200, 77])));
+        let out = denoise_image(&rgba, &NlmParams::with_sigma(10.0)).unwrap();
+        assert_eq!(out.as_rgba8().unwrap(), rgba.as_rgba8().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let img = FloatImage::from_dynamic(&DynamicImage::new_luma8(8, 8));
+        assert_invalid(
+            [
+                NlmParams::with_sigma(0.0),
+                NlmParams { patch_size: 4, ..NlmParams::default() },
+                NlmParams { search_window: 1, ..NlmParams::default() },
+                NlmParams { h: -1.0, ..NlmParams::default() },
+            ],
+            |params| denoise_buffer(&img, params),
+        );
+        let empty = FloatImage::from_dynamic(&DynamicImage::new_luma8(0, 0));
+        assert_invalid([NlmVariant::Pixelwise, NlmVariant::Fast], |&variant| {
+            denoise_buffer(&empty, &NlmParams { variant, ..NlmParams::default() })
+        });
+    }
+}
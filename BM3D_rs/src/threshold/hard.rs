@@ -3,6 +3,7 @@
 
 /// Apply hard thresholding to a 2D block in-place.
 /// All coefficients with absolute value less than `threshold` are set to zero.
+#[allow(clippy::ptr_arg)]
 pub fn hard_threshold(block: &mut Vec<Vec<f64>>, threshold: f64) {
     for row in block.iter_mut() {
         for val in row.iter_mut() {
@@ -1 +1,2 @@
 pub mod hard;
+pub mod shrink;
@@ -0,0 +1,159 @@
+//! Wavelet shrinkage: threshold rules and soft/hard thresholding of coefficients
+//! Parameters: sigma, threshold rule, threshold mode
+
+/// How a coefficient is shrunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ThresholdMode {
+    /// keep or kill: |v| < t -> 0, v otherwise
+    Hard,
+    /// shrink towards zero: sign(v) * max(|v| - t, 0)
+    #[default]
+    Soft,
+}
+
+impl ThresholdMode {
+    /// Apply the threshold `t` to `v`
+    pub fn apply(self, v: f64, t: f64) -> f64 {
+        match self {
+            ThresholdMode::Hard => {
+                if v.abs() < t {
+                    0.0
+                } else {
+                    v
+                }
+            }
+            ThresholdMode::Soft => v.signum() * (v.abs() - t).max(0.0),
+        }
+    }
+}
+
+/// How the threshold of a detail band is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ShrinkRule {
+    /// universal threshold sigma * sqrt(2 ln n) (Donoho-Johnstone), the same for every band
+    VisuShrink,
+    /// sigma² / sigma_x per band, from a generalized gaussian prior (Chang-Yu-Vetterli)
+    #[default]
+    BayesShrink,
+    /// per band minimum of Stein's unbiased risk estimate, universal threshold on sparse bands
+    SureShrink,
+}
+
+impl ShrinkRule {
+    /// Threshold of a band, `samples` is the number of pixels of the image (used by VisuShrink)
+    pub fn threshold(self, band: &[f64], sigma: f64, samples: usize) -> f64 {
+        match self {
+            ShrinkRule::VisuShrink => visu_threshold(sigma, samples),
+            ShrinkRule::BayesShrink => bayes_threshold(band, sigma),
+            ShrinkRule::SureShrink => sure_threshold(band, sigma),
+        }
+    }
+}
+
+/// sigma * sqrt(2 ln n)
+pub fn visu_threshold(sigma: f64, samples: usize) -> f64 {
+    sigma * (2.0 * (samples.max(2) as f64).ln()).sqrt()
+}
+
+/// sigma² / sigma_x with sigma_x² = max(E[y²] - sigma², 0); a band with no signal left is removed entirely
+pub fn bayes_threshold(band: &[f64], sigma: f64) -> f64 {
+    if band.is_empty() {
+        return 0.0;
+    }
+    let energy = band.iter().map(|v| v * v).sum::<f64>() / band.len() as f64;
+    let signal = (energy - sigma * sigma).max(0.0).sqrt();
+    if signal == 0.0 {
+        band.iter().fold(0.0, |m, v| m.max(v.abs()))
+    } else {
+        sigma * sigma / signal
+    }
+}
+
+/// SureShrink (Donoho-Johnstone 1995): SURE minimizer for soft thresholding, capped at the universal
+/// threshold, and the universal threshold itself when the band is too sparse for SURE to be reliable
+pub fn sure_threshold(band: &[f64], sigma: f64) -> f64 {
+    let n = band.len();
+    if n == 0 || sigma <= 0.0 {
+        return 0.0;
+    }
+    let universal = (2.0 * (n.max(2) as f64).ln()).sqrt();
+
+    let mut squares: Vec<f64> = band.iter().map(|v| (v / sigma).powi(2)).collect();
+    let total: f64 = squares.iter().sum();
+    let nf = n as f64;
+    // banda sparsa: la stima di SURE è troppo rumorosa
+    let sparsity = (total - nf) / nf;
+    if sparsity <= nf.log2().powf(1.5) / nf.sqrt() {
+        return sigma * universal;
+    }
+
+    squares.sort_by(f64::total_cmp);
+    // SURE(t) = n - 2 #{|y| <= t} + sum min(y², t²), valutato in t = |y_k|
+    let mut best = (nf, 0.0);
+    let mut partial = 0.0;
+    for (k, &sq) in squares.iter().enumerate() {
+        partial += sq;
+        let risk = nf - 2.0 * (k + 1) as f64 + partial + (n - k - 1) as f64 * sq;
+        if risk < best.0 {
+            best = (risk, sq.sqrt());
+        }
+    }
+    sigma * best.1.min(universal)
+}
+
+/// Robust noise estimate from the finest diagonal band: median(|d|) / 0.6745
+pub fn estimate_sigma_mad(band: &[f64]) -> f64 {
+    if band.is_empty() {
+        return 0.0;
+    }
+    let mut abs: Vec<f64> = band.iter().map(|v| v.abs()).collect();
+    let mid = abs.len() / 2;
+    let (_, median, _) = abs.select_nth_unstable_by(mid, f64::total_cmp);
+    *median / 0.6745
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_and_hard() {
+        assert_eq!(ThresholdMode::Hard.apply(0.5, 1.0), 0.0);
+        assert_eq!(ThresholdMode::Hard.apply(-2.0, 1.0), -2.0);
+        assert_eq!(ThresholdMode::Soft.apply(-2.0, 1.0), -1.0);
+        assert_eq!(ThresholdMode::Soft.apply(0.5, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_visu_threshold() {
+        // sqrt(2 ln 1024) = 3.7233
+        assert!((visu_threshold(1.0, 1024) - 3.7233).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_bayes_threshold() {
+        // E[y²] = 5, sigma = 1 -> sigma_x = 2, t = 1/2
+        let band = [1.0, -3.0, 1.0, -3.0, 1.0, 3.0, -1.0, 3.0];
+        assert!((bayes_threshold(&band, 1.0) - 0.5).abs() < 1e-12);
+        // solo rumore: tutta la banda va a zero
+        assert_eq!(bayes_threshold(&[0.5, -0.2], 1.0), 0.5);
+    }
+
+    #[test]
+    fn test_sure_threshold() {
+        // banda sparsa -> soglia universale
+        let sparse = vec![0.0; 256];
+        assert!((sure_threshold(&sparse, 1.0) - (2.0 * 256f64.ln()).sqrt()).abs() < 1e-12);
+
+        // segnale forte su tutta la banda -> soglia piccola
+        let dense: Vec<f64> = (0..256).map(|i| if i % 2 == 0 { 10.0 } else { -10.0 }).collect();
+        assert!(sure_threshold(&dense, 1.0) < 1.0);
+    }
+
+    #[test]
+    fn test_estimate_sigma_mad() {
+        // mediana di |d| = 0.6745 -> sigma = 1
+        let band = [0.1, -0.6745, 2.0, -5.0, 0.6745];
+        assert!((estimate_sigma_mad(&band) - 1.0).abs() < 1e-12);
+    }
+}
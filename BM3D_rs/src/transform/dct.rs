@@ -68,17 +68,13 @@ fn idct1d(matrix: &mut [f64], array_length: Option<usize>) -> Vec<f64> {
         .collect()
 }
 /// implementation of dct2d
+#[allow(clippy::needless_range_loop)]
 pub fn dct2d(matrix: &mut Vec<Vec<f64>>,
     quant_rows: Option<usize>,
     quant_columns: Option<usize>) -> &mut Vec<Vec<f64>>{
-    let rows = quant_rows.unwrap_or_else(|| matrix.len());
+    let rows = quant_rows.unwrap_or(matrix.len());
     let columns = quant_columns.unwrap_or_else(|| matrix[0].len());
-        
-    let _array2d = vec![
-        vec![1.0, 2.0, 3.0],
-        vec![4.0, 5.0, 6.0],
-        vec![7.0, 8.0, 9.0],
-    ];
+
     for row in matrix.iter_mut() {
         *row = dct1d(&mut*row.as_mut_slice(), None);
     }
@@ -89,7 +85,7 @@ pub fn dct2d(matrix: &mut Vec<Vec<f64>>,
             .collect();
 
         // Apply dct to first column
-        let transformed = dct1d(&mut column.as_mut_slice(), None);
+        let transformed = dct1d(column.as_mut_slice(), None);
 
         for (i, val) in transformed.into_iter().enumerate() {
             matrix[i][j] = val;
@@ -98,17 +94,13 @@ pub fn dct2d(matrix: &mut Vec<Vec<f64>>,
     matrix
 }
 /// implementation fo inverse dct2d
+#[allow(clippy::needless_range_loop)]
 pub fn idct2d(matrix: &mut Vec<Vec<f64>>,
     quant_rows: Option<usize>,
     quant_columns: Option<usize>) -> &mut Vec<Vec<f64>>{
-    let rows = quant_rows.unwrap_or_else(|| matrix.len());
+    let rows = quant_rows.unwrap_or(matrix.len());
     let columns = quant_columns.unwrap_or_else(|| matrix[0].len());
-        
-    let _array2d = vec![
-        vec![1.0, 2.0, 3.0],
-        vec![4.0, 5.0, 6.0],
-        vec![7.0, 8.0, 9.0],
-    ];
+
     for row in matrix.iter_mut() {
         *row = idct1d(&mut*row.as_mut_slice(), None);
     }
@@ -118,7 +110,7 @@ pub fn idct2d(matrix: &mut Vec<Vec<f64>>,
             .collect();
 
         // Apply idct to first column
-        let transformed = idct1d(&mut column.as_mut_slice(), None);
+        let transformed = idct1d(column.as_mut_slice(), None);
 
         for (i, val) in transformed.into_iter().enumerate() {
             matrix[i][j] = val;
@@ -153,6 +145,7 @@ impl Dct2D {
     }
 
     /// iDCT with separated in-out
+    #[allow(clippy::ptr_arg)]
     pub fn process(&self, input: &Vec<Vec<f64>>, output: &mut Vec<Vec<f64>>) {
         *output = input.clone();
         self.dct_2d(output);
@@ -177,6 +170,7 @@ impl IDct2D {
     }
 
     /// iDCT with separated in-out
+    #[allow(clippy::ptr_arg)]
     pub fn process(&self, input: &Vec<Vec<f64>>, output: &mut Vec<Vec<f64>>) {
         *output = input.clone();
         self.idct_2d(output);
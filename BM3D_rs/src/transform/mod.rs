@@ -11,4 +11,6 @@
 pub mod dct;
 
 /// Wrapper for wiener functions
-pub mod wiener;
\ No newline at end of file
+pub mod wiener;
+/// Wrapper for 2D wavelet transforms
+pub mod wavelet;
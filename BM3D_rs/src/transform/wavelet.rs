@@ -0,0 +1,616 @@
+//! multi-level 2D discrete wavelet transform built on the `dwt` crate.
+//! params:
+//! - wavelet family (Haar, Daubechies, Symlets)
+//! - decomposition levels
+//!
+//! `dwt` only ships Haar, the other orthogonal families are given as scaling filters
+//! (PyWavelets `rec_lo` order) and turned into `dwt::wavelet::Wavelet` with the GSL convention.
+//! Rows and columns use periodic extension, the image is first padded by mirroring
+//! up to a multiple of `2^levels`.
+//!
+//! Coefficients are stored in the Mallat layout: the approximation of the last level in the
+//! top-left corner and, for each level, three detail bands around it.
+//!
+//! The decimated transform is not translation invariant, thresholding it leaves ringing near
+//! edges. [`cycle_spin`] averages the result over shifted decompositions (Coifman-Donoho).
+//! With every shift of one period it is as translation invariant as the stationary transform,
+//! but it still runs one full decomposition per shift: 4^levels of them.
+
+use crate::error::ImageProcessingError;
+use dwt::{wavelet::Wavelet, Operation, Transform};
+use rayon::prelude::*;
+
+const HAAR: [f64; 2] = [std::f64::consts::FRAC_1_SQRT_2, std::f64::consts::FRAC_1_SQRT_2];
+
+const DB2: [f64; 4] = [
+    0.48296291314453427,
+    0.836516303737808,
+    0.2241438680420133,
+    -0.12940952255126045,
+];
+
+const DB3: [f64; 6] = [
+    0.3326705529500827,
+    0.8068915093110927,
+    0.45987750211849154,
+    -0.13501102001025464,
+    -0.08544127388202664,
+    0.03522629188570957,
+];
+
+const DB4: [f64; 8] = [
+    0.23037781330889656,
+    0.7148465705529159,
+    0.630880767929859,
+    -0.02798376941685991,
+    -0.18703481171909314,
+    0.030841381835560764,
+    0.032883011666885203,
+    -0.010597401785069037,
+];
+
+const DB6: [f64; 12] = [
+    0.11154074335010952,
+    0.4946238903984533,
+    0.7511339080210956,
+    0.3152503517091976,
+    -0.2262646939654401,
+    -0.12976686756726194,
+    0.09750160558732315,
+    0.027522865530305723,
+    -0.031582039317486064,
+    0.000553842201161505,
+    0.004777257510945514,
+    -0.0010773010853084813,
+];
+
+const DB8: [f64; 16] = [
+    0.05441584224310399,
+    0.3128715909142999,
+    0.6756307362972898,
+    0.5853546836542063,
+    -0.015829105256348466,
+    -0.28401554296154746,
+    0.00047248457391377215,
+    0.12874742662047806,
+    -0.017369301001807308,
+    -0.0440882539307948,
+    0.013981027917398274,
+    0.008746094047405771,
+    -0.004870352993451569,
+    -0.00039174037337694716,
+    0.0006754494064505686,
+    -0.00011747678412476937,
+];
+
+const SYM4: [f64; 8] = [
+    0.032223100604051466,
+    -0.012603967262031317,
+    -0.09921954357663351,
+    0.2978577956053061,
+    0.8037387518051322,
+    0.49761866763277496,
+    -0.02963552764600254,
+    -0.07576571478950225,
+];
+
+const SYM6: [f64; 12] = [
+    0.015404109327044843,
+    0.0034907120842222064,
+    -0.11799011114852008,
+    -0.048311742585698175,
+    0.49105594192797364,
+    0.7876411410286513,
+    0.3379294217281657,
+    -0.07263752278637652,
+    -0.021060292512370925,
+    0.04472490177078142,
+    0.0017677118642539969,
+    -0.007800708325032386,
+];
+
+const SYM8: [f64; 16] = [
+    0.0018899503327676872,
+    -0.00030292051472413634,
+    -0.014952258337062178,
+    0.003808752013894498,
+    0.04913717967373023,
+    -0.02721902991710333,
+    -0.05194583810788184,
+    0.3644418948361784,
+    0.7771857516996287,
+    0.4813596512590532,
+    -0.061273359067811166,
+    -0.14329423835127258,
+    0.007607487324976668,
+    0.03169508781152596,
+    -0.0005421323318000062,
+    -0.0033824159510050028,
+];
+
+/// Orthogonal wavelet family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum WaveletFamily {
+    /// Haar (db1), 2 taps
+    Haar,
+    /// Daubechies 2, 4 taps
+    Db2,
+    /// Daubechies 3, 6 taps
+    Db3,
+    /// Daubechies 4, 8 taps
+    Db4,
+    /// Daubechies 6, 12 taps
+    Db6,
+    /// Daubechies 8, 16 taps
+    Db8,
+    /// Symlet 4, 8 taps, nearly symmetric
+    #[default]
+    Sym4,
+    /// Symlet 6, 12 taps
+    Sym6,
+    /// Symlet 8, 16 taps
+    Sym8,
+}
+
+impl WaveletFamily {
+    /// every family, shortest filter first
+    pub const ALL: [WaveletFamily; 9] = [
+        WaveletFamily::Haar,
+        WaveletFamily::Db2,
+        WaveletFamily::Db3,
+        WaveletFamily::Db4,
+        WaveletFamily::Db6,
+        WaveletFamily::Db8,
+        WaveletFamily::Sym4,
+        WaveletFamily::Sym6,
+        WaveletFamily::Sym8,
+    ];
+
+    /// short lower case name, as in PyWavelets
+    pub fn name(self) -> &'static str {
+        match self {
+            WaveletFamily::Haar => "haar",
+            WaveletFamily::Db2 => "db2",
+            WaveletFamily::Db3 => "db3",
+            WaveletFamily::Db4 => "db4",
+            WaveletFamily::Db6 => "db6",
+            WaveletFamily::Db8 => "db8",
+            WaveletFamily::Sym4 => "sym4",
+            WaveletFamily::Sym6 => "sym6",
+            WaveletFamily::Sym8 => "sym8",
+        }
+    }
+
+    /// scaling (low-pass) filter, its taps sum to √2
+    pub fn filter(self) -> &'static [f64] {
+        match self {
+            WaveletFamily::Haar => &HAAR,
+            WaveletFamily::Db2 => &DB2,
+            WaveletFamily::Db3 => &DB3,
+            WaveletFamily::Db4 => &DB4,
+            WaveletFamily::Db6 => &DB6,
+            WaveletFamily::Db8 => &DB8,
+            WaveletFamily::Sym4 => &SYM4,
+            WaveletFamily::Sym6 => &SYM6,
+            WaveletFamily::Sym8 => &SYM8,
+        }
+    }
+
+    /// `dwt` wavelet: the high-pass filter is the quadrature mirror of the low-pass one,
+    /// the filters are centred to limit the shift of the coefficients
+    pub fn wavelet(self) -> Wavelet<f64> {
+        let lo = self.filter().to_vec();
+        let len = lo.len();
+        let hi: Vec<f64> = (0..len).map(|i| if i % 2 == 0 { lo[len - 1 - i] } else { -lo[len - 1 - i] }).collect();
+        Wavelet {
+            length: len,
+            offset: len / 2,
+            dec_lo: lo.clone(),
+            dec_hi: hi.clone(),
+            rec_lo: lo,
+            rec_hi: hi,
+        }
+    }
+
+    /// Deepest decomposition whose coarsest band is still as large as the filter
+    pub fn max_levels(self, width: usize, height: usize) -> usize {
+        let len = self.filter().len();
+        let mut levels = 0;
+        while (width.min(height) >> (levels + 1)) >= len {
+            levels += 1;
+        }
+        levels
+    }
+}
+
+/// How the image is sampled by the decomposition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum WaveletMode {
+    /// one critically sampled decomposition, fastest
+    #[default]
+    Decimated,
+    /// average over the `shifts` x `shifts` smallest shifts, the finest bands (where most of
+    /// the noise lives) are the ones that gain the most from a few shifts
+    CycleSpinning {
+        /// shifts per axis, 1 is the decimated transform
+        shifts: usize,
+    },
+    /// every shift of one period: fully translation invariant, but `4^levels` decompositions
+    /// (256 at 4 levels) instead of the O(N * levels) of an a trous stationary transform
+    FullCycleSpinning,
+}
+
+impl WaveletMode {
+    /// short lower case name
+    pub fn name(self) -> &'static str {
+        match self {
+            WaveletMode::Decimated => "decimated",
+            WaveletMode::CycleSpinning { .. } => "cycle-spinning",
+            WaveletMode::FullCycleSpinning => "full-cycle-spinning",
+        }
+    }
+
+    /// shifts per axis for a decomposition on `levels` levels
+    pub fn shifts(self, levels: usize) -> usize {
+        let period = 1 << levels;
+        match self {
+            WaveletMode::Decimated => 1,
+            WaveletMode::CycleSpinning { shifts } => shifts.clamp(1, period),
+            WaveletMode::FullCycleSpinning => period,
+        }
+    }
+}
+
+/// Direction of a detail band
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Orientation {
+    /// horizontal edges: low-pass on rows, high-pass on columns
+    Horizontal,
+    /// vertical edges: high-pass on rows, low-pass on columns
+    Vertical,
+    /// diagonal details: high-pass on both
+    Diagonal,
+}
+
+/// Rectangle of a detail band in the coefficient plane
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Band {
+    /// decomposition level, 1 is the finest
+    pub level: usize,
+    /// band direction
+    pub orientation: Orientation,
+    /// left column
+    pub x: usize,
+    /// top row
+    pub y: usize,
+    /// band width
+    pub width: usize,
+    /// band height
+    pub height: usize,
+}
+
+/// Multi-level 2D wavelet decomposition of one plane
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dwt2 {
+    family: WaveletFamily,
+    levels: usize,
+    /// size of the image before padding
+    width: usize,
+    height: usize,
+    /// position of the image in the padded plane, (0, 0) without cycle spinning
+    shift: (usize, usize),
+    /// size of the coefficient plane, multiple of 2^levels
+    padded_width: usize,
+    padded_height: usize,
+    coeffs: Vec<f64>,
+}
+
+impl Dwt2 {
+    /// Decompose a `width` x `height` plane on `levels` levels
+    pub fn forward(plane: &[f32], width: usize, height: usize, family: WaveletFamily, levels: usize) -> Result<Self, ImageProcessingError> {
+        Self::forward_shifted(plane, width, height, family, levels, (0, 0))
+    }
+
+    /// Decompose the plane moved by `shift` pixels inside the padded plane, each shift below `2^levels`.
+    /// A plane of the wrong size, too many levels or a shift outside one period give
+    /// `WaveletError`; the denoiser checks its own parameters before getting here.
+    pub fn forward_shifted(
+        plane: &[f32],
+        width: usize,
+        height: usize,
+        family: WaveletFamily,
+        levels: usize,
+        shift: (usize, usize),
+    ) -> Result<Self, ImageProcessingError> {
+        let period = 1 << levels;
+        if plane.len() != width * height
+            || levels == 0
+            || levels > family.max_levels(width, height)
+            || shift.0 >= period
+            || shift.1 >= period
+        {
+            return Err(ImageProcessingError::WaveletError);
+        }
+
+        let (dx, dy) = shift;
+        let padded_width = padded_len(width + dx, levels);
+        let padded_height = padded_len(height + dy, levels);
+        let mut coeffs = vec![0.0; padded_width * padded_height];
+        // mirror è periodico su 2*len: y + 2*height - dy riflette anche le righe prima dello shift
+        for y in 0..padded_height {
+            let src = mirror(y + 2 * height - dy, height) * width;
+            for x in 0..padded_width {
+                coeffs[y * padded_width + x] = plane[src + mirror(x + 2 * width - dx, width)] as f64;
+            }
+        }
+
+        let mut dwt = Dwt2 { family, levels, width, height, shift, padded_width, padded_height, coeffs };
+        let wavelet = family.wavelet();
+        for level in 0..levels {
+            dwt.step(level, Operation::Forward, &wavelet);
+        }
+        Ok(dwt)
+    }
+
+    /// Rebuild the plane, padding removed
+    pub fn inverse(&self) -> Vec<f32> {
+        let mut dwt = self.clone();
+        let wavelet = self.family.wavelet();
+        for level in (0..self.levels).rev() {
+            dwt.step(level, Operation::Inverse, &wavelet);
+        }
+        let (dx, dy) = self.shift;
+        let mut plane = Vec::with_capacity(self.width * self.height);
+        for y in dy..dy + self.height {
+            let row = &dwt.coeffs[y * self.padded_width + dx..][..self.width];
+            plane.extend(row.iter().map(|&v| v as f32));
+        }
+        plane
+    }
+
+    /// decomposition levels
+    pub fn levels(&self) -> usize {
+        self.levels
+    }
+
+    /// wavelet family
+    pub fn family(&self) -> WaveletFamily {
+        self.family
+    }
+
+    /// all the detail bands, finest level first
+    pub fn detail_bands(&self) -> Vec<Band> {
+        let mut bands = Vec::with_capacity(3 * self.levels);
+        for level in 1..=self.levels {
+            let (w, h) = (self.padded_width >> level, self.padded_height >> level);
+            for (orientation, x, y) in [
+                (Orientation::Horizontal, 0, h),
+                (Orientation::Vertical, w, 0),
+                (Orientation::Diagonal, w, h),
+            ] {
+                bands.push(Band { level, orientation, x, y, width: w, height: h });
+            }
+        }
+        bands
+    }
+
+    /// coefficients of a band, row major
+    pub fn band(&self, band: &Band) -> Vec<f64> {
+        let mut values = Vec::with_capacity(band.width * band.height);
+        for y in band.y..band.y + band.height {
+            values.extend_from_slice(&self.coeffs[y * self.padded_width + band.x..][..band.width]);
+        }
+        values
+    }
+
+    /// apply `f` to every coefficient of a band
+    pub fn map_band(&mut self, band: &Band, f: impl Fn(f64) -> f64) {
+        for y in band.y..band.y + band.height {
+            for v in &mut self.coeffs[y * self.padded_width + band.x..][..band.width] {
+                *v = f(*v);
+            }
+        }
+    }
+
+    /// one level on the top-left `2^-level` part: rows then columns forward, the opposite inverse
+    fn step(&mut self, level: usize, operation: Operation, wavelet: &Wavelet<f64>) {
+        let (w, h) = (self.padded_width >> level, self.padded_height >> level);
+        let stride = self.padded_width;
+        let rows = |coeffs: &mut [f64]| {
+            for y in 0..h {
+                coeffs[y * stride..][..w].transform(operation, wavelet, 1);
+            }
+        };
+        let columns = |coeffs: &mut [f64]| {
+            let mut column = vec![0.0; h];
+            for x in 0..w {
+                for (y, c) in column.iter_mut().enumerate() {
+                    *c = coeffs[y * stride + x];
+                }
+                column.transform(operation, wavelet, 1);
+                for (y, c) in column.iter().enumerate() {
+                    coeffs[y * stride + x] = *c;
+                }
+            }
+        };
+        match operation {
+            Operation::Forward => {
+                rows(&mut self.coeffs);
+                columns(&mut self.coeffs);
+            }
+            Operation::Inverse => {
+                columns(&mut self.coeffs);
+                rows(&mut self.coeffs);
+            }
+        }
+    }
+}
+
+/// Translation invariant processing: decompose the plane for every shift of `mode`, let `f` change
+/// the coefficients, rebuild and average. Shifts run in parallel.
+pub fn cycle_spin(
+    plane: &[f32],
+    width: usize,
+    height: usize,
+    family: WaveletFamily,
+    levels: usize,
+    mode: WaveletMode,
+    f: impl Fn(&mut Dwt2) + Sync,
+) -> Result<Vec<f32>, ImageProcessingError> {
+    let shifts = mode.shifts(levels);
+    let offsets: Vec<(usize, usize)> = (0..shifts * shifts).map(|i| (i % shifts, i / shifts)).collect();
+
+    let sum = offsets
+        .par_iter()
+        .map(|&shift| -> Result<Vec<f64>, ImageProcessingError> {
+            let mut dwt = Dwt2::forward_shifted(plane, width, height, family, levels, shift)?;
+            f(&mut dwt);
+            Ok(dwt.inverse().into_iter().map(f64::from).collect())
+        })
+        .try_reduce(
+            || vec![0.0; width * height],
+            |mut acc, part| {
+                acc.iter_mut().zip(&part).for_each(|(a, p)| *a += p);
+                Ok(acc)
+            },
+        )?;
+
+    let n = offsets.len() as f64;
+    Ok(sum.into_iter().map(|v| (v / n) as f32).collect())
+}
+
+/// smallest multiple of 2^levels >= len
+fn padded_len(len: usize, levels: usize) -> usize {
+    let block = 1 << levels;
+    len.div_ceil(block) * block
+}
+
+/// symmetric extension: ... 2 1 0 | 0 1 2 ... n-1 | n-1 n-2 ...
+fn mirror(i: usize, len: usize) -> usize {
+    let i = i % (2 * len);
+    if i < len { i } else { 2 * len - 1 - i }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_plane(width: usize, height: usize) -> Vec<f32> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as f32, (i / width) as f32);
+                0.5 + 0.3 * (x * 0.3).sin() * (y * 0.2).cos() + if x > width as f32 / 2.0 { 0.1 } else { 0.0 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_filters_are_orthonormal() {
+        for family in WaveletFamily::ALL {
+            let h = family.filter();
+            assert!((h.iter().sum::<f64>() - 2f64.sqrt()).abs() < 1e-12, "{}", family.name());
+            for shift in (0..h.len()).step_by(2) {
+                let dot: f64 = h.iter().zip(&h[shift..]).map(|(a, b)| a * b).sum();
+                let expected = if shift == 0 { 1.0 } else { 0.0 };
+                assert!((dot - expected).abs() < 1e-12, "{} shift {}", family.name(), shift);
+            }
+        }
+    }
+
+    #[test]
+    fn test_perfect_reconstruction() {
+        // dimensioni non multiple di 2^levels: passa dal padding
+        let (width, height) = (70, 45);
+        let plane = test_plane(width, height);
+        for family in WaveletFamily::ALL {
+            let levels = family.max_levels(width, height).min(3);
+            let dwt = Dwt2::forward(&plane, width, height, family, levels).unwrap();
+            let back = dwt.inverse();
+            let err = plane.iter().zip(&back).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(err < 1e-5, "{}: {}", family.name(), err);
+        }
+    }
+
+    #[test]
+    fn test_energy_is_preserved() {
+        // trasformata ortogonale: stessa energia (senza padding)
+        let plane = test_plane(64, 32);
+        let dwt = Dwt2::forward(&plane, 64, 32, WaveletFamily::Db4, 2).unwrap();
+        let energy: f64 = plane.iter().map(|&v| (v as f64).powi(2)).sum();
+        let coeff_energy: f64 = dwt.coeffs.iter().map(|v| v * v).sum();
+        assert!((energy - coeff_energy).abs() / energy < 1e-9);
+    }
+
+    #[test]
+    fn test_constant_has_no_details() {
+        let plane = vec![0.25f32; 32 * 32];
+        let dwt = Dwt2::forward(&plane, 32, 32, WaveletFamily::Sym4, 2).unwrap();
+        assert_eq!(dwt.detail_bands().len(), 6);
+        for band in dwt.detail_bands() {
+            assert!(dwt.band(&band).iter().all(|v| v.abs() < 1e-12), "{:?}", band);
+        }
+    }
+
+    #[test]
+    fn test_shifted_reconstruction() {
+        let (width, height) = (50, 37);
+        let plane = test_plane(width, height);
+        for shift in [(1, 0), (3, 2), (7, 7)] {
+            let dwt = Dwt2::forward_shifted(&plane, width, height, WaveletFamily::Db2, 3, shift).unwrap();
+            let back = dwt.inverse();
+            let err = plane.iter().zip(&back).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(err < 1e-5, "{:?}: {}", shift, err);
+        }
+        assert!(Dwt2::forward_shifted(&plane, width, height, WaveletFamily::Db2, 3, (8, 0)).is_err());
+    }
+
+    #[test]
+    fn test_cycle_spin_is_translation_invariant() {
+        // senza modifiche dei coefficienti la media delle ricostruzioni è il piano stesso
+        let plane = test_plane(40, 40);
+        let back = cycle_spin(&plane, 40, 40, WaveletFamily::Db2, 2, WaveletMode::FullCycleSpinning, |_| {}).unwrap();
+        assert!(plane.iter().zip(&back).all(|(a, b)| (a - b).abs() < 1e-5));
+
+        // un impulso spostato di un pixel: con tutte le traslazioni il risultato si sposta e basta
+        let kill_finest = |dwt: &mut Dwt2| {
+            for band in dwt.detail_bands().iter().filter(|b| b.level == 1) {
+                dwt.map_band(band, |_| 0.0);
+            }
+        };
+        let (size, levels) = (32, 2);
+        let spike = |at: usize| {
+            let mut p = vec![0.0f32; size * size];
+            p[at * size + at] = 1.0;
+            p
+        };
+        let run = |p: &[f32], mode| cycle_spin(p, size, size, WaveletFamily::Haar, levels, mode, kill_finest).unwrap();
+        let invariant = |mode| {
+            let (a, b) = (run(&spike(12), mode), run(&spike(13), mode));
+            (0..size - 1)
+                .flat_map(|y| (0..size - 1).map(move |x| (x, y)))
+                .map(|(x, y)| (a[y * size + x] - b[(y + 1) * size + x + 1]).abs())
+                .fold(0.0, f32::max)
+        };
+        assert!(invariant(WaveletMode::FullCycleSpinning) < 1e-5);
+        assert!(invariant(WaveletMode::Decimated) > 1e-2);
+        assert_eq!(WaveletMode::CycleSpinning { shifts: 9 }.shifts(levels), 4);
+    }
+
+    #[test]
+    fn test_too_many_levels() {
+        let plane = vec![0.0f32; 16 * 16];
+        assert_eq!(WaveletFamily::Db4.max_levels(16, 16), 1);
+        assert!(matches!(
+            Dwt2::forward(&plane, 16, 16, WaveletFamily::Db4, 2),
+            Err(ImageProcessingError::WaveletError)
+        ));
+        assert!(matches!(
+            Dwt2::forward(&plane[1..], 16, 16, WaveletFamily::Haar, 1),
+            Err(ImageProcessingError::WaveletError)
+        ));
+        assert!(matches!(
+            Dwt2::forward_shifted(&plane, 16, 16, WaveletFamily::Haar, 2, (4, 0)),
+            Err(ImageProcessingError::WaveletError)
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+//! border handling for filters that read outside the image.
+//!
+//! Names follow OpenCV: with `abcdefgh` as the line,
+//! - `Reflect`:    `fedcba|abcdefgh|hgfedcb`
+//! - `Reflect101`: `gfedcb|abcdefgh|gfedcba`
+//! - `Replicate`:  `aaaaaa|abcdefgh|hhhhhhh`
+//! - `Wrap`:       `cdefgh|abcdefgh|abcdefg`
+//! - `Constant`:   `iiiiii|abcdefgh|iiiiiii`
+
+/// How samples outside the image are made up
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BorderMode {
+    /// mirror, the edge sample is repeated
+    #[default]
+    Reflect,
+    /// mirror around the edge sample, which is not repeated
+    Reflect101,
+    /// repeat the edge sample
+    Replicate,
+    /// periodic image
+    Wrap,
+    /// fixed value, normalized [0, 1] scale
+    Constant(f32),
+}
+
+impl BorderMode {
+    /// Index read for position `i` on a line of `len` samples, `None` for the constant border
+    pub fn index(self, i: isize, len: usize) -> Option<usize> {
+        let n = len as isize;
+        if (0..n).contains(&i) {
+            return Some(i as usize);
+        }
+        let i = match self {
+            BorderMode::Reflect => {
+                let i = i.rem_euclid(2 * n);
+                if i < n { i } else { 2 * n - 1 - i }
+            }
+            BorderMode::Reflect101 if n == 1 => 0,
+            BorderMode::Reflect101 => {
+                let i = i.rem_euclid(2 * n - 2);
+                if i < n { i } else { 2 * n - 2 - i }
+            }
+            BorderMode::Replicate => i.clamp(0, n - 1),
+            BorderMode::Wrap => i.rem_euclid(n),
+            BorderMode::Constant(_) => return None,
+        };
+        Some(i as usize)
+    }
+
+    /// Sample `i` of `line`, inside or outside it
+    pub fn sample(self, line: &[f32], i: isize) -> f32 {
+        match (self.index(i, line.len()), self) {
+            (Some(i), _) => line[i],
+            (None, BorderMode::Constant(value)) => value,
+            (None, _) => unreachable!("only the constant border has no index"),
+        }
+    }
+
+    /// Pixel (`x`, `y`) of a `width` x `height` plane, inside or outside it
+    pub fn pixel(self, plane: &[f32], width: usize, height: usize, x: isize, y: isize) -> f32 {
+        match (self.index(x, width), self.index(y, height), self) {
+            (Some(x), Some(y), _) => plane[y * width + x],
+            (_, _, BorderMode::Constant(value)) => value,
+            _ => unreachable!("only the constant border has no index"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extend(mode: BorderMode) -> Vec<f32> {
+        let line = [0.0, 1.0, 2.0, 3.0];
+        (-3..7).map(|i| mode.sample(&line, i)).collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        assert_eq!(extend(BorderMode::Reflect), [2.0, 1.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 2.0, 1.0]);
+        assert_eq!(extend(BorderMode::Reflect101), [3.0, 2.0, 1.0, 0.0, 1.0, 2.0, 3.0, 2.0, 1.0, 0.0]);
+        assert_eq!(extend(BorderMode::Replicate), [0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 3.0, 3.0, 3.0]);
+        assert_eq!(extend(BorderMode::Wrap), [1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0]);
+        assert_eq!(extend(BorderMode::Constant(9.0)), [9.0, 9.0, 9.0, 0.0, 1.0, 2.0, 3.0, 9.0, 9.0, 9.0]);
+    }
+
+    #[test]
+    fn test_far_and_single_sample() {
+        // oltre un periodo intero
+        assert_eq!(BorderMode::Reflect.index(-9, 4), Some(0));
+        assert_eq!(BorderMode::Reflect101.index(13, 4), Some(1));
+        for mode in [BorderMode::Reflect, BorderMode::Reflect101, BorderMode::Replicate, BorderMode::Wrap] {
+            assert_eq!(mode.index(-5, 1), Some(0), "{:?}", mode);
+        }
+        assert_eq!(BorderMode::Replicate.pixel(&[1.0, 2.0, 3.0, 4.0], 2, 2, 5, -1), 2.0);
+    }
+}
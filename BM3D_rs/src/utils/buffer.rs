@@ -0,0 +1,365 @@
+//! Planar floating point image buffer used internally by the denoise pipeline.
+//! Integer samples are normalized to [0, 1] so 8-bit and 16-bit inputs share the same code path,
+//! float samples are kept as they are.
+
+use crate::error::ImageProcessingError;
+use image::{DynamicImage, ImageBuffer};
+
+/// Sample depth of the source image, used to write the result back with the same precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BitDepth {
+    /// 8 bits per sample
+    U8,
+    /// 16 bits per sample
+    U16,
+    /// 32-bit float per sample
+    F32,
+}
+
+impl BitDepth {
+    /// Largest value a sample of this depth can hold (1.0 for float images).
+    pub fn max_value(self) -> f64 {
+        match self {
+            BitDepth::U8 => u8::MAX as f64,
+            BitDepth::U16 => u16::MAX as f64,
+            BitDepth::F32 => 1.0,
+        }
+    }
+}
+
+/// Channel layout of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelLayout {
+    /// single luminance channel
+    Luma,
+    /// luminance + alpha
+    LumaA,
+    /// red, green, blue
+    Rgb,
+    /// red, green, blue + alpha
+    Rgba,
+}
+
+impl ChannelLayout {
+    /// Total number of channels, alpha included.
+    pub fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Luma => 1,
+            ChannelLayout::LumaA => 2,
+            ChannelLayout::Rgb => 3,
+            ChannelLayout::Rgba => 4,
+        }
+    }
+
+    /// Number of color channels, alpha excluded.
+    pub fn color_channels(self) -> usize {
+        match self {
+            ChannelLayout::Luma | ChannelLayout::LumaA => 1,
+            ChannelLayout::Rgb | ChannelLayout::Rgba => 3,
+        }
+    }
+
+    /// true if the last channel is alpha
+    pub fn has_alpha(self) -> bool {
+        matches!(self, ChannelLayout::LumaA | ChannelLayout::Rgba)
+    }
+
+    /// Same layout without the alpha channel.
+    pub fn without_alpha(self) -> Self {
+        match self {
+            ChannelLayout::LumaA => ChannelLayout::Luma,
+            ChannelLayout::Rgba => ChannelLayout::Rgb,
+            other => other,
+        }
+    }
+}
+
+/// Scale on which the noise sigma is expressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SigmaScale {
+    /// sigma in 8-bit units (0-255) whatever the input depth, the historical behaviour
+    #[default]
+    EightBit,
+    /// sigma relative to the full range, e.g. 0.1 = 10% of white
+    Normalized,
+    /// sigma in the units of the input samples (0-255, 0-65535 or 0.0-1.0)
+    Native,
+}
+
+impl SigmaScale {
+    /// Convert `sigma` to the normalized [0, 1] scale used by the pipeline.
+    pub fn normalize(self, sigma: f64, depth: BitDepth) -> f64 {
+        match self {
+            SigmaScale::EightBit => sigma / u8::MAX as f64,
+            SigmaScale::Normalized => sigma,
+            SigmaScale::Native => sigma / depth.max_value(),
+        }
+    }
+}
+
+/// Planar f32 image: one `Vec<f32>` of `width * height` samples per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloatImage {
+    width: usize,
+    height: usize,
+    layout: ChannelLayout,
+    depth: BitDepth,
+    planes: Vec<Vec<f32>>,
+}
+
+impl FloatImage {
+    /// Create a black image.
+    pub fn new(width: usize, height: usize, layout: ChannelLayout, depth: BitDepth) -> Self {
+        Self {
+            width,
+            height,
+            layout,
+            depth,
+            planes: vec![vec![0.0; width * height]; layout.channels()],
+        }
+    }
+
+    /// Build an image from already separated planes.
+    pub fn from_planes(
+        width: usize,
+        height: usize,
+        layout: ChannelLayout,
+        depth: BitDepth,
+        planes: Vec<Vec<f32>>,
+    ) -> Result<Self, ImageProcessingError> {
+        if planes.len() != layout.channels() {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "{:?} layout needs {} planes, got {}",
+                layout,
+                layout.channels(),
+                planes.len()
+            )));
+        }
+        if let Some(plane) = planes.iter().find(|p| p.len() != width * height) {
+            return Err(ImageProcessingError::DimensionMismatch {
+                a: width * height,
+                b: plane.len(),
+            });
+        }
+        Ok(Self { width, height, layout, depth, planes })
+    }
+
+    /// Convert a `DynamicImage` keeping its channel layout and bit depth.
+    pub fn from_dynamic(img: &DynamicImage) -> Self {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        match img {
+            DynamicImage::ImageLuma8(buf) => Self::from_interleaved(width, height, ChannelLayout::Luma, BitDepth::U8, buf.as_raw()),
+            DynamicImage::ImageLumaA8(buf) => Self::from_interleaved(width, height, ChannelLayout::LumaA, BitDepth::U8, buf.as_raw()),
+            DynamicImage::ImageRgb8(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgb, BitDepth::U8, buf.as_raw()),
+            DynamicImage::ImageRgba8(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgba, BitDepth::U8, buf.as_raw()),
+            DynamicImage::ImageLuma16(buf) => Self::from_interleaved(width, height, ChannelLayout::Luma, BitDepth::U16, buf.as_raw()),
+            DynamicImage::ImageLumaA16(buf) => Self::from_interleaved(width, height, ChannelLayout::LumaA, BitDepth::U16, buf.as_raw()),
+            DynamicImage::ImageRgb16(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgb, BitDepth::U16, buf.as_raw()),
+            DynamicImage::ImageRgba16(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgba, BitDepth::U16, buf.as_raw()),
+            DynamicImage::ImageRgb32F(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgb, BitDepth::F32, buf.as_raw()),
+            DynamicImage::ImageRgba32F(buf) => Self::from_interleaved(width, height, ChannelLayout::Rgba, BitDepth::F32, buf.as_raw()),
+            other if other.color().has_alpha() => {
+                Self::from_interleaved(width, height, ChannelLayout::Rgba, BitDepth::F32, other.to_rgba32f().as_raw())
+            }
+            other => Self::from_interleaved(width, height, ChannelLayout::Rgb, BitDepth::F32, other.to_rgb32f().as_raw()),
+        }
+    }
+
+    fn from_interleaved<T: Sample>(
+        width: usize,
+        height: usize,
+        layout: ChannelLayout,
+        depth: BitDepth,
+        data: &[T],
+    ) -> Self {
+        let channels = layout.channels();
+        let planes = (0..channels)
+            .map(|c| data.iter().skip(c).step_by(channels).map(|&v| v.to_unit()).collect())
+            .collect();
+        Self { width, height, layout, depth, planes }
+    }
+
+    fn to_interleaved<T: Sample>(&self) -> Vec<T> {
+        let mut data = Vec::with_capacity(self.width * self.height * self.planes.len());
+        for i in 0..self.width * self.height {
+            data.extend(self.planes.iter().map(|plane| T::from_unit(plane[i])));
+        }
+        data
+    }
+
+    /// Convert back to a `DynamicImage` with the layout and bit depth of this buffer.
+    /// Float images without color are expanded to RGB because `image` has no float luma variant.
+    pub fn to_dynamic(&self) -> DynamicImage {
+        let (w, h) = (self.width as u32, self.height as u32);
+        // the buffer sizes always match, `from_raw` can't fail here
+        match (self.depth, self.layout) {
+            (BitDepth::U8, ChannelLayout::Luma) => DynamicImage::ImageLuma8(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U8, ChannelLayout::LumaA) => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U8, ChannelLayout::Rgb) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U8, ChannelLayout::Rgba) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U16, ChannelLayout::Luma) => DynamicImage::ImageLuma16(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U16, ChannelLayout::LumaA) => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U16, ChannelLayout::Rgb) => DynamicImage::ImageRgb16(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::U16, ChannelLayout::Rgba) => DynamicImage::ImageRgba16(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::F32, ChannelLayout::Rgb) => DynamicImage::ImageRgb32F(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::F32, ChannelLayout::Rgba) => DynamicImage::ImageRgba32F(ImageBuffer::from_raw(w, h, self.to_interleaved()).unwrap()),
+            (BitDepth::F32, ChannelLayout::Luma | ChannelLayout::LumaA) => {
+                let mut planes = vec![self.planes[0].clone(); 3];
+                if self.layout.has_alpha() {
+                    planes.push(self.planes[1].clone());
+                }
+                let layout = if self.layout.has_alpha() { ChannelLayout::Rgba } else { ChannelLayout::Rgb };
+                Self { planes, layout, ..self.clone() }.to_dynamic()
+            }
+        }
+    }
+
+    /// image width
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// image height
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// (width, height)
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// InvalidParameter on an image without pixels, which the filters cannot split in rows
+    pub fn ensure_not_empty(&self) -> Result<(), ImageProcessingError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(ImageProcessingError::InvalidParameter(format!(
+                "empty image ({}x{})",
+                self.width, self.height
+            )));
+        }
+        Ok(())
+    }
+
+    /// channel layout
+    pub fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    /// bit depth of the source samples
+    pub fn depth(&self) -> BitDepth {
+        self.depth
+    }
+
+    /// Override the bit depth used when converting back to `DynamicImage`.
+    pub fn set_depth(&mut self, depth: BitDepth) {
+        self.depth = depth;
+    }
+
+    /// all planes, alpha last
+    pub fn planes(&self) -> &[Vec<f32>] {
+        &self.planes
+    }
+
+    /// mutable access to all planes
+    pub fn planes_mut(&mut self) -> &mut [Vec<f32>] {
+        &mut self.planes
+    }
+
+    /// single plane
+    pub fn plane(&self, channel: usize) -> &[f32] {
+        &self.planes[channel]
+    }
+
+    /// single mutable plane
+    pub fn plane_mut(&mut self, channel: usize) -> &mut [f32] {
+        &mut self.planes[channel]
+    }
+
+    /// sample of `channel` at (x, y)
+    pub fn get(&self, channel: usize, x: usize, y: usize) -> f32 {
+        self.planes[channel][y * self.width + x]
+    }
+
+    /// Drop the alpha plane, if any.
+    pub fn drop_alpha(mut self) -> Self {
+        if self.layout.has_alpha() {
+            self.planes.pop();
+            self.layout = self.layout.without_alpha();
+        }
+        self
+    }
+}
+
+/// Integer and float sample types that can be mapped to and from the normalized scale.
+trait Sample: Copy {
+    fn to_unit(self) -> f32;
+    fn from_unit(v: f32) -> Self;
+}
+
+impl Sample for u8 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u8::MAX as f32
+    }
+    fn from_unit(v: f32) -> Self {
+        (v * u8::MAX as f32).round().clamp(0.0, u8::MAX as f32) as u8
+    }
+}
+
+impl Sample for u16 {
+    fn to_unit(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+    fn from_unit(v: f32) -> Self {
+        (v * u16::MAX as f32).round().clamp(0.0, u16::MAX as f32) as u16
+    }
+}
+
+impl Sample for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+    fn from_unit(v: f32) -> Self {
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, Rgb};
+
+    #[test]
+    fn test_roundtrip_rgb16_keeps_precision() {
+        let img = ImageBuffer::from_fn(4, 3, |x, y| Rgb([x as u16 * 1000 + 1, y as u16 * 7 + 3, 65535]));
+        let dynamic = DynamicImage::ImageRgb16(img);
+
+        let float = FloatImage::from_dynamic(&dynamic);
+        assert_eq!(float.layout(), ChannelLayout::Rgb);
+        assert_eq!(float.depth(), BitDepth::U16);
+
+        assert_eq!(float.to_dynamic(), dynamic);
+    }
+
+    #[test]
+    fn test_float_luma_becomes_rgb32f() {
+        let float = FloatImage::from_planes(2, 1, ChannelLayout::Luma, BitDepth::F32, vec![vec![0.25, 1.5]]).unwrap();
+        let rgb = float.to_dynamic().into_rgb32f();
+        assert_eq!(rgb.get_pixel(1, 0).0, [1.5, 1.5, 1.5]);
+    }
+
+    #[test]
+    fn test_from_planes_checks_sizes() {
+        let res = FloatImage::from_planes(2, 2, ChannelLayout::Luma, BitDepth::U8, vec![vec![0.0; 3]]);
+        assert!(matches!(res, Err(ImageProcessingError::DimensionMismatch { a: 4, b: 3 })));
+
+        let gray = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(2, 2, Luma([255u8])));
+        assert_eq!(FloatImage::from_dynamic(&gray).plane(0), &[1.0; 4]);
+    }
+
+    #[test]
+    fn test_sigma_scale() {
+        assert!((SigmaScale::EightBit.normalize(25.5, BitDepth::U16) - 0.1).abs() < 1e-12);
+        assert!((SigmaScale::Native.normalize(6553.5, BitDepth::U16) - 0.1).abs() < 1e-12);
+        assert!((SigmaScale::Normalized.normalize(0.1, BitDepth::U8) - 0.1).abs() < 1e-12);
+    }
+}
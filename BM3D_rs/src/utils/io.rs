@@ -0,0 +1,135 @@
+//! loading and saving images
+
+use std::fmt;
+use std::path::Path;
+use image::{ColorType, DynamicImage};
+use crate::error::ImageProcessingError;
+
+/// Load an image from disk, the format is guessed from the extension.
+pub fn load_dynamic_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage, ImageProcessingError> {
+    image::open(path).map_err(ImageProcessingError::decode)
+}
+
+/// Shrink the image so that its largest side is at most `max_size` (0 = no limit), keeping the aspect ratio
+pub fn fit_max_dimension(img: DynamicImage, max_size: u32) -> DynamicImage {
+    if max_size == 0 || (img.width() <= max_size && img.height() <= max_size) {
+        return img;
+    }
+    let scale = max_size as f32 / img.width().max(img.height()) as f32;
+    let new_width = (img.width() as f32 * scale) as u32;
+    let new_height = (img.height() as f32 * scale) as u32;
+    img.resize(new_width, new_height, image::imageops::FilterType::Triangle)
+}
+
+/// What [`save_dynamic_image`] wrote: narrower than the image when the format refused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveOutcome {
+    /// color type of the image to save
+    pub requested: ColorType,
+    /// color type written to the file
+    pub saved: ColorType,
+}
+
+impl SaveOutcome {
+    /// `image` converts some formats on its own (JPEG is written as Rgb8): the color type is read
+    /// back from the header of the file, the written buffer is only a fallback
+    fn new(requested: &DynamicImage, written: &DynamicImage, path: &Path) -> Self {
+        let stored = || -> Option<ColorType> {
+            let reader = image::ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+            Some(image::ImageDecoder::color_type(&reader.into_decoder().ok()?))
+        };
+        SaveOutcome { requested: requested.color(), saved: stored().unwrap_or(written.color()) }
+    }
+
+    /// the file holds the image as it was
+    pub fn is_exact(&self) -> bool {
+        self.requested == self.saved
+    }
+
+    /// samples were reduced to 8 bits
+    pub fn lost_depth(&self) -> bool {
+        let depth = |c: ColorType| c.bytes_per_pixel() / c.channel_count();
+        depth(self.saved) < depth(self.requested)
+    }
+
+    /// the alpha channel was dropped
+    pub fn dropped_alpha(&self) -> bool {
+        self.requested.has_alpha() && !self.saved.has_alpha()
+    }
+}
+
+impl fmt::Display for SaveOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lost = Vec::new();
+        if self.lost_depth() {
+            lost.push("saved with 8 bits per sample");
+        }
+        if self.dropped_alpha() {
+            lost.push("alpha channel dropped");
+        }
+        if lost.is_empty() {
+            return write!(f, "saved as {:?}", self.saved);
+        }
+        write!(f, "the format cannot store {:?}: {}", self.requested, lost.join(", "))
+    }
+}
+
+/// Save an image keeping its bit depth and channel layout when the format allows it (PNG, TIFF),
+/// otherwise fall back to 8 bits per sample and, if still refused, drop the alpha channel (e.g. JPEG).
+/// The returned [`SaveOutcome`] tells the caller what was written; nothing is printed.
+pub fn save_dynamic_image<P: AsRef<Path>>(img: &DynamicImage, path: P) -> Result<SaveOutcome, ImageProcessingError> {
+    let path = path.as_ref();
+    let err = match img.save(path) {
+        Err(image::ImageError::Unsupported(e)) => e,
+        other => return other.map(|_| SaveOutcome::new(img, img, path)).map_err(ImageProcessingError::encode),
+    };
+
+    let eight_bit = match img.color() {
+        image::ColorType::L16 => DynamicImage::ImageLuma8(img.to_luma8()),
+        image::ColorType::La16 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        c if c.has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+        c if c.has_color() => DynamicImage::ImageRgb8(img.to_rgb8()),
+        _ => DynamicImage::ImageLuma8(img.to_luma8()),
+    };
+    let err = if eight_bit.color() != img.color() {
+        match eight_bit.save(path) {
+            Err(image::ImageError::Unsupported(e)) => e,
+            other => return other.map(|_| SaveOutcome::new(img, &eight_bit, path)).map_err(ImageProcessingError::encode),
+        }
+    } else {
+        err
+    };
+    if !eight_bit.color().has_alpha() {
+        return Err(ImageProcessingError::encode(image::ImageError::Unsupported(err)));
+    }
+
+    let opaque = if eight_bit.color().has_color() {
+        DynamicImage::ImageRgb8(eight_bit.to_rgb8())
+    } else {
+        DynamicImage::ImageLuma8(eight_bit.to_luma8())
+    };
+    opaque.save(path).map_err(ImageProcessingError::encode)?;
+    Ok(SaveOutcome::new(img, &opaque, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_reports_downgrades() {
+        let dir = tempfile::tempdir().unwrap();
+        let img = DynamicImage::new_rgba16(8, 8);
+
+        let png = save_dynamic_image(&img, dir.path().join("exact.png")).unwrap();
+        assert!(png.is_exact());
+        assert_eq!(png.saved, ColorType::Rgba16);
+
+        // il JPEG non ha né 16 bit né alpha
+        let jpeg = save_dynamic_image(&img, dir.path().join("lossy.jpg")).unwrap();
+        assert_eq!(jpeg.saved, ColorType::Rgb8);
+        assert!(jpeg.lost_depth() && jpeg.dropped_alpha());
+        assert_eq!(image::open(dir.path().join("lossy.jpg")).unwrap().color(), ColorType::Rgb8);
+        assert_eq!(jpeg.to_string(), "the format cannot store Rgba16: saved with 8 bits per sample, alpha channel dropped");
+    }
+}
@@ -1,68 +1,583 @@
-use std::path::Path;
-use image::DynamicImage;
+//! image quality metrics: MSE, PSNR, SSIM and MS-SSIM.
+//!
+//! All metrics work on normalized samples (see [`FloatImage`]), so the peak value is 1 for every
+//! bit depth and the PSNR of two 8-bit images is the one computed on raw samples with a 255 peak.
+//! Only color channels are compared, alpha is ignored.
+//!
+//! - SSIM follows Wang et al. 2004: 11x11 gaussian window (sigma 1.5), K1 = 0.01, K2 = 0.03,
+//!   computed on the windows that fit entirely inside the image.
+//! - MS-SSIM follows Wang, Simoncelli and Bovik 2003: 5 scales with the published weights,
+//!   2x2 average downsampling between scales.
+//!
+//! An optional mask (one `bool` per pixel, row major) restricts every metric to a region;
+//! for SSIM a window counts when its centre is inside the mask.
+
 use crate::error::ImageProcessingError;
-use zune_image::{image::Image, codecs::bmp::zune_core::colorspace::ColorSpace,};
-
-pub fn load_dynamic_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage, ImageProcessingError> {
-    let img = image::open(path).map_err(|e| {
-        ImageProcessingError::Other(Box::leak(format!("Error while loading image: {}", e).into_boxed_str()))
-    })?;
-    Ok(img)
-}
-
-pub fn dynamic_to_ycbcr(dynamic_img: &DynamicImage) -> Result<Image, ImageProcessingError> {
-    let rgb_img = dynamic_img.to_rgb8();
-    let (width, height) = rgb_img.dimensions();
-    
-    let zune_img = Image::from_u8(
-        rgb_img.as_raw(),
-        width as usize,
-        height as usize,
-        ColorSpace::RGB
-    );
-    if zune_img.frames_len() == 0{
-        return Err(ImageProcessingError::ColorConversionError);
-    };
-
-    // Nota: zune-image potrebbe non supportare direttamente la conversione a YCbCr
-    // In tal caso, lavoriamo direttamente in RGB
-    Ok(zune_img)
-}
-
-pub fn ycbcr_to_dynamic(ycbcr_img: Image) -> Result<DynamicImage, ImageProcessingError> {
-    let img_clone = ycbcr_img.clone();
-    let (width, height) = img_clone.dimensions();
-    let data = img_clone.flatten_to_u8();
-    
-    if data.is_empty() {
-        return Err(ImageProcessingError::ColorConversionError);
-    }
-    
-    // Prendi il primo canale
-    let channel_data = data[0].clone();
-    
-    // Controlla se abbiamo abbastanza dati
-    let expected_size = (width * height * 3) as usize;
-    let actual_size = channel_data.len();
-    
-    let rgb_data = if actual_size >= expected_size {
-        // I dati sono già in formato RGB
-        channel_data
-    } else if actual_size >= width * height {
-        // I dati sono in scala di grigi, convertiamo a RGB
-        let mut rgb = Vec::with_capacity(width * height * 3);
-        for &gray in &channel_data {
-            rgb.push(gray); // R
-            rgb.push(gray); // G  
-            rgb.push(gray); // B
+use crate::utils::buffer::FloatImage;
+use image::{DynamicImage, Rgb, RgbImage};
+use serde::Serialize;
+
+/// side of the SSIM gaussian window
+pub const SSIM_WINDOW: usize = 11;
+/// standard deviation of the SSIM gaussian window
+pub const SSIM_SIGMA: f64 = 1.5;
+/// MS-SSIM weights, finest scale first
+pub const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+const K1: f64 = 0.01;
+const K2: f64 = 0.03;
+const C1: f64 = K1 * K1;
+const C2: f64 = K2 * K2;
+
+/// Quality of a single channel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ChannelQuality {
+    /// mean squared error
+    pub mse: f64,
+    /// peak signal to noise ratio in dB, infinite for identical channels
+    pub psnr: f64,
+    /// structural similarity
+    pub ssim: f64,
+    /// multi-scale structural similarity, `None` if the image is too small for 5 scales
+    pub ms_ssim: Option<f64>,
+}
+
+/// Quality of a test image against a reference
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QualityReport {
+    /// mean squared error over all color samples
+    pub mse: f64,
+    /// PSNR of the global MSE
+    pub psnr: f64,
+    /// mean of the per channel SSIM
+    pub ssim: f64,
+    /// mean of the per channel MS-SSIM, `None` if the image is too small for 5 scales
+    pub ms_ssim: Option<f64>,
+    /// the same metrics channel by channel
+    pub channels: Vec<ChannelQuality>,
+}
+
+impl QualityReport {
+    /// Compute every metric, `mask` restricts them to a region.
+    pub fn compute(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<Self, ImageProcessingError> {
+        let mse_c = mse_per_channel(reference, test, mask)?;
+        let ssim_c = ssim_per_channel(reference, test, mask)?;
+        let ms_ssim_c = if fits_ms_ssim(reference.width(), reference.height()) {
+            Some(ms_ssim_per_channel(reference, test, mask)?)
+        } else {
+            None
+        };
+
+        let mse = mean(&mse_c);
+        let channels = (0..mse_c.len())
+            .map(|c| ChannelQuality {
+                mse: mse_c[c],
+                psnr: psnr_from_mse(mse_c[c]),
+                ssim: ssim_c[c],
+                ms_ssim: ms_ssim_c.as_ref().map(|v| v[c]),
+            })
+            .collect();
+
+        Ok(QualityReport {
+            mse,
+            psnr: psnr_from_mse(mse),
+            ssim: mean(&ssim_c),
+            ms_ssim: ms_ssim_c.as_deref().map(mean),
+            channels,
+        })
+    }
+}
+
+/// Compare two decoded images, they can have different bit depths but must have the same size
+/// and number of color channels.
+pub fn compare_images(
+    reference: &DynamicImage,
+    test: &DynamicImage,
+    mask: Option<&[bool]>,
+) -> Result<QualityReport, ImageProcessingError> {
+    QualityReport::compute(&FloatImage::from_dynamic(reference), &FloatImage::from_dynamic(test), mask)
+}
+
+/// Build a region mask from an image: pixels with luma >= 0.5 are selected.
+pub fn mask_from_image(img: &DynamicImage) -> Vec<bool> {
+    img.to_luma32f().pixels().map(|p| p.0[0] >= 0.5).collect()
+}
+
+/// Absolute difference of every pixel, averaged over the color channels
+pub fn difference_map(reference: &FloatImage, test: &FloatImage) -> Result<Vec<f32>, ImageProcessingError> {
+    check_compatible(reference, test, None)?;
+    let channels = reference.layout().color_channels();
+    let mut map = vec![0.0f32; reference.width() * reference.height()];
+    for c in 0..channels {
+        for ((m, &a), &b) in map.iter_mut().zip(reference.plane(c)).zip(test.plane(c)) {
+            *m += (a - b).abs() / channels as f32;
         }
-        rgb
+    }
+    Ok(map)
+}
+
+/// Render a difference map as a black-red-yellow-white heatmap, `scale` is the difference
+/// shown as white (the largest difference if `None`).
+pub fn heatmap(map: &[f32], width: usize, height: usize, scale: Option<f32>) -> RgbImage {
+    let scale = scale.unwrap_or_else(|| map.iter().cloned().fold(0.0, f32::max));
+    RgbImage::from_fn(width as u32, height as u32, |x, y| {
+        let v = map[y as usize * width + x as usize];
+        let t = if scale > 0.0 { (v / scale).clamp(0.0, 1.0) * 3.0 } else { 0.0 };
+        // tre rampe: nero -> rosso -> giallo -> bianco
+        let channel = |offset: f32| ((t - offset).clamp(0.0, 1.0) * 255.0).round() as u8;
+        Rgb([channel(0.0), channel(1.0), channel(2.0)])
+    })
+}
+
+/// Heatmap of the difference between two images, `scale` is in the sample units of the
+/// reference (0-255, 0-65535 or 0.0-1.0), see [`heatmap`].
+pub fn difference_heatmap(
+    reference: &FloatImage,
+    test: &FloatImage,
+    scale: Option<f64>,
+) -> Result<RgbImage, ImageProcessingError> {
+    let map = difference_map(reference, test)?;
+    let scale = scale.map(|s| (s / reference.depth().max_value()) as f32);
+    Ok(heatmap(&map, reference.width(), reference.height(), scale))
+}
+
+/// PSNR in dB for a peak value of 1
+pub fn psnr_from_mse(mse: f64) -> f64 {
+    if mse == 0.0 {
+        f64::INFINITY
     } else {
-        return Err(ImageProcessingError::ColorConversionError);
-    };
-    
-    Ok(DynamicImage::ImageRgb8(
-        image::RgbImage::from_raw(width as u32, height as u32, rgb_data)
-            .ok_or(ImageProcessingError::ColorConversionError)?
-    ))
-}
\ No newline at end of file
+        -10.0 * mse.log10()
+    }
+}
+
+/// Mean squared error of every color channel
+pub fn mse_per_channel(
+    reference: &FloatImage,
+    test: &FloatImage,
+    mask: Option<&[bool]>,
+) -> Result<Vec<f64>, ImageProcessingError> {
+    check_compatible(reference, test, mask)?;
+    let selected = mask.map_or(reference.width() * reference.height(), |m| m.iter().filter(|&&v| v).count());
+    if selected == 0 {
+        return Err(empty_mask());
+    }
+
+    Ok((0..reference.layout().color_channels())
+        .map(|c| {
+            let sum: f64 = reference
+                .plane(c)
+                .iter()
+                .zip(test.plane(c))
+                .enumerate()
+                .filter(|(i, _)| mask.is_none_or(|m| m[*i]))
+                .map(|(_, (&a, &b))| (a as f64 - b as f64).powi(2))
+                .sum();
+            sum / selected as f64
+        })
+        .collect())
+}
+
+/// Mean squared error over all color samples
+pub fn mse(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<f64, ImageProcessingError> {
+    Ok(mean(&mse_per_channel(reference, test, mask)?))
+}
+
+/// PSNR of every color channel
+pub fn psnr_per_channel(
+    reference: &FloatImage,
+    test: &FloatImage,
+    mask: Option<&[bool]>,
+) -> Result<Vec<f64>, ImageProcessingError> {
+    Ok(mse_per_channel(reference, test, mask)?.into_iter().map(psnr_from_mse).collect())
+}
+
+/// PSNR over all color samples
+pub fn psnr(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<f64, ImageProcessingError> {
+    Ok(psnr_from_mse(mse(reference, test, mask)?))
+}
+
+/// SSIM of every color channel
+pub fn ssim_per_channel(
+    reference: &FloatImage,
+    test: &FloatImage,
+    mask: Option<&[bool]>,
+) -> Result<Vec<f64>, ImageProcessingError> {
+    check_compatible(reference, test, mask)?;
+    let (width, height) = reference.dimensions();
+    if width < SSIM_WINDOW || height < SSIM_WINDOW {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "SSIM needs at least {}x{} pixels, image is {}x{}",
+            SSIM_WINDOW, SSIM_WINDOW, width, height
+        )));
+    }
+
+    (0..reference.layout().color_channels())
+        .map(|c| {
+            let x = to_f64(reference.plane(c));
+            let y = to_f64(test.plane(c));
+            let maps = SsimMaps::new(&x, &y, width, height);
+            maps.mean(&maps.ssim, mask, width)
+        })
+        .collect()
+}
+
+/// Mean SSIM over the color channels
+pub fn ssim(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<f64, ImageProcessingError> {
+    Ok(mean(&ssim_per_channel(reference, test, mask)?))
+}
+
+/// MS-SSIM of every color channel, the image must be at least 176x176
+pub fn ms_ssim_per_channel(
+    reference: &FloatImage,
+    test: &FloatImage,
+    mask: Option<&[bool]>,
+) -> Result<Vec<f64>, ImageProcessingError> {
+    check_compatible(reference, test, mask)?;
+    let (width, height) = reference.dimensions();
+    if !fits_ms_ssim(width, height) {
+        let min = SSIM_WINDOW << (MS_SSIM_WEIGHTS.len() - 1);
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "MS-SSIM needs at least {}x{} pixels, image is {}x{}",
+            min, min, width, height
+        )));
+    }
+
+    (0..reference.layout().color_channels())
+        .map(|c| {
+            let mut x = to_f64(reference.plane(c));
+            let mut y = to_f64(test.plane(c));
+            let mut mask = mask.map(<[bool]>::to_vec);
+            let (mut w, mut h) = (width, height);
+            let mut value = 1.0;
+
+            for (scale, &weight) in MS_SSIM_WEIGHTS.iter().enumerate() {
+                let maps = SsimMaps::new(&x, &y, w, h);
+                // solo il contrasto/struttura alle scale fini, la luminanza solo all'ultima
+                let map = if scale + 1 == MS_SSIM_WEIGHTS.len() { &maps.ssim } else { &maps.cs };
+                // valori negativi darebbero NaN con esponenti frazionari
+                value *= maps.mean(map, mask.as_deref(), w)?.max(0.0).powf(weight);
+
+                x = downsample(&x, w, h);
+                y = downsample(&y, w, h);
+                mask = mask.map(|m| downsample_mask(&m, w, h));
+                (w, h) = (w / 2, h / 2);
+            }
+            Ok(value)
+        })
+        .collect()
+}
+
+/// Mean MS-SSIM over the color channels
+pub fn ms_ssim(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<f64, ImageProcessingError> {
+    Ok(mean(&ms_ssim_per_channel(reference, test, mask)?))
+}
+
+/// true if the image is large enough for the 5 MS-SSIM scales
+fn fits_ms_ssim(width: usize, height: usize) -> bool {
+    let min = SSIM_WINDOW << (MS_SSIM_WEIGHTS.len() - 1);
+    width >= min && height >= min
+}
+
+fn check_compatible(reference: &FloatImage, test: &FloatImage, mask: Option<&[bool]>) -> Result<(), ImageProcessingError> {
+    if reference.dimensions() != test.dimensions() {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "image sizes differ: {}x{} and {}x{}",
+            reference.width(),
+            reference.height(),
+            test.width(),
+            test.height()
+        )));
+    }
+    if reference.layout().color_channels() != test.layout().color_channels() {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "color channels differ: {} and {}",
+            reference.layout().color_channels(),
+            test.layout().color_channels()
+        )));
+    }
+    if let Some(mask) = mask {
+        let pixels = reference.width() * reference.height();
+        if mask.len() != pixels {
+            return Err(ImageProcessingError::DimensionMismatch { a: pixels, b: mask.len() });
+        }
+    }
+    Ok(())
+}
+
+fn empty_mask() -> ImageProcessingError {
+    ImageProcessingError::InvalidParameter("the mask does not select any pixel".to_string())
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn to_f64(plane: &[f32]) -> Vec<f64> {
+    plane.iter().map(|&v| v as f64).collect()
+}
+
+/// normalized 1D gaussian, the 2D window is separable
+fn gaussian_window() -> [f64; SSIM_WINDOW] {
+    let half = (SSIM_WINDOW / 2) as f64;
+    let mut window = [0.0; SSIM_WINDOW];
+    for (i, w) in window.iter_mut().enumerate() {
+        let d = i as f64 - half;
+        *w = (-d * d / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp();
+    }
+    let sum: f64 = window.iter().sum();
+    window.iter_mut().for_each(|w| *w /= sum);
+    window
+}
+
+/// Separable gaussian filter keeping only the windows fully inside the image
+fn filter_valid(plane: &[f64], width: usize, height: usize, window: &[f64; SSIM_WINDOW]) -> Vec<f64> {
+    let out_w = width - SSIM_WINDOW + 1;
+    let out_h = height - SSIM_WINDOW + 1;
+
+    let mut rows = vec![0.0; out_w * height];
+    for y in 0..height {
+        let line = &plane[y * width..(y + 1) * width];
+        for x in 0..out_w {
+            rows[y * out_w + x] = window.iter().zip(&line[x..]).map(|(w, v)| w * v).sum();
+        }
+    }
+
+    let mut out = vec![0.0; out_w * out_h];
+    for y in 0..out_h {
+        for x in 0..out_w {
+            out[y * out_w + x] = window.iter().enumerate().map(|(k, w)| w * rows[(y + k) * out_w + x]).sum();
+        }
+    }
+    out
+}
+
+/// SSIM and contrast-structure maps of one channel
+struct SsimMaps {
+    ssim: Vec<f64>,
+    cs: Vec<f64>,
+    width: usize,
+    height: usize,
+}
+
+impl SsimMaps {
+    fn new(x: &[f64], y: &[f64], width: usize, height: usize) -> Self {
+        let window = gaussian_window();
+        let product = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).collect::<Vec<_>>();
+
+        let mu_x = filter_valid(x, width, height, &window);
+        let mu_y = filter_valid(y, width, height, &window);
+        let xx = filter_valid(&product(x, x), width, height, &window);
+        let yy = filter_valid(&product(y, y), width, height, &window);
+        let xy = filter_valid(&product(x, y), width, height, &window);
+
+        let mut ssim = Vec::with_capacity(mu_x.len());
+        let mut cs = Vec::with_capacity(mu_x.len());
+        for i in 0..mu_x.len() {
+            let (mx, my) = (mu_x[i], mu_y[i]);
+            let var_x = xx[i] - mx * mx;
+            let var_y = yy[i] - my * my;
+            let cov = xy[i] - mx * my;
+
+            let contrast_structure = (2.0 * cov + C2) / (var_x + var_y + C2);
+            let luminance = (2.0 * mx * my + C1) / (mx * mx + my * my + C1);
+            ssim.push(luminance * contrast_structure);
+            cs.push(contrast_structure);
+        }
+
+        SsimMaps { ssim, cs, width: width - SSIM_WINDOW + 1, height: height - SSIM_WINDOW + 1 }
+    }
+
+    /// Mean of `map` over the windows whose centre is inside the mask
+    fn mean(&self, map: &[f64], mask: Option<&[bool]>, image_width: usize) -> Result<f64, ImageProcessingError> {
+        let half = SSIM_WINDOW / 2;
+        let mut sum = 0.0;
+        let mut count = 0usize;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if mask.is_none_or(|m| m[(y + half) * image_width + x + half]) {
+                    sum += map[y * self.width + x];
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            return Err(empty_mask());
+        }
+        Ok(sum / count as f64)
+    }
+}
+
+/// 2x2 average, odd rows and columns are dropped
+fn downsample(plane: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let (out_w, out_h) = (width / 2, height / 2);
+    let mut out = Vec::with_capacity(out_w * out_h);
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let i = 2 * y * width + 2 * x;
+            out.push((plane[i] + plane[i + 1] + plane[i + width] + plane[i + width + 1]) / 4.0);
+        }
+    }
+    out
+}
+
+/// a downsampled pixel is selected only if the four source pixels are
+fn downsample_mask(mask: &[bool], width: usize, height: usize) -> Vec<bool> {
+    let (out_w, out_h) = (width / 2, height / 2);
+    let mut out = Vec::with_capacity(out_w * out_h);
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let i = 2 * y * width + 2 * x;
+            out.push(mask[i] && mask[i + 1] && mask[i + width] && mask[i + width + 1]);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::buffer::{BitDepth, ChannelLayout};
+
+    fn gray(width: usize, height: usize, f: impl Fn(usize, usize) -> f32) -> FloatImage {
+        let plane = (0..width * height).map(|i| f(i % width, i / width)).collect();
+        FloatImage::from_planes(width, height, ChannelLayout::Luma, BitDepth::U8, vec![plane]).unwrap()
+    }
+
+    /// deterministic texture in [0.2, 0.8]
+    fn texture(x: usize, y: usize) -> f32 {
+        let h = (x as u32).wrapping_mul(73_856_093) ^ (y as u32).wrapping_mul(19_349_663);
+        0.2 + 0.6 * ((h % 1000) as f32 / 1000.0)
+    }
+
+    #[test]
+    fn test_mse_psnr_closed_form() {
+        let a = gray(16, 16, |_, _| 0.5);
+        let b = gray(16, 16, |_, _| 0.625);
+
+        // 0.125² = 1/64, PSNR = 10 log10(64)
+        assert_eq!(mse(&a, &b, None).unwrap(), 1.0 / 64.0);
+        assert!((psnr(&a, &b, None).unwrap() - 18.0618).abs() < 1e-4);
+        assert_eq!(psnr(&a, &a, None).unwrap(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_psnr_one_level_8bit() {
+        // one level of difference on every sample: 20 log10(255) = 48.1308 dB
+        let a = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(32, 32, image::Rgb([100, 150, 200])));
+        let b = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(32, 32, image::Rgb([101, 149, 201])));
+
+        let report = compare_images(&a, &b, None).unwrap();
+        assert!((report.psnr - 48.1308).abs() < 1e-3);
+        assert_eq!(report.channels.len(), 3);
+        assert!(report.ms_ssim.is_none());
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let a = gray(200, 180, texture);
+        assert!((ssim(&a, &a, None).unwrap() - 1.0).abs() < 1e-9);
+        assert!((ms_ssim(&a, &a, None).unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_constant_images() {
+        // no variance: only the luminance term is left, (2ab + C1) / (a² + b² + C1)
+        let (a, b) = (0.3f64, 0.7f64);
+        let expected = (2.0 * a * b + C1) / (a * a + b * b + C1);
+        let x = gray(32, 32, |_, _| a as f32);
+        let y = gray(32, 32, |_, _| b as f32);
+        assert!((ssim(&x, &y, None).unwrap() - expected).abs() < 1e-6);
+
+        // MS-SSIM of constant images keeps only the luminance of the coarsest scale
+        let x = gray(176, 176, |_, _| a as f32);
+        let y = gray(176, 176, |_, _| b as f32);
+        let expected_ms = expected.powf(MS_SSIM_WEIGHTS[4]);
+        assert!((ms_ssim(&x, &y, None).unwrap() - expected_ms).abs() < 1e-6);
+    }
+
+    /// coppia fissa a 8 bit: scacchiera a rampe e la stessa con rumore uniforme in [-20, 20]
+    fn reference_pair() -> (DynamicImage, DynamicImage) {
+        let reference = |x: u32, y: u32| (x * 7 + y * 3) % 160 + 48 + ((x / 16 + y / 16) % 2) * 40;
+        let test = |x: u32, y: u32| {
+            let h = x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663);
+            (reference(x, y) as i32 + (h % 41) as i32 - 20).clamp(0, 255)
+        };
+        (
+            DynamicImage::ImageLuma8(image::GrayImage::from_fn(192, 192, |x, y| image::Luma([reference(x, y) as u8]))),
+            DynamicImage::ImageLuma8(image::GrayImage::from_fn(192, 192, |x, y| image::Luma([test(x, y) as u8]))),
+        )
+    }
+
+    #[test]
+    fn test_ssim_ms_ssim_reference_values() {
+        // valori di ssim_index.m (Wang et al. 2004) e msssim.m (Wang, Simoncelli, Bovik 2003),
+        // L = 255 sui campioni grezzi, filter2 'valid' 2D diretto e filtro 2x2 prima del
+        // sottocampionamento, calcolati fuori da questo crate sulla stessa coppia
+        let (reference, test) = reference_pair();
+        let report = compare_images(&reference, &test, None).unwrap();
+        assert!((report.ssim - 0.841_244_316_6).abs() < 1e-6, "SSIM {}", report.ssim);
+        assert!((report.ms_ssim.unwrap() - 0.986_719_462_1).abs() < 1e-6, "MS-SSIM {:?}", report.ms_ssim);
+    }
+
+    #[test]
+    fn test_ssim_decreases_with_distortion() {
+        let a = gray(64, 64, texture);
+        let small = gray(64, 64, |x, y| texture(x, y) + 0.02 * (texture(y, x) - 0.5));
+        let large = gray(64, 64, |x, y| texture(x, y) + 0.2 * (texture(y, x) - 0.5));
+
+        let s_small = ssim(&a, &small, None).unwrap();
+        let s_large = ssim(&a, &large, None).unwrap();
+        assert!(s_small < 1.0 && s_large < s_small);
+    }
+
+    #[test]
+    fn test_mask_restricts_region() {
+        // differenze solo nella metà sinistra
+        let a = gray(64, 32, texture);
+        let b = gray(64, 32, |x, y| if x < 32 { 1.0 - texture(x, y) } else { texture(x, y) });
+        let mask: Vec<bool> = (0..64 * 32).map(|i| i % 64 >= 40).collect();
+
+        assert_eq!(mse(&a, &b, Some(&mask)).unwrap(), 0.0);
+        assert!((ssim(&a, &b, Some(&mask)).unwrap() - 1.0).abs() < 1e-9);
+        assert!(ssim(&a, &b, None).unwrap() < 0.9);
+    }
+
+    #[test]
+    fn test_difference_heatmap() {
+        let a = gray(4, 1, |_, _| 0.5);
+        let b = gray(4, 1, |x, _| 0.5 + x as f32 * 0.125);
+        let map = difference_map(&a, &b).unwrap();
+        assert_eq!(map, vec![0.0, 0.125, 0.25, 0.375]);
+
+        let img = heatmap(&map, 4, 1, None);
+        assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(img.get_pixel(1, 0).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(3, 0).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_heatmap_scale_follows_bit_depth() {
+        // la stessa differenza relativa, scala espressa nelle unita' di ciascuna profondita'
+        let wide = |v: u16| image::DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(2, 2, image::Luma([v])));
+        let narrow = |v: u8| image::DynamicImage::ImageLuma8(image::GrayImage::from_pixel(2, 2, image::Luma([v])));
+        let (a16, b16) = (FloatImage::from_dynamic(&wide(0)), FloatImage::from_dynamic(&wide(257 * 40)));
+        let (a8, b8) = (FloatImage::from_dynamic(&narrow(0)), FloatImage::from_dynamic(&narrow(40)));
+
+        let red = [255, 0, 0];
+        assert_eq!(difference_heatmap(&a8, &b8, Some(120.0)).unwrap().get_pixel(0, 0).0, red);
+        assert_eq!(difference_heatmap(&a16, &b16, Some(257.0 * 120.0)).unwrap().get_pixel(0, 0).0, red);
+        assert_eq!(difference_heatmap(&a16, &b16, Some(40.0)).unwrap().get_pixel(0, 0).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_incompatible_inputs() {
+        let a = gray(16, 16, |_, _| 0.5);
+        let b = gray(16, 12, |_, _| 0.5);
+        assert!(matches!(mse(&a, &b, None), Err(ImageProcessingError::InvalidParameter(_))));
+        assert!(matches!(
+            mse(&a, &a, Some(&[true; 4])),
+            Err(ImageProcessingError::DimensionMismatch { a: 256, b: 4 })
+        ));
+        assert!(ms_ssim(&a, &a, None).is_err());
+    }
+}
@@ -1,3 +1,24 @@
 //! wrapper for metrics
 
-pub mod metrics;
\ No newline at end of file
+/// image quality metrics
+pub mod metrics;
+
+/// loading and saving images
+pub mod io;
+
+/// synthetic noise
+pub mod noise;
+
+/// planar float image buffer
+pub mod buffer;
+
+/// allocator that tracks the peak memory
+pub mod alloc;
+
+/// border modes of the filters
+pub mod border;
+
+/// fixtures shared by the tests of the filters, each feature set uses only a part of them
+#[cfg(test)]
+#[allow(dead_code)]
+pub(crate) mod testing;
@@ -0,0 +1,369 @@
+//! synthetic noise for test and benchmark images.
+//! params:
+//! - noise model and its parameters (normalized units, 1.0 = full range)
+//! - seed
+//!
+//! The same [`NoiseRecord`] always gives the same noisy image: every channel draws from its own
+//! ChaCha8 stream, so the result does not depend on threads or on the other channels.
+//! Records serialize to JSON and are saved next to the noisy image.
+
+use crate::error::ImageProcessingError;
+use crate::utils::buffer::FloatImage;
+use image::DynamicImage;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal, Poisson};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Noise model, sigmas are in normalized units (25 on a 0-255 scale is 25 / 255)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum NoiseModel {
+    /// additive white gaussian noise
+    Gaussian {
+        /// standard deviation
+        sigma: f64,
+    },
+    /// shot noise, `peak` photons at full scale (lower = noisier)
+    Poisson {
+        /// expected photon count of a white pixel
+        peak: f64,
+    },
+    /// shot noise followed by gaussian read noise
+    PoissonGaussian {
+        /// expected photon count of a white pixel
+        peak: f64,
+        /// standard deviation of the read noise
+        sigma: f64,
+    },
+    /// impulse noise, the same pixel is hit on every color channel
+    SaltAndPepper {
+        /// fraction of pixels replaced
+        density: f64,
+        /// fraction of the replaced pixels set to white
+        salt_ratio: f64,
+    },
+    /// multiplicative noise, `v * (1 + n)` with n gaussian
+    Speckle {
+        /// standard deviation of n
+        sigma: f64,
+    },
+    /// gaussian noise low-pass filtered with a gaussian kernel, the final standard deviation is `sigma`
+    Correlated {
+        /// standard deviation
+        sigma: f64,
+        /// standard deviation of the filter in pixels (0 = white noise)
+        correlation: f64,
+    },
+}
+
+impl NoiseModel {
+    /// Check the parameters
+    pub fn validate(&self) -> Result<(), ImageProcessingError> {
+        let check = |ok: bool, what: &str| {
+            if ok {
+                Ok(())
+            } else {
+                Err(ImageProcessingError::InvalidParameter(format!("{} ({:?})", what, self)))
+            }
+        };
+        let sigma_ok = |sigma: f64| sigma.is_finite() && sigma >= 0.0;
+        let peak_ok = |peak: f64| peak.is_finite() && peak > 0.0;
+
+        match *self {
+            NoiseModel::Gaussian { sigma } | NoiseModel::Speckle { sigma } => check(sigma_ok(sigma), "sigma must be >= 0"),
+            NoiseModel::Poisson { peak } => check(peak_ok(peak), "peak must be > 0"),
+            NoiseModel::PoissonGaussian { peak, sigma } => {
+                check(peak_ok(peak), "peak must be > 0")?;
+                check(sigma_ok(sigma), "sigma must be >= 0")
+            }
+            NoiseModel::SaltAndPepper { density, salt_ratio } => {
+                check((0.0..=1.0).contains(&density), "density must be 0.0-1.0")?;
+                check((0.0..=1.0).contains(&salt_ratio), "salt ratio must be 0.0-1.0")
+            }
+            NoiseModel::Correlated { sigma, correlation } => {
+                check(sigma_ok(sigma), "sigma must be >= 0")?;
+                check(correlation.is_finite() && correlation >= 0.0, "correlation must be >= 0")
+            }
+        }
+    }
+}
+
+/// Everything needed to reproduce a noisy image
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NoiseRecord {
+    /// noise model and parameters
+    #[serde(flatten)]
+    pub model: NoiseModel,
+    /// seed of the random generator
+    pub seed: u64,
+    /// clamp the result to [0, 1]
+    pub clip: bool,
+}
+
+impl NoiseRecord {
+    /// Record with clipping enabled
+    pub fn new(model: NoiseModel, seed: u64) -> Self {
+        NoiseRecord { model, seed, clip: true }
+    }
+
+    /// Add the noise to the color channels, alpha is kept as is
+    pub fn apply(&self, img: &FloatImage) -> Result<FloatImage, ImageProcessingError> {
+        self.model.validate()?;
+        let mut out = img.clone();
+        let (width, height) = img.dimensions();
+        let channels = img.layout().color_channels();
+
+        match self.model {
+            NoiseModel::SaltAndPepper { density, salt_ratio } => {
+                // un solo stream: lo stesso pixel è colpito su tutti i canali
+                let mut rng = self.rng(0);
+                for i in 0..width * height {
+                    if rng.random::<f64>() < density {
+                        let value = if rng.random::<f64>() < salt_ratio { 1.0 } else { 0.0 };
+                        for plane in &mut out.planes_mut()[..channels] {
+                            plane[i] = value;
+                        }
+                    }
+                }
+            }
+            model => {
+                for (c, plane) in out.planes_mut()[..channels].iter_mut().enumerate() {
+                    let mut rng = self.rng(c as u64);
+                    apply_to_plane(model, plane, width, height, &mut rng)?;
+                }
+            }
+        }
+
+        if self.clip {
+            for plane in &mut out.planes_mut()[..channels] {
+                plane.iter_mut().for_each(|v| *v = v.clamp(0.0, 1.0));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Add the noise to a decoded image, bit depth and layout are kept
+    pub fn apply_dynamic(&self, img: &DynamicImage) -> Result<DynamicImage, ImageProcessingError> {
+        Ok(self.apply(&FloatImage::from_dynamic(img))?.to_dynamic())
+    }
+
+    /// Pretty printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("noise record is always serializable")
+    }
+
+    /// Parse a record written by [`to_json`](Self::to_json)
+    pub fn from_json(json: &str) -> Result<Self, ImageProcessingError> {
+        serde_json::from_str(json).map_err(|e| ImageProcessingError::InvalidParameter(format!("noise record: {}", e)))
+    }
+
+    /// Write the record as a JSON file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageProcessingError> {
+        Ok(std::fs::write(path, self.to_json() + "\n")?)
+    }
+
+    /// Read a record saved with [`save`](Self::save)
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ImageProcessingError> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    fn rng(&self, stream: u64) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(stream);
+        rng
+    }
+}
+
+/// Path of the JSON record saved next to a noisy image (`noisy.png` -> `noisy.png.json`)
+pub fn record_path<P: AsRef<Path>>(image_path: P) -> std::path::PathBuf {
+    let mut path = image_path.as_ref().as_os_str().to_owned();
+    path.push(".json");
+    path.into()
+}
+
+fn apply_to_plane(
+    model: NoiseModel,
+    plane: &mut [f32],
+    width: usize,
+    height: usize,
+    rng: &mut ChaCha8Rng,
+) -> Result<(), ImageProcessingError> {
+    let normal = |sigma: f64| {
+        Normal::new(0.0, sigma).map_err(|e| ImageProcessingError::InvalidParameter(format!("sigma: {}", e)))
+    };
+    match model {
+        NoiseModel::Gaussian { sigma } => {
+            let dist = normal(sigma)?;
+            plane.iter_mut().for_each(|v| *v += dist.sample(rng) as f32);
+        }
+        NoiseModel::Poisson { peak } => {
+            plane.iter_mut().for_each(|v| *v = shot(*v, peak, rng));
+        }
+        NoiseModel::PoissonGaussian { peak, sigma } => {
+            let dist = normal(sigma)?;
+            plane.iter_mut().for_each(|v| *v = shot(*v, peak, rng) + dist.sample(rng) as f32);
+        }
+        NoiseModel::Speckle { sigma } => {
+            let dist = normal(sigma)?;
+            plane.iter_mut().for_each(|v| *v *= 1.0 + dist.sample(rng) as f32);
+        }
+        NoiseModel::Correlated { sigma, correlation } => {
+            let dist = normal(1.0)?;
+            let white: Vec<f64> = (0..plane.len()).map(|_| dist.sample(rng)).collect();
+            let noise = correlate(&white, width, height, correlation);
+            for (v, n) in plane.iter_mut().zip(noise) {
+                *v += (sigma * n) as f32;
+            }
+        }
+        NoiseModel::SaltAndPepper { .. } => unreachable!("salt and pepper is applied to all channels at once"),
+    }
+    Ok(())
+}
+
+/// Poisson sample of `v * peak` photons, back to normalized units
+fn shot(v: f32, peak: f64, rng: &mut ChaCha8Rng) -> f32 {
+    let lambda = v.max(0.0) as f64 * peak;
+    if lambda <= 0.0 {
+        return 0.0;
+    }
+    let count: f64 = Poisson::new(lambda).expect("lambda is positive and finite").sample(rng);
+    (count / peak) as f32
+}
+
+/// Separable gaussian filter of unit variance white noise, rescaled to unit variance
+fn correlate(white: &[f64], width: usize, height: usize, correlation: f64) -> Vec<f64> {
+    if correlation == 0.0 {
+        return white.to_vec();
+    }
+    let radius = (3.0 * correlation).ceil() as isize;
+    let mut kernel: Vec<f64> = (-radius..=radius)
+        .map(|d| (-(d * d) as f64 / (2.0 * correlation * correlation)).exp())
+        .collect();
+    // varianza del rumore filtrato = (sum k²)² per un kernel separabile
+    let norm = kernel.iter().map(|k| k * k).sum::<f64>().sqrt();
+    kernel.iter_mut().for_each(|k| *k /= norm);
+
+    // bordi a specchio
+    let mirror = |i: isize, len: usize| -> usize {
+        let len = len as isize;
+        let period = 2 * len;
+        let i = i.rem_euclid(period);
+        (if i < len { i } else { period - 1 - i }) as usize
+    };
+
+    let mut rows = vec![0.0; white.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * white[y * width + mirror(x as isize + k as isize - radius, width)])
+                .sum();
+        }
+    }
+    let mut out = vec![0.0; white.len()];
+    for y in 0..height {
+        for x in 0..width {
+            out[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * rows[mirror(y as isize + k as isize - radius, height) * width + x])
+                .sum();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::buffer::{BitDepth, ChannelLayout};
+
+    fn flat(width: usize, height: usize, layout: ChannelLayout, value: f32) -> FloatImage {
+        let planes = vec![vec![value; width * height]; layout.channels()];
+        FloatImage::from_planes(width, height, layout, BitDepth::U8, planes).unwrap()
+    }
+
+    fn stats(plane: &[f32]) -> (f64, f64) {
+        let mean = plane.iter().map(|&v| v as f64).sum::<f64>() / plane.len() as f64;
+        let var = plane.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / plane.len() as f64;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn test_same_seed_same_noise() {
+        let img = flat(32, 32, ChannelLayout::Rgb, 0.5);
+        let record = NoiseRecord::new(NoiseModel::Gaussian { sigma: 0.1 }, 7);
+        assert_eq!(record.apply(&img).unwrap(), record.apply(&img).unwrap());
+
+        let other = NoiseRecord::new(NoiseModel::Gaussian { sigma: 0.1 }, 8);
+        assert_ne!(record.apply(&img).unwrap(), other.apply(&img).unwrap());
+    }
+
+    #[test]
+    fn test_gaussian_statistics() {
+        let img = flat(128, 128, ChannelLayout::Luma, 0.5);
+        let noisy = NoiseRecord::new(NoiseModel::Gaussian { sigma: 0.05 }, 1).apply(&img).unwrap();
+        let (mean, std) = stats(noisy.plane(0));
+        assert!((mean - 0.5).abs() < 0.002);
+        assert!((std - 0.05).abs() < 0.002);
+    }
+
+    #[test]
+    fn test_poisson_variance() {
+        // varianza di Poisson: v / peak
+        let img = flat(128, 128, ChannelLayout::Luma, 0.5);
+        let noisy = NoiseRecord::new(NoiseModel::Poisson { peak: 100.0 }, 2).apply(&img).unwrap();
+        let (mean, std) = stats(noisy.plane(0));
+        assert!((mean - 0.5).abs() < 0.005);
+        assert!((std - (0.5f64 / 100.0).sqrt()).abs() < 0.003);
+    }
+
+    #[test]
+    fn test_correlated_keeps_sigma() {
+        let img = flat(128, 128, ChannelLayout::Luma, 0.5);
+        let model = NoiseModel::Correlated { sigma: 0.05, correlation: 1.5 };
+        let noisy = NoiseRecord::new(model, 3).apply(&img).unwrap();
+        let plane = noisy.plane(0);
+        let (_, std) = stats(plane);
+        assert!((std - 0.05).abs() < 0.01);
+
+        // i vicini sono correlati
+        let cov: f64 = (0..plane.len() - 1)
+            .map(|i| (plane[i] as f64 - 0.5) * (plane[i + 1] as f64 - 0.5))
+            .sum::<f64>()
+            / (plane.len() - 1) as f64;
+        assert!(cov / (std * std) > 0.5);
+    }
+
+    #[test]
+    fn test_salt_and_pepper() {
+        let img = flat(64, 64, ChannelLayout::Rgba, 0.5);
+        let model = NoiseModel::SaltAndPepper { density: 0.1, salt_ratio: 1.0 };
+        let noisy = NoiseRecord::new(model, 4).apply(&img).unwrap();
+
+        let hit = noisy.plane(0).iter().filter(|&&v| v == 1.0).count();
+        assert!((hit as f64 / 4096.0 - 0.1).abs() < 0.02);
+        assert_eq!(noisy.plane(0), noisy.plane(2));
+        assert!(noisy.plane(3).iter().all(|&v| v == 0.5));
+    }
+
+    #[test]
+    fn test_record_json_roundtrip() {
+        let record = NoiseRecord::new(NoiseModel::PoissonGaussian { peak: 30.0, sigma: 0.01 }, 42);
+        let json = record.to_json();
+        assert!(json.contains("\"model\": \"poisson_gaussian\""));
+        assert_eq!(NoiseRecord::from_json(&json).unwrap(), record);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let img = flat(8, 8, ChannelLayout::Luma, 0.5);
+        let record = NoiseRecord::new(NoiseModel::Gaussian { sigma: -1.0 }, 0);
+        assert!(matches!(record.apply(&img), Err(ImageProcessingError::InvalidParameter(_))));
+        let record = NoiseRecord::new(NoiseModel::SaltAndPepper { density: 2.0, salt_ratio: 0.5 }, 0);
+        assert!(record.apply(&img).is_err());
+    }
+}
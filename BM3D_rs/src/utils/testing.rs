@@ -0,0 +1,23 @@
+//! fixtures and checks shared by the tests of the filters
+
+use super::buffer::FloatImage;
+use crate::error::ImageProcessingError;
+use image::{DynamicImage, ImageBuffer, Pixel};
+use std::fmt::Debug;
+
+/// 16-bit step edge slanted by one pixel every two rows, `low` on the left and `high` on the right
+pub fn step<P>(width: u32, height: u32, low: P, high: P) -> FloatImage
+where
+    P: Pixel<Subpixel = u16>,
+    DynamicImage: From<ImageBuffer<P, Vec<u16>>>,
+{
+    let img = ImageBuffer::from_fn(width, height, |x, y| if x + y / 2 < width / 2 { low } else { high });
+    FloatImage::from_dynamic(&DynamicImage::from(img))
+}
+
+/// every parameter set must be rejected with InvalidParameter
+pub fn assert_invalid<P: Debug>(params: impl IntoIterator<Item = P>, denoise: impl Fn(&P) -> Result<FloatImage, ImageProcessingError>) {
+    for params in params {
+        assert!(matches!(denoise(&params), Err(ImageProcessingError::InvalidParameter(_))), "{:?}", params);
+    }
+}
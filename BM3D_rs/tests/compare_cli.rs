@@ -0,0 +1,53 @@
+#![cfg(feature = "cli")]
+
+use image::{DynamicImage, ImageBuffer, Luma};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// immagine a 16 bit scritta nella cartella temporanea di cargo
+fn fixture(name: &str, value: u16) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    DynamicImage::ImageLuma16(ImageBuffer::from_pixel(16, 16, Luma([value]))).save(&path).unwrap();
+    path
+}
+
+fn compare(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_bm3d_rs")).arg("compare").args(args).output().unwrap()
+}
+
+#[test]
+fn test_compare_json_and_heatmap() {
+    let reference = fixture("compare_reference.png", 1000);
+    let test = fixture("compare_test.png", 1000 + 257 * 40);
+    let heatmap = Path::new(env!("CARGO_TARGET_TMPDIR")).join("compare_heatmap.png");
+
+    // scala nelle unita' a 16 bit: 40 livelli a 8 bit sono un terzo di 120 * 257
+    let out = compare(&[
+        "--reference", reference.to_str().unwrap(),
+        "--test", test.to_str().unwrap(),
+        "--heatmap", heatmap.to_str().unwrap(),
+        "--heatmap-scale", "30840",
+        "--json",
+    ]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let psnr = json["metrics"]["psnr"].as_f64().unwrap();
+    // differenza di 40/255: 20 log10(255 / 40)
+    assert!((psnr - 16.0896).abs() < 1e-3, "PSNR {}", psnr);
+
+    let heatmap = image::open(&heatmap).unwrap().to_rgb8();
+    // un terzo della scala: fine della rampa rossa
+    assert_eq!(heatmap.get_pixel(0, 0).0, [255, 0, 0]);
+}
+
+#[test]
+fn test_compare_rejects_different_sizes() {
+    let reference = fixture("compare_small.png", 0);
+    let other = Path::new(env!("CARGO_TARGET_TMPDIR")).join("compare_large.png");
+    DynamicImage::new_luma16(20, 16).save(&other).unwrap();
+
+    let out = compare(&["--reference", reference.to_str().unwrap(), "--test", other.to_str().unwrap()]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("image sizes differ"));
+}
@@ -0,0 +1,29 @@
+use bm3d_rs::utils::alloc::TrackingAllocator;
+use bm3d_rs::{denoise_buffer, estimate, Bm3dParams, FloatImage, ParamValue, Parameters, Preset};
+use image::{DynamicImage, Rgb, RgbImage};
+
+#[global_allocator]
+static ALLOC: TrackingAllocator = TrackingAllocator;
+
+#[test]
+fn test_estimate_peak_memory_matches_allocator() {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_fn(160, 128, |x, y| {
+        Rgb([(x * 3 % 256) as u8, (y * 5 % 256) as u8, ((x * y) % 256) as u8])
+    }));
+    let mut params = Bm3dParams::new();
+    Preset::Default.apply(&mut params);
+    params.set(Parameters::Threads, ParamValue::I32(1));
+
+    // la stima conta anche il buffer di ingresso: la misura parte prima di crearlo
+    let baseline = TrackingAllocator::current();
+    TrackingAllocator::reset_peak();
+    let buffer = FloatImage::from_dynamic(&img);
+    let denoised = denoise_buffer(&buffer, &params).unwrap();
+    let measured = TrackingAllocator::peak().saturating_sub(baseline);
+    drop(denoised);
+
+    assert!(TrackingAllocator::is_installed());
+    let estimated = estimate(&buffer, &params, 64).unwrap().peak_memory_bytes;
+    let ratio = estimated as f64 / measured as f64;
+    assert!((0.8..1.25).contains(&ratio), "estimated {} bytes, measured {} bytes", estimated, measured);
+}
@@ -0,0 +1,109 @@
+//! denoise: every denoiser of the workspace behind one crate.
+//!
+//! Each algorithm is a cargo feature, only the enabled ones are compiled in:
+//! `median` and `bm3d` (default), `wavelet`, `nlm`, `local-means`, `adaptive-means`,
+//! `bilateral`, `tv`, `guided`, `diffusion`, or `full` for all of them.
+//!
+//! They share one error type, [`ImageProcessingError`], and one image type, [`DynamicImage`]:
+//! every algorithm module has an `XxxDenoiser` implementing [`Denoiser`].
+
+pub use denoise_core::{Denoiser, DynDenoiser, ImageProcessingError};
+pub use image::{self, DynamicImage};
+
+/// median filter
+#[cfg(feature = "median")]
+pub mod median {
+    pub use ::median::models::ColorSpace;
+    pub use ::median::{denoise, denoise_buffer, denoise_image, MedianDenoiser, MedianParams, MedianSample};
+}
+
+/// BM3D
+#[cfg(feature = "bm3d")]
+pub mod bm3d {
+    pub use bm3d_rs::bm3d::*;
+    pub use bm3d_rs::{Bm3dParams, ParamValue, Parameters};
+}
+
+/// wavelet shrinkage
+#[cfg(feature = "wavelet")]
+pub use bm3d_rs::wavelet;
+
+/// non-local means
+#[cfg(feature = "nlm")]
+pub use bm3d_rs::nlm;
+
+/// box, gaussian and separable local means
+#[cfg(feature = "local-means")]
+pub use bm3d_rs::local_means;
+
+/// Lee, Kuan and Frost adaptive local means
+#[cfg(feature = "adaptive-means")]
+pub use bm3d_rs::adaptive_means;
+
+/// bilateral and joint bilateral filters
+#[cfg(feature = "bilateral")]
+pub use bm3d_rs::bilateral;
+
+/// total variation
+#[cfg(feature = "tv")]
+pub use bm3d_rs::tv;
+
+/// guided image filter
+#[cfg(feature = "guided")]
+pub use bm3d_rs::guided;
+
+/// Perona-Malik and coherence-enhancing diffusion
+#[cfg(feature = "diffusion")]
+pub use bm3d_rs::diffusion;
+
+/// presets, sigma scale and float buffer shared by the algorithms of `bm3d_rs`
+#[cfg(any(
+    feature = "bm3d",
+    feature = "wavelet",
+    feature = "nlm",
+    feature = "local-means",
+    feature = "adaptive-means",
+    feature = "bilateral",
+    feature = "tv",
+    feature = "guided",
+    feature = "diffusion"
+))]
+pub use bm3d_rs::{FloatImage, Preset, SigmaScale};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enabled_denoisers_share_the_trait() {
+        // la lista dipende dalle feature attive
+        let all: Vec<Box<dyn DynDenoiser>> = vec![
+            #[cfg(feature = "median")]
+            Box::new(median::MedianDenoiser::new(median::MedianParams { kernel: 1, color_space: median::ColorSpace::Rgb8 })),
+            #[cfg(feature = "bm3d")]
+            Box::new(bm3d::Bm3dDenoiser::new(bm3d::Bm3dParams::new())),
+            #[cfg(feature = "wavelet")]
+            Box::new(wavelet::WaveletDenoiser::new(wavelet::WaveletParams::with_sigma(20.0))),
+            #[cfg(feature = "nlm")]
+            Box::new(nlm::NlmDenoiser::new(nlm::NlmParams::with_sigma(20.0))),
+            #[cfg(feature = "local-means")]
+            Box::new(local_means::LocalMeansDenoiser::new(local_means::LocalMeansParams::default())),
+            #[cfg(feature = "adaptive-means")]
+            Box::new(adaptive_means::AdaptiveDenoiser::new(adaptive_means::AdaptiveParams::default())),
+            #[cfg(feature = "bilateral")]
+            Box::new(bilateral::BilateralDenoiser::new(bilateral::BilateralParams::default())),
+            #[cfg(feature = "tv")]
+            Box::new(tv::TvDenoiser::new(tv::TvParams::with_sigma(20.0))),
+            #[cfg(feature = "guided")]
+            Box::new(guided::GuidedDenoiser::new(guided::GuidedParams::with_sigma(20.0))),
+            #[cfg(feature = "diffusion")]
+            Box::new(diffusion::DiffusionDenoiser::new(diffusion::DiffusionParams::default())),
+        ];
+
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 40, |x, y| image::Rgb([(x * 6) as u8, (y * 6) as u8, 90])));
+        for denoiser in &all {
+            let out = denoiser.denoise(&img).unwrap();
+            assert_eq!((out.width(), out.height()), (40, 40), "{}", denoiser.name());
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+use std::io;
+
+/// Central error handling for image processing operations.
+#[derive(Debug)]
+pub enum ImageProcessingError {
+    /// Error in color conversion
+    ColorConversionError,
+    /// Error in discrete cosine transform (DCT)
+    DctError,
+    /// Error in wavelet transform
+    WaveletError,
+    /// Invalid parameter, e.g. window size, threshold
+    InvalidParameter(String),
+    /// Access out of bounds, e.g. pixel index, patch index
+    OutOfBounds(String),
+    /// Unsupported image format
+    UnsupportedFormat(String),
+    /// I/O error while reading or writing an image
+    Io(io::Error),
+    /// The image could not be decoded
+    Decode(image::ImageError),
+    /// The image could not be encoded or saved
+    Encode(image::ImageError),
+    /// Invalid mix factor in aggregation (must be 0.0-1.0)
+    InvalidMixFactor(f64),
+    /// Dimension mismatch between two buffers
+    DimensionMismatch {
+        /// length of the first buffer
+        a: usize,
+        /// length of the second buffer
+        b: usize,
+    },
+    /// The operation was cancelled before completion
+    Cancelled,
+    /// Other error
+    Other(String),
+}
+
+impl ImageProcessingError {
+    /// Wrap an error returned by `image` while decoding, keeping I/O failures apart.
+    pub fn decode(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(e) => Self::Io(e),
+            e => Self::Decode(e),
+        }
+    }
+
+    /// Wrap an error returned by `image` while encoding, keeping I/O failures apart.
+    pub fn encode(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(e) => Self::Io(e),
+            e => Self::Encode(e),
+        }
+    }
+}
+
+impl fmt::Display for ImageProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ColorConversionError => write!(f, "Error in color conversion"),
+            Self::DctError => write!(f, "Error in discrete cosine transform (DCT)"),
+            Self::WaveletError => write!(f, "Error in wavelet transform"),
+            Self::InvalidParameter(param) => write!(f, "Invalid parameter: {}", param),
+            Self::OutOfBounds(context) => write!(f, "Access out of bounds: {}", context),
+            Self::UnsupportedFormat(fmt) => write!(f, "Unsupported image format: {}", fmt),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Decode(e) => write!(f, "Error while loading image: {}", e),
+            Self::Encode(e) => write!(f, "Failed to save image: {}", e),
+            Self::InvalidMixFactor(mix) => write!(f, "Invalid mix factor: {} (must be 0.0-1.0)", mix),
+            Self::DimensionMismatch { a, b } => write!(f, "Dimension mismatch: original={}, reconstructed={}", a, b),
+            Self::Cancelled => write!(f, "Operation cancelled"),
+            Self::Other(msg) => write!(f, "Generic error: {}", msg),
+        }
+    }
+}
+
+/// Implement std::error::Error for compatibility with other Rust APIs
+impl std::error::Error for ImageProcessingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Decode(e) | Self::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Common conversions
+impl From<&str> for ImageProcessingError {
+    fn from(s: &str) -> Self {
+        ImageProcessingError::Other(s.to_owned())
+    }
+}
+
+impl From<String> for ImageProcessingError {
+    fn from(s: String) -> Self {
+        ImageProcessingError::Other(s)
+    }
+}
+
+impl From<io::Error> for ImageProcessingError {
+    fn from(e: io::Error) -> Self {
+        ImageProcessingError::Io(e)
+    }
+}
+
+impl TryFrom<u32> for ImageProcessingError {
+    type Error = &'static str;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ImageProcessingError::ColorConversionError),
+            1 => Ok(ImageProcessingError::DctError),
+            2 => Ok(ImageProcessingError::WaveletError),
+            _ => Err("Code error not valid"),
+        }
+    }
+}
+
+impl From<ImageProcessingError> for u32 {
+    fn from(err: ImageProcessingError) -> Self {
+        match err {
+            ImageProcessingError::ColorConversionError => 0,
+            ImageProcessingError::DctError => 1,
+            ImageProcessingError::WaveletError => 2,
+            _ => 999,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_decode_keeps_source() {
+        let err = image::open("does/not/exist.png").unwrap_err();
+        let wrapped = ImageProcessingError::decode(err);
+
+        assert!(matches!(wrapped, ImageProcessingError::Io(_)));
+        assert!(wrapped.source().is_some());
+    }
+
+    #[test]
+    fn test_owned_messages() {
+        let name = "window_size";
+        let err = ImageProcessingError::InvalidParameter(format!("{} must be odd", name));
+        assert_eq!(err.to_string(), "Invalid parameter: window_size must be odd");
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let err = ImageProcessingError::Cancelled;
+        assert_eq!(err.to_string(), "Operation cancelled");
+        assert!(err.source().is_none());
+        assert_eq!(u32::from(err), 999);
+    }
+}
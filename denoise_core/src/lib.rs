@@ -0,0 +1,101 @@
+//! denoise_core: pieces shared by every denoiser of the workspace.
+//!
+//! - [`ImageProcessingError`], the error returned by all the algorithms
+//! - [`Denoiser`], an algorithm bound to its parameters
+//! - [`DynDenoiser`], the object safe view of a [`Denoiser`] for code that picks the algorithm
+//!   at run time (benchmarks, plugin hosts)
+
+use image::DynamicImage;
+use std::fmt;
+
+/// shared error type
+pub mod error;
+
+pub use error::ImageProcessingError;
+
+/// A denoising algorithm with its parameters.
+///
+/// Bit depth and channel layout of the input are kept unless the algorithm documents otherwise.
+pub trait Denoiser {
+    /// parameters of the algorithm
+    type Params: Clone + fmt::Debug;
+
+    /// Bind the algorithm to `params`
+    fn new(params: Self::Params) -> Self
+    where
+        Self: Sized;
+
+    /// Parameters used by [`Denoiser::denoise`]
+    fn params(&self) -> &Self::Params;
+
+    /// short lower case name of the algorithm
+    fn name(&self) -> &'static str;
+
+    /// Denoise an in-memory image
+    fn denoise(&self, image: &DynamicImage) -> Result<DynamicImage, ImageProcessingError>;
+}
+
+/// [`Denoiser`] without the parameter type, usable as `Box<dyn DynDenoiser>`
+pub trait DynDenoiser: Send + Sync {
+    /// short lower case name of the algorithm
+    fn name(&self) -> &'static str;
+
+    /// Denoise an in-memory image
+    fn denoise(&self, image: &DynamicImage) -> Result<DynamicImage, ImageProcessingError>;
+}
+
+impl<D: Denoiser + Send + Sync> DynDenoiser for D {
+    fn name(&self) -> &'static str {
+        Denoiser::name(self)
+    }
+
+    fn denoise(&self, image: &DynamicImage) -> Result<DynamicImage, ImageProcessingError> {
+        Denoiser::denoise(self, image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// inverte i campioni, errore sulle immagini vuote
+    struct Invert {
+        params: u8,
+    }
+
+    impl Denoiser for Invert {
+        type Params = u8;
+
+        fn new(params: u8) -> Self {
+            Invert { params }
+        }
+
+        fn params(&self) -> &u8 {
+            &self.params
+        }
+
+        fn name(&self) -> &'static str {
+            "invert"
+        }
+
+        fn denoise(&self, image: &DynamicImage) -> Result<DynamicImage, ImageProcessingError> {
+            if image.width() == 0 {
+                return Err(ImageProcessingError::InvalidParameter("empty image".to_string()));
+            }
+            let mut out = image.to_luma8();
+            out.pixels_mut().for_each(|p| p.0[0] = self.params - p.0[0]);
+            Ok(DynamicImage::ImageLuma8(out))
+        }
+    }
+
+    #[test]
+    fn test_dyn_denoiser_dispatch() {
+        let host: Vec<Box<dyn DynDenoiser>> = vec![Box::new(Invert::new(255)), Box::new(Invert::new(100))];
+        let img = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(2, 2, image::Luma([40])));
+        let names: Vec<_> = host.iter().map(|d| d.name()).collect();
+        assert_eq!(names, ["invert", "invert"]);
+        let values: Vec<u8> = host.iter().map(|d| d.denoise(&img).unwrap().to_luma8().get_pixel(0, 0).0[0]).collect();
+        assert_eq!(values, [215, 60]);
+        assert!(host[0].denoise(&DynamicImage::new_luma8(0, 0)).is_err());
+    }
+}
@@ -1,51 +1,149 @@
+mod filter;
 pub mod models;
 
-use image::{DynamicImage, ImageReader, RgbImage, RgbaImage, GrayImage};
-use imageproc::filter::median_filter;
+use denoise_core::Denoiser;
+use image::{DynamicImage, ImageBuffer, ImageReader, Luma, LumaA, Pixel, Rgb, Rgba};
+use std::path::Path;
 use crate::models::ColorSpace;
 
-pub fn denoise(
-    path: &str,
+/// subpixel types `denoise_buffer` accepts: `u8`, `u16` and `f32`
+pub use crate::filter::MedianSample;
+
+/// error of every fallible function of the crate, shared with the other denoisers
+pub use denoise_core::ImageProcessingError;
+
+/// parameters of the median filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MedianParams {
+    /// radius of the square window, in pixels
+    pub kernel: u32,
+    /// layout the image is filtered in, `Auto` for the one of the image
+    pub color_space: ColorSpace,
+}
+
+/// median filter behind the shared `Denoiser` trait
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MedianDenoiser {
+    pub params: MedianParams,
+}
+
+impl Denoiser for MedianDenoiser {
+    type Params = MedianParams;
+
+    fn new(params: MedianParams) -> Self {
+        MedianDenoiser { params }
+    }
+
+    fn params(&self) -> &MedianParams {
+        &self.params
+    }
+
+    fn name(&self) -> &'static str {
+        "median"
+    }
+
+    fn denoise(&self, image: &DynamicImage) -> Result<DynamicImage, ImageProcessingError> {
+        denoise_image(image, self.params.kernel, self.params.color_space)
+    }
+}
+
+/// Load the image at `path` and filter it, see [`denoise_image`].
+/// Open and decode failures keep the `io`/`image` error as source.
+pub fn denoise<P: AsRef<Path>>(
+    path: P,
     kernel: u32,
     color_space: ColorSpace,
-) -> Result<DynamicImage, String> {
-    let img = ImageReader::open(path)
-        .map_err(|_| "Error opening image")?
+) -> Result<DynamicImage, ImageProcessingError> {
+    let img = ImageReader::open(path)?
+        .with_guessed_format()?
         .decode()
-        .map_err(|_| "Error decoding image")?;
-
-    let img_denoised = match color_space {
-        ColorSpace::Rgb8
-        | ColorSpace::Rgb16
-        | ColorSpace::Rgb32 => {
-            let img_buf: RgbImage = img.to_rgb8();
-            let filtered = median_filter(&img_buf, kernel, kernel);
-            DynamicImage::ImageRgb8(filtered)
-        }
+        .map_err(ImageProcessingError::decode)?;
+
+    denoise_image(&img, kernel, color_space)
+}
 
-        ColorSpace::Rgba8
-        | ColorSpace::Rgba16
-        | ColorSpace::Rgba32 => {
-            let img_buf: RgbaImage = img.to_rgba8();
-            let filtered = median_filter(&img_buf, kernel, kernel);
-            DynamicImage::ImageRgba8(filtered)
+/// Median filter of radius `kernel` on an in-memory image, in its own layout with
+/// `ColorSpace::Auto`, converted to `color_space` first otherwise
+pub fn denoise_image(
+    img: &DynamicImage,
+    kernel: u32,
+    color_space: ColorSpace,
+) -> Result<DynamicImage, ImageProcessingError> {
+    if img.width() == 0 || img.height() == 0 {
+        return Err(ImageProcessingError::InvalidParameter(format!(
+            "empty image ({}x{})",
+            img.width(),
+            img.height()
+        )));
+    }
+    Ok(filter(img, kernel, color_space))
+}
+
+/// Median filter of radius `kernel` on a buffer of any pixel type, layout and depth kept
+pub fn denoise_buffer<P>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    kernel: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+    P::Subpixel: MedianSample,
+{
+    filter::median_filter(img, kernel)
+}
+
+fn filter(img: &DynamicImage, kernel: u32, color_space: ColorSpace) -> DynamicImage {
+    match color_space.resolve(img) {
+        ColorSpace::Auto => unreachable!("resolve never returns Auto"),
+        ColorSpace::Rgb8 => DynamicImage::ImageRgb8(denoise_buffer(&img.to_rgb8(), kernel)),
+        ColorSpace::Rgba8 => DynamicImage::ImageRgba8(denoise_buffer(&img.to_rgba8(), kernel)),
+        ColorSpace::Luma8 => DynamicImage::ImageLuma8(denoise_buffer(&img.to_luma8(), kernel)),
+        ColorSpace::LumaAlpha8 => {
+            DynamicImage::ImageLumaA8(denoise_buffer(&img.to_luma_alpha8(), kernel))
         }
 
-        ColorSpace::Luma8
-        | ColorSpace::Luma16
-        | ColorSpace::Luma32 => {
-            let img_buf: GrayImage = img.to_luma8();
-            let filtered = median_filter(&img_buf, kernel, kernel);
-            DynamicImage::ImageLuma8(filtered)
+        ColorSpace::Rgb16 => DynamicImage::ImageRgb16(denoise_buffer(&img.to_rgb16(), kernel)),
+        ColorSpace::Rgba16 => DynamicImage::ImageRgba16(denoise_buffer(&img.to_rgba16(), kernel)),
+        ColorSpace::Luma16 => DynamicImage::ImageLuma16(denoise_buffer(&img.to_luma16(), kernel)),
+        ColorSpace::LumaAlpha16 => {
+            DynamicImage::ImageLumaA16(denoise_buffer(&img.to_luma_alpha16(), kernel))
         }
 
-        ColorSpace::LumaAlpha8
-        | ColorSpace::LumaAlpha16
-        | ColorSpace::LumaAlpha32 => {
-            let img_buf = img.to_luma_alpha8();
-            let filtered = median_filter(&img_buf, kernel, kernel);
-            DynamicImage::ImageLumaA8(filtered)
+        ColorSpace::Rgb32 => DynamicImage::ImageRgb32F(denoise_buffer(&img.to_rgb32f(), kernel)),
+        ColorSpace::Rgba32 => DynamicImage::ImageRgba32F(denoise_buffer(&img.to_rgba32f(), kernel)),
+        // DynamicImage non ha varianti grigie float: si filtra il canale in f32
+        // e lo si replica su rgb, senza perdere precisione
+        ColorSpace::Luma32 => {
+            let gray = denoise_buffer(&luma32f(img), kernel);
+            DynamicImage::ImageRgb32F(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                let l = gray.get_pixel(x, y)[0];
+                Rgb([l, l, l])
+            }))
         }
-    };
-    Ok(img_denoised)
+        ColorSpace::LumaAlpha32 => {
+            let gray = denoise_buffer(&luma_alpha32f(img), kernel);
+            DynamicImage::ImageRgba32F(ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                let [l, a] = gray.get_pixel(x, y).0;
+                Rgba([l, l, l, a])
+            }))
+        }
+    }
+}
+
+/// gray f32 plane, with the same luma weights `image` uses for the 8/16-bit conversions
+fn luma32f(img: &DynamicImage) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+    let rgb = img.to_rgb32f();
+    ImageBuffer::from_fn(rgb.width(), rgb.height(), |x, y| Luma([luma(rgb.get_pixel(x, y).0)]))
+}
+
+fn luma_alpha32f(img: &DynamicImage) -> ImageBuffer<LumaA<f32>, Vec<f32>> {
+    let rgba = img.to_rgba32f();
+    ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        LumaA([luma([r, g, b]), a])
+    })
+}
+
+fn luma([r, g, b]: [f32; 3]) -> f32 {
+    // stessi pesi rec. 709 di image::Rgb::to_luma
+    0.2126 * r + 0.7152 * g + 0.0722 * b
 }
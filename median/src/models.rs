@@ -1,4 +1,11 @@
+use image::ColorType;
+
+/// layout the median works in; `Auto` keeps the one of the image,
+/// the others force a conversion first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum ColorSpace{
+    #[default]
+    Auto,
     Rgb8,
     Rgb16,
     Rgb32,
@@ -11,4 +18,33 @@ pub enum ColorSpace{
     LumaAlpha8,
     LumaAlpha16,
     LumaAlpha32,
-}
\ No newline at end of file
+}
+
+impl ColorSpace {
+    /// native layout of an image of color type `color`, never `Auto`
+    pub fn of(color: ColorType) -> ColorSpace {
+        match color {
+            ColorType::L8 => ColorSpace::Luma8,
+            ColorType::La8 => ColorSpace::LumaAlpha8,
+            ColorType::Rgb8 => ColorSpace::Rgb8,
+            ColorType::Rgba8 => ColorSpace::Rgba8,
+            ColorType::L16 => ColorSpace::Luma16,
+            ColorType::La16 => ColorSpace::LumaAlpha16,
+            ColorType::Rgb16 => ColorSpace::Rgb16,
+            ColorType::Rgba16 => ColorSpace::Rgba16,
+            ColorType::Rgb32F => ColorSpace::Rgb32,
+            ColorType::Rgba32F => ColorSpace::Rgba32,
+            // tipi futuri: float, per non perdere precisione
+            other if other.has_alpha() => ColorSpace::Rgba32,
+            _ => ColorSpace::Rgb32,
+        }
+    }
+
+    /// `self`, or the native layout of `image` for `Auto`
+    pub fn resolve(self, image: &image::DynamicImage) -> ColorSpace {
+        match self {
+            ColorSpace::Auto => ColorSpace::of(image.color()),
+            forced => forced,
+        }
+    }
+}
@@ -1,32 +1,221 @@
+use denoise_core::{Denoiser, DynDenoiser};
+use image::{ColorType, DynamicImage, GrayImage, ImageBuffer, Luma, Rgb, Rgb32FImage, RgbImage, Rgba, RgbaImage};
+use imageproc::filter::median_filter;
 use median::denoise;
 use median::models::ColorSpace;
-use std::path::Path;
+use median::{denoise_buffer, denoise_image, ImageProcessingError, MedianDenoiser, MedianParams};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// immagine di prova con rumore sale e pepe, scritta nella cartella temporanea di cargo
+fn fixture(name: &str, image: DynamicImage) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    image.save(&path).unwrap();
+    path
+}
+
+fn output(name: &str) -> String {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(name).to_string_lossy().into_owned()
+}
 
 #[test]
 fn test_denoise_rgb8() {
-    let input_path = "tests/test.jpg";
-    let output_path = "tests/output_test2.png";
+    let noisy = RgbImage::from_fn(64, 48, |x, y| {
+        if (x * 7 + y * 13) % 17 == 0 { Rgb([255, 255, 255]) } else { Rgb([(x * 3) as u8, (y * 5) as u8, 120]) }
+    });
+    let input = fixture("test_rgb8.png", DynamicImage::ImageRgb8(noisy));
+    let input_path = input.to_str().unwrap();
+    let output_path = output("output_test2.png");
 
     assert!(Path::new(input_path).exists(), "Test file not found {}", input_path);
 
     let result = denoise(input_path, 3, ColorSpace::Rgb8);
     
     assert!(result.is_ok(), "Denoise function failed {:?}", result.err());
-    result.unwrap().save(output_path).unwrap();
-    assert!(Path::new(output_path).exists(), "Output file not created");
+    result.unwrap().save(&output_path).unwrap();
+    assert!(Path::new(&output_path).exists(), "Output file not created");
 }
 
-// #[test]
+#[test]
+fn test_denoiser_trait() {
+    // un pixel isolato sparisce, il resto resta uguale
+    let mut gray = GrayImage::from_pixel(9, 9, Luma([50]));
+    gray.put_pixel(4, 4, Luma([255]));
+    let img = DynamicImage::ImageLuma8(gray);
+
+    let params = MedianParams { kernel: 1, color_space: ColorSpace::Luma8 };
+    let median = MedianDenoiser::new(params);
+    assert_eq!(median.params(), &params);
+
+    let boxed: Box<dyn DynDenoiser> = Box::new(median);
+    assert_eq!(boxed.name(), "median");
+    let out = boxed.denoise(&img).unwrap();
+    assert!(out.to_luma8().pixels().all(|p| p.0 == [50]));
+}
+
+#[test]
 fn test_denoise_rgb16() {
-    let input_path = "tests/noise.jpg";
-    let output_path = "tests/output1_test.png";
+    let noisy: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(64, 48, |x, y| {
+        if (x * 5 + y * 11) % 19 == 0 { Rgb([0, 0, 0]) } else { Rgb([(x * 900) as u16, (y * 1200) as u16, 40000]) }
+    });
+    let input = fixture("test_rgb16.png", DynamicImage::ImageRgb16(noisy));
+    let input_path = input.to_str().unwrap();
+    let output_path = output("output1_test.png");
 
     assert!(Path::new(input_path).exists(), "Test file not found {}", input_path);
 
     let result = denoise(input_path, 3, ColorSpace::Rgb16);
     
     assert!(result.is_ok(), "Denoise function failed {:?}", result.err());
-    result.unwrap().save(output_path).unwrap();
-    assert!(Path::new(output_path).exists(), "Output file not created");
+    let denoised = result.unwrap();
+    assert_eq!(denoised.color(), ColorType::Rgb16);
+    // 40000 non è multiplo di 257: a 8 bit diventerebbe 39835 o 40092
+    assert_eq!(denoised.as_rgb16().unwrap().get_pixel(32, 24).0[2], 40000);
+    denoised.save(&output_path).unwrap();
+    assert!(Path::new(&output_path).exists(), "Output file not created");
 }
 
+#[test]
+fn test_in_memory_matches_buffer() {
+    let img = RgbImage::from_fn(31, 23, |x, y| Rgb([((x * 37 + y * 11) % 256) as u8, ((x * y) % 256) as u8, 7]));
+    let from_dynamic = denoise_image(&DynamicImage::ImageRgb8(img.clone()), 2, ColorSpace::Rgb8).unwrap();
+    assert_eq!(from_dynamic.as_rgb8(), Some(&denoise_buffer(&img, 2)));
+
+    let empty = denoise_image(&DynamicImage::new_rgb8(0, 4), 1, ColorSpace::Rgb8);
+    assert!(matches!(empty, Err(ImageProcessingError::InvalidParameter(_))));
+}
+
+#[test]
+fn test_16bit_keeps_depth() {
+    // 1001 non e' multiplo di 257: passando per 8 bit diventerebbe 1028
+    let clean: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_pixel(9, 7, Rgb([1001, 30003, 65534]));
+    let mut noisy = clean.clone();
+    noisy.put_pixel(4, 3, Rgb([65535, 0, 0]));
+    let (clean, noisy) = (DynamicImage::ImageRgb16(clean), DynamicImage::ImageRgb16(noisy));
+
+    let expected = [
+        (ColorSpace::Rgb16, ColorType::Rgb16),
+        (ColorSpace::Rgba16, ColorType::Rgba16),
+        (ColorSpace::Luma16, ColorType::L16),
+        (ColorSpace::LumaAlpha16, ColorType::La16),
+    ];
+    for (color_space, color) in expected {
+        let out = denoise_image(&noisy, 1, color_space).unwrap();
+        assert_eq!(out.color(), color, "{:?}", color_space);
+        // il pixel isolato sparisce, il resto e' la sola conversione, sempre a 16 bit
+        assert_eq!(out, denoise_image(&clean, 1, color_space).unwrap(), "{:?}", color_space);
+    }
+    let rgb = denoise_image(&noisy, 1, ColorSpace::Rgb16).unwrap();
+    assert!(rgb.as_rgb16().unwrap().pixels().all(|p| p.0 == [1001, 30003, 65534]));
+}
+
+#[test]
+fn test_float_keeps_depth() {
+    let mut img = Rgb32FImage::from_pixel(9, 7, Rgb([0.123_456_7, 0.5, 1.5]));
+    img.put_pixel(4, 3, Rgb([-3.0, 9.0, f32::NAN]));
+    let img = DynamicImage::ImageRgb32F(img);
+
+    let rgb = denoise_image(&img, 1, ColorSpace::Rgb32).unwrap();
+    assert!(rgb.as_rgb32f().unwrap().pixels().all(|p| p.0 == [0.123_456_7, 0.5, 1.5]));
+    let rgba = denoise_image(&img, 1, ColorSpace::Rgba32).unwrap();
+    assert!(rgba.as_rgba32f().unwrap().pixels().all(|p| p.0 == [0.123_456_7, 0.5, 1.5, 1.0]));
+
+    // niente grigio float in DynamicImage: il grigio torna replicato su rgb in f32
+    let luma = 0.2126 * 0.123_456_7 + 0.7152 * 0.5 + 0.0722 * 1.5;
+    let gray = denoise_image(&img, 1, ColorSpace::Luma32).unwrap();
+    assert_eq!(gray.color(), ColorType::Rgb32F);
+    assert!(gray.as_rgb32f().unwrap().pixels().all(|p| p.0 == [luma; 3]));
+    let gray_alpha = denoise_image(&img, 1, ColorSpace::LumaAlpha32).unwrap();
+    assert_eq!(gray_alpha.color(), ColorType::Rgba32F);
+    assert!(gray_alpha.as_rgba32f().unwrap().pixels().all(|p| p.0 == [luma, luma, luma, 1.0]));
+}
+
+#[test]
+fn test_buffer_matches_imageproc_semantics() {
+    // stesso bordo replicato e stesso rango di imageproc, anche a 16 bit e in float
+    let img = GrayImage::from_fn(13, 11, |x, y| Luma([((x * 53 + y * 29 + x * y) % 256) as u8]));
+    let wide: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_fn(13, 11, |x, y| Luma([img.get_pixel(x, y)[0] as u16 * 3]));
+    let float: ImageBuffer<Luma<f32>, Vec<f32>> = ImageBuffer::from_fn(13, 11, |x, y| Luma([img.get_pixel(x, y)[0] as f32 * 0.5]));
+    for kernel in [1, 2, 5] {
+        let narrow = median_filter(&img, kernel, kernel);
+        let expected = narrow.pixels().map(|p| p[0] as u16 * 3);
+        assert!(denoise_buffer(&wide, kernel).pixels().map(|p| p[0]).eq(expected), "kernel {}", kernel);
+        let expected = narrow.pixels().map(|p| p[0] as f32 * 0.5);
+        assert!(denoise_buffer(&float, kernel).pixels().map(|p| p[0]).eq(expected), "kernel {}", kernel);
+    }
+}
+
+#[test]
+fn test_8bit_matches_imageproc() {
+    // piu' strisce, raggi piccoli, grandi e piu' grandi dell'immagine
+    let rgba = RgbaImage::from_fn(37, 71, |x, y| {
+        let v = (x * 97 + y * 61 + x * x * y) % 256;
+        Rgba([v as u8, ((v * 7) % 256) as u8, if (x + y) % 5 == 0 { 255 } else { 0 }, (y * 3) as u8])
+    });
+    let rgb = DynamicImage::ImageRgba8(rgba.clone()).to_rgb8();
+    let gray = DynamicImage::ImageRgba8(rgba.clone()).to_luma8();
+    let gray_alpha = DynamicImage::ImageRgba8(rgba.clone()).to_luma_alpha8();
+    for kernel in [0, 1, 3, 16, 40] {
+        assert_eq!(denoise_buffer(&rgba, kernel), median_filter(&rgba, kernel, kernel), "kernel {}", kernel);
+        assert_eq!(denoise_buffer(&rgb, kernel), median_filter(&rgb, kernel, kernel), "kernel {}", kernel);
+        assert_eq!(denoise_buffer(&gray, kernel), median_filter(&gray, kernel, kernel), "kernel {}", kernel);
+        assert_eq!(denoise_buffer(&gray_alpha, kernel), median_filter(&gray_alpha, kernel, kernel), "kernel {}", kernel);
+    }
+
+    let line = GrayImage::from_fn(1, 90, |_, y| Luma([(y * 41 % 256) as u8]));
+    assert_eq!(denoise_buffer(&line, 15), median_filter(&line, 15, 15));
+}
+
+#[test]
+fn test_auto_keeps_the_file_layout() {
+    // rgba a 16 bit con alpha e valori non rappresentabili a 8 bit
+    let noisy: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_fn(16, 12, |x, y| {
+        if (x + y * 3) % 7 == 0 { Rgba([65535, 0, 65535, 0]) } else { Rgba([1001, 2002 + x as u16, 3003, 40001]) }
+    });
+    let input = fixture("test_rgba16_auto.png", DynamicImage::ImageRgba16(noisy.clone()));
+
+    let out = denoise(&input, 1, ColorSpace::Auto).unwrap();
+    assert_eq!(out.color(), ColorType::Rgba16);
+    assert_eq!(out.as_rgba16(), Some(&denoise_buffer(&noisy, 1)));
+
+    // l'override forza ancora la conversione
+    assert_eq!(denoise(&input, 1, ColorSpace::Luma8).unwrap().color(), ColorType::L8);
+    assert_eq!(ColorSpace::default(), ColorSpace::Auto);
+}
+
+#[test]
+fn test_auto_resolves_every_layout() {
+    let images = [
+        (DynamicImage::new_luma8(5, 4), ColorSpace::Luma8),
+        (DynamicImage::new_luma_a8(5, 4), ColorSpace::LumaAlpha8),
+        (DynamicImage::new_rgb8(5, 4), ColorSpace::Rgb8),
+        (DynamicImage::new_rgba8(5, 4), ColorSpace::Rgba8),
+        (DynamicImage::new_luma16(5, 4), ColorSpace::Luma16),
+        (DynamicImage::new_luma_a16(5, 4), ColorSpace::LumaAlpha16),
+        (DynamicImage::new_rgb16(5, 4), ColorSpace::Rgb16),
+        (DynamicImage::new_rgba16(5, 4), ColorSpace::Rgba16),
+        (DynamicImage::new_rgb32f(5, 4), ColorSpace::Rgb32),
+        (DynamicImage::new_rgba32f(5, 4), ColorSpace::Rgba32),
+    ];
+    for (img, native) in images {
+        assert_eq!(ColorSpace::Auto.resolve(&img), native);
+        assert_eq!(ColorSpace::Rgb8.resolve(&img), ColorSpace::Rgb8);
+        let out = denoise_image(&img, 1, ColorSpace::Auto).unwrap();
+        assert_eq!(out.color(), img.color(), "{:?}", native);
+    }
+}
+
+#[test]
+fn test_errors_keep_the_cause() {
+    let missing = denoise(Path::new(env!("CARGO_TARGET_TMPDIR")).join("missing.png"), 1, ColorSpace::Rgb8).unwrap_err();
+    assert!(matches!(missing, ImageProcessingError::Io(_)), "{:?}", missing);
+    assert!(missing.source().is_some());
+
+    // estensione png, contenuto no
+    let garbage = Path::new(env!("CARGO_TARGET_TMPDIR")).join("garbage.png");
+    std::fs::write(&garbage, b"not an image at all").unwrap();
+    let broken = denoise(&garbage, 1, ColorSpace::Rgb8).unwrap_err();
+    assert!(matches!(broken, ImageProcessingError::Decode(_)), "{:?}", broken);
+    assert!(broken.source().is_some());
+    assert!(broken.to_string().starts_with("Error while loading image"));
+}